@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{data_processing::DataSet, msom::{clustering::SuperClusters, MSOM}};
+
+// Where a single sample of a dataset ends up on a map
+#[derive(Debug, Clone, Serialize)]
+pub struct SampleAssignment {
+    pub index: usize,
+    pub label: Option<String>,
+    pub text: String,
+    // None for empty samples, which have no BMU
+    pub bmu_row: Option<usize>,
+    pub bmu_col: Option<usize>,
+    // None if the map has no super-clusters
+    pub super_cluster: Option<usize>,
+    // Combined error of the last step, the one that decides the BMU of the whole sequence
+    pub quantization_error: f32,
+    pub trajectory: Vec<(usize, usize)>,
+}
+
+pub fn assign_samples(map: &MSOM, clusters: Option<&SuperClusters>, dataset: &DataSet) -> Result<Vec<SampleAssignment>, String> {
+    let samples = dataset.processed_data.as_ref().ok_or("The dataset is not processed")?;
+
+    Ok(samples.iter().enumerate().map(|(index, sample)| {
+        let trajectory = map.evaluate_trajectory(sample);
+        let (bmu, quantization_error) = trajectory.last().map_or((None, f32::NAN), |(bmu, error)| (Some(*bmu), *error));
+
+        SampleAssignment {
+            index,
+            label: dataset.labels.as_ref().and_then(|labels| labels.get(index).cloned()),
+            text: dataset.raw_data.get(index).cloned().unwrap_or_default(),
+            bmu_row: bmu.map(|bmu| bmu.0),
+            bmu_col: bmu.map(|bmu| bmu.1),
+            super_cluster: bmu.zip(clusters).and_then(|(bmu, clusters)| clusters.cluster_of(bmu)),
+            quantization_error,
+            trajectory: trajectory.into_iter().map(|(bmu, _)| bmu).collect(),
+        }
+    }).collect())
+}
+
+// The trajectory goes into a single column as `row:col` steps separated by spaces
+pub fn to_csv(assignments: &[SampleAssignment]) -> String {
+    let mut csv = "index,label,text,bmu_row,bmu_col,super_cluster,quantization_error,trajectory\n".to_owned();
+    for assignment in assignments {
+        let trajectory: Vec<String> = assignment.trajectory.iter().map(|(row, col)| format!("{row}:{col}")).collect();
+        csv += &format!("{},{},{},{},{},{},{},{}\n",
+            assignment.index,
+            csv_field(assignment.label.as_deref().unwrap_or_default()),
+            csv_field(&assignment.text),
+            assignment.bmu_row.map_or("".to_owned(), |row| row.to_string()),
+            assignment.bmu_col.map_or("".to_owned(), |col| col.to_string()),
+            assignment.super_cluster.map_or("".to_owned(), |cluster| cluster.to_string()),
+            assignment.quantization_error,
+            trajectory.join(" "));
+    }
+
+    csv
+}
+
+pub fn to_jsonl(assignments: &[SampleAssignment]) -> Result<String, String> {
+    let mut jsonl = String::new();
+    for assignment in assignments {
+        jsonl += &serde_json::to_string(assignment).map_err(|err| err.to_string())?;
+        jsonl += "\n";
+    }
+
+    Ok(jsonl)
+}
+
+// Writes as CSV or JSONL depending on the extension
+pub fn export(map: &MSOM, clusters: Option<&SuperClusters>, dataset: &DataSet, path: &Path) -> Result<(), String> {
+    let assignments = assign_samples(map, clusters, dataset)?;
+    let contents = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("jsonl") => to_jsonl(&assignments)?,
+        _ => to_csv(&assignments),
+    };
+
+    std::fs::write(path, contents).map_err(|err| format!("Error while writing {}: {err}", path.display()))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::HashMap, fs::{self, File}, io::{BufWriter, Write}, path::{Path, PathBuf}};
+
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::file_format::{self, Versioned};
+
+// Layout: MAGIC, header length as u64 LE, JSON header, padding, then the chunks as raw little-endian numbers.
+// The header holds the usual versioned envelope of the metadata plus where each chunk lies.
+const MAGIC: &[u8; 8] = b"MSOMBIN\0";
+const ALIGNMENT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ChunkType {
+    F32,
+    U64,
+}
+
+impl ChunkType {
+    fn size(&self) -> usize {
+        match self {
+            ChunkType::F32 => 4,
+            ChunkType::U64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub chunk_type: ChunkType,
+    pub shape: Vec<usize>,
+    // In bytes, relative to the start of the chunk data
+    offset: usize,
+    len: usize,
+}
+
+impl ChunkInfo {
+    pub fn value_count(&self) -> usize {
+        self.len / self.chunk_type.size()
+    }
+
+    // Everything a chunk of a file with `data_len` bytes of data has to satisfy to be read safely
+    fn validate(&self, data_len: usize) -> Result<(), String> {
+        let end = self.offset.checked_add(self.len).ok_or("its end overflows")?;
+        if end > data_len {
+            return Err("it is truncated".to_owned());
+        }
+        if !self.offset.is_multiple_of(ALIGNMENT) {
+            return Err(format!("its offset {} is not a multiple of {ALIGNMENT}", self.offset));
+        }
+        let size = self.chunk_type.size();
+        if !self.len.is_multiple_of(size) {
+            return Err(format!("its length {} is not a multiple of {size}", self.len));
+        }
+        let expected = self.shape.iter()
+            .try_fold(size, |total, dimension| total.checked_mul(*dimension))
+            .ok_or("its shape overflows")?;
+        if expected != self.len {
+            return Err(format!("its shape {:?} needs {expected} bytes, it has {}", self.shape, self.len));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    envelope: Value,
+    chunks: HashMap<String, ChunkInfo>,
+}
+
+#[derive(Default)]
+pub struct BinaryWriter {
+    chunks: HashMap<String, ChunkInfo>,
+    data: Vec<u8>,
+}
+
+impl BinaryWriter {
+    pub fn add_f32<'a>(&mut self, name: &str, shape: &[usize], values: impl IntoIterator<Item = &'a f32>) {
+        let offset = self.data.len();
+        for value in values {
+            self.data.extend_from_slice(&value.to_le_bytes());
+        }
+        self.finish_chunk(name, ChunkType::F32, shape, offset);
+    }
+
+    pub fn add_u64(&mut self, name: &str, values: &[u64]) {
+        let offset = self.data.len();
+        for value in values {
+            self.data.extend_from_slice(&value.to_le_bytes());
+        }
+        self.finish_chunk(name, ChunkType::U64, &[values.len()], offset);
+    }
+
+    fn finish_chunk(&mut self, name: &str, chunk_type: ChunkType, shape: &[usize], offset: usize) {
+        let len = self.data.len() - offset;
+        self.data.resize(self.data.len().next_multiple_of(ALIGNMENT), 0);
+        self.chunks.insert(name.to_owned(), ChunkInfo { chunk_type, shape: shape.to_vec(), offset, len });
+    }
+
+    pub fn write<T: Versioned>(self, metadata: &T, filename: &Path) -> Result<(), String> {
+        let header = Header { envelope: file_format::envelope(metadata)?, chunks: self.chunks };
+        let header = serde_json::to_vec(&header)
+            .map_err(|err| format!("Error while serializing the header of {}: {err}", filename.display()))?;
+
+        let data_start = (MAGIC.len() + 8 + header.len()).next_multiple_of(ALIGNMENT);
+        let padding = data_start - (MAGIC.len() + 8 + header.len());
+
+        let temp_filename = filename.with_extension("tmp");
+        let file = File::create(&temp_filename)
+            .map_err(|err| format!("Error while opening {}: {err}", temp_filename.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)
+            .and_then(|_| writer.write_all(&(header.len() as u64).to_le_bytes()))
+            .and_then(|_| writer.write_all(&header))
+            .and_then(|_| writer.write_all(&vec![0; padding]))
+            .and_then(|_| writer.write_all(&self.data))
+            .and_then(|_| writer.flush())
+            .map_err(|err| format!("Error while writing {}: {err}", temp_filename.display()))?;
+
+        fs::rename(&temp_filename, filename)
+            .map_err(|err| format!("Error while writing {}: {err}", filename.display()))
+    }
+}
+
+// Memory-mapped file, chunks are handed out as slices into the mapping and only read from disk when touched
+#[derive(Debug)]
+pub struct BinaryFile {
+    // Absolute, so that json files referring to the file keep working from another directory
+    path: PathBuf,
+    mmap: Mmap,
+    data_start: usize,
+    chunks: HashMap<String, ChunkInfo>,
+}
+
+impl BinaryFile {
+    pub fn open<T: Versioned>(filename: &Path) -> Result<(T, BinaryFile), String> {
+        let file = File::open(filename)
+            .map_err(|err| format!("Error while opening {}: {err}", filename.display()))?;
+        // Safety: the mapping is only valid as long as nobody truncates the file underneath us,
+        // the app never writes to a file in place (see BinaryWriter::write)
+        let mmap = unsafe { Mmap::map(&file) }
+            .map_err(|err| format!("Error while mapping {}: {err}", filename.display()))?;
+
+        if mmap.len() < MAGIC.len() + 8 || &mmap[..MAGIC.len()] != MAGIC {
+            return Err(format!("{} is not a binary SOM file", filename.display()));
+        }
+
+        let header_len = u64::from_le_bytes(mmap[MAGIC.len()..MAGIC.len() + 8].try_into().unwrap());
+        let header_end = usize::try_from(header_len).ok()
+            .and_then(|header_len| header_len.checked_add(MAGIC.len() + 8))
+            .filter(|header_end| *header_end <= mmap.len())
+            .ok_or(format!("The header of {} is truncated", filename.display()))?;
+
+        let header: Header = serde_json::from_slice(&mmap[MAGIC.len() + 8..header_end])
+            .map_err(|err| format!("Error parsing the header of {}: {err}", filename.display()))?;
+        let data_start = header_end.next_multiple_of(ALIGNMENT);
+        let data_len = mmap.len().saturating_sub(data_start);
+        for (name, chunk) in &header.chunks {
+            chunk.validate(data_len).map_err(|err| format!("Chunk `{name}` of {} is invalid: {err}", filename.display()))?;
+        }
+
+        let metadata = file_format::from_value(header.envelope)?;
+        let path = filename.canonicalize().unwrap_or_else(|_| filename.to_path_buf());
+        Ok((metadata, BinaryFile { path, mmap, data_start, chunks: header.chunks }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn chunk(&self, name: &str, chunk_type: ChunkType) -> Result<&ChunkInfo, String> {
+        match self.chunks.get(name) {
+            Some(chunk) if chunk.chunk_type == chunk_type => Ok(chunk),
+            Some(chunk) => Err(format!("Chunk `{name}` holds {:?} instead of {chunk_type:?}", chunk.chunk_type)),
+            None => Err(format!("Missing chunk `{name}`")),
+        }
+    }
+
+    pub fn f32_slice(&self, chunk: &ChunkInfo) -> Result<&[f32], String> {
+        if !cfg!(target_endian = "little") {
+            return Err("Binary files can only be mapped on little-endian machines".to_owned());
+        }
+        let bytes = &self.mmap[self.data_start + chunk.offset..self.data_start + chunk.offset + chunk.len];
+        // The mapping is page aligned and `open` checked that every chunk starts at a multiple of ALIGNMENT
+        let (prefix, values, suffix) = unsafe { bytes.align_to::<f32>() };
+        if !prefix.is_empty() || !suffix.is_empty() {
+            return Err("Misaligned f32 chunk".to_owned());
+        }
+        Ok(values)
+    }
+
+    pub fn u64_values(&self, chunk: &ChunkInfo) -> Vec<u64> {
+        let bytes = &self.mmap[self.data_start + chunk.offset..self.data_start + chunk.offset + chunk.len];
+        bytes.chunks_exact(8).map(|value| u64::from_le_bytes(value.try_into().unwrap())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Metadata {
+        name: String,
+    }
+
+    impl Versioned for Metadata {
+        fn format() -> String {
+            "test_metadata".to_owned()
+        }
+
+        fn migrate(_payload: &mut Value, _from_version: u32) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("msom_binary_{}_{name}.bin", std::process::id()))
+    }
+
+    // A file laid out like BinaryWriter::write does, but with whatever chunk table the test wants
+    fn write_raw(path: &Path, chunks: HashMap<String, ChunkInfo>, data: &[u8]) {
+        let metadata = Metadata { name: "raw".to_owned() };
+        let header = Header { envelope: file_format::envelope(&metadata).unwrap(), chunks };
+        let header = serde_json::to_vec(&header).unwrap();
+        let data_start = (MAGIC.len() + 8 + header.len()).next_multiple_of(ALIGNMENT);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.resize(data_start, 0);
+        bytes.extend_from_slice(data);
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn open_error(path: &Path) -> String {
+        let res = BinaryFile::open::<Metadata>(path);
+        fs::remove_file(path).unwrap();
+        res.expect_err("the file should have been rejected")
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round_trip");
+        let mut writer = BinaryWriter::default();
+        writer.add_f32("values", &[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        // Odd length, so that the next chunk needs padding
+        writer.add_f32("odd", &[1], &[7.0]);
+        writer.add_u64("counts", &[3, 1, 4]);
+        writer.write(&Metadata { name: "test".to_owned() }, &path).unwrap();
+
+        let (metadata, file) = BinaryFile::open::<Metadata>(&path).unwrap();
+        assert_eq!(metadata, Metadata { name: "test".to_owned() });
+        let values = file.chunk("values", ChunkType::F32).unwrap();
+        assert_eq!(values.shape, vec![2, 3]);
+        assert_eq!(file.f32_slice(values).unwrap(), &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(file.f32_slice(file.chunk("odd", ChunkType::F32).unwrap()).unwrap(), &[7.0]);
+        assert_eq!(file.u64_values(file.chunk("counts", ChunkType::U64).unwrap()), vec![3, 1, 4]);
+        assert!(file.chunk("counts", ChunkType::F32).is_err());
+        assert!(file.chunk("missing", ChunkType::F32).is_err());
+
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_file() {
+        let path = temp_path("truncated");
+        let mut writer = BinaryWriter::default();
+        writer.add_f32("values", &[4], &[1.0, 2.0, 3.0, 4.0]);
+        writer.write(&Metadata { name: "test".to_owned() }, &path).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert!(open_error(&path).contains("truncated"));
+
+        fs::write(&path, &bytes[..MAGIC.len() + 8 + 3]).unwrap();
+        assert!(open_error(&path).contains("header"));
+
+        fs::write(&path, b"not a binary file").unwrap();
+        assert!(open_error(&path).contains("not a binary SOM file"));
+    }
+
+    #[test]
+    fn corrupt_chunk_table() {
+        let chunk = |offset, len, shape: &[usize]| ChunkInfo { chunk_type: ChunkType::F32, shape: shape.to_vec(), offset, len };
+        let cases = [
+            ("overflow", chunk(usize::MAX - 3, 8, &[2]), "overflows"),
+            ("misaligned", chunk(4, 8, &[2]), "multiple of 8"),
+            ("partial_value", chunk(0, 6, &[1]), "multiple of 4"),
+            ("wrong_shape", chunk(0, 8, &[3]), "shape"),
+            ("shape_overflow", chunk(0, 8, &[usize::MAX, 2]), "shape overflows"),
+            ("past_the_end", chunk(8, 16, &[4]), "truncated"),
+        ];
+
+        for (name, info, message) in cases {
+            let path = temp_path(name);
+            write_raw(&path, HashMap::from([("values".to_owned(), info)]), &[0; 16]);
+            let err = open_error(&path);
+            assert!(err.contains(message), "{name}: {err}");
+        }
+    }
+}
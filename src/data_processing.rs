@@ -1,13 +1,14 @@
-use std::{clone, collections::HashSet, fs::File, io::{BufReader, Read}, path::PathBuf, sync::{Arc, Mutex}, thread, time::Duration, vec};
-use finalfusion::prelude::*;
+use std::{clone, collections::HashSet, fs::File, io::{BufReader, Read}, path::{Path, PathBuf}, sync::{Arc, Mutex, OnceLock}, thread, time::Duration, vec};
+use finalfusion::{prelude::*, similarity::EmbeddingSimilarity, storage::NdArray, vocab::SimpleVocab};
 
 use egui::{include_image, CentralPanel, Color32, ComboBox, DragValue, Frame, Grid, Image, Layout, Rounding, ScrollArea, Sense, SidePanel, Stroke, Style, Ui, Vec2};
-use ndarray::{concatenate, Array1, Axis};
+use ndarray::{concatenate, s, Array1, ArrayView1, Axis};
 use rfd::FileDialog;
 
 use tqdm::tqdm;
-use crate::{msom::MSOM, SOMParams};
-use serde::{Serialize, Deserialize};
+use crate::{binary_format::{BinaryFile, BinaryWriter, ChunkInfo, ChunkType}, file_format::{self, remove_field, Versioned}, msom::MSOM, provenance::{content_hash, new_id}, registry::Registry, som_pak, SOMParams};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 
 const DATASET_SEPARATOR: &str = "-=-=-=-=-=-=-";
 // const DATASET_SEPARATOR: &str = "\n";
@@ -18,7 +19,123 @@ enum ProcessingType {
     DatasetContext
 }
 
-fn process_word2vec(dataset: Arc<Mutex<DataSet>>, params: SOMParams) -> Vec<Array1<f32>> {
+const EMBEDDINGS_PATH: &str = "./resources/glove-twitter-25.txt";
+static EMBEDDINGS: OnceLock<Option<Embeddings<SimpleVocab, NdArray>>> = OnceLock::new();
+
+// Parsing the text file takes a while, so it is done once and shared
+pub fn embeddings() -> Option<&'static Embeddings<SimpleVocab, NdArray>> {
+    EMBEDDINGS.get_or_init(|| {
+        let mut reader = BufReader::new(File::open(EMBEDDINGS_PATH).ok()?);
+        Embeddings::read_text_dims(&mut reader).ok()
+    }).as_ref()
+}
+
+pub fn embeddings_loaded() -> bool {
+    EMBEDDINGS.get().is_some()
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .map(|word| word.to_owned())
+        .collect()
+}
+
+// Everything needed to turn a new text into the same kind of vector the dataset was processed into
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum TextPipeline {
+    // Histogram of the text's words over a map trained on the dataset's vocabulary
+    Word2Vec { word_map: MSOM },
+    // Concatenated word embeddings, one chunk per word
+    DatasetContext,
+}
+
+impl TextPipeline {
+    pub fn embed(&self, text: &str) -> Result<Array1<f32>, &str> {
+        let embeddings = embeddings().ok_or("Error while loading the word embeddings")?;
+        let words = tokenize(text);
+
+        match self {
+            TextPipeline::Word2Vec { word_map } => {
+                let mut cur_vec: Vec<f32> = vec![0.0; word_map.n * word_map.m];
+
+                for word in words {
+                    if let Some(map_pos) = embeddings.embedding(&word).and_then(|word_vec| word_map.evaluate(word_vec.view())) {
+                        cur_vec[map_pos.0 * word_map.m + map_pos.1] += 1.0;
+                    }
+                }
+                let vec_sum = cur_vec.iter().sum::<f32>();
+                if vec_sum != 0.0 {
+                    Ok(Array1::from_vec(cur_vec) / vec_sum)
+                }
+                else {
+                    Ok(Array1::from_vec(cur_vec))
+                }
+            }
+            TextPipeline::DatasetContext => {
+                let vecs: Vec<_> = words
+                    .iter()
+                    .filter_map(|word| embeddings.embedding(word))
+                    .collect();
+                if vecs.is_empty() {
+                    return Ok(Array1::zeros(0));
+                }
+
+                let view_vec = vecs.iter().map(|a| a.view()).collect::<Vec<_>>();
+                Ok(concatenate(Axis(0), view_vec.as_slice()).unwrap())
+            }
+        }
+    }
+
+    // Vocabulary words closest to a prototype of a map trained on this pipeline's output. The score is the
+    // cosine similarity for DatasetContext and the histogram weight of the word's unit for Word2Vec
+    pub fn nearest_words(&self, prototype: ArrayView1<f32>, limit: usize) -> Result<Vec<(String, f32)>, String> {
+        let embeddings = embeddings().ok_or("Error while loading the word embeddings")?;
+        let nearest = |query: ArrayView1<f32>, limit: usize| -> Result<Vec<(String, f32)>, String> {
+            if query.len() != embeddings.dims() {
+                return Err(format!("Prototype length {} doesn't match the embedding size {}", query.len(), embeddings.dims()));
+            }
+            Ok(embeddings.embedding_similarity(query, limit, None).unwrap_or_default().iter()
+                .map(|result| (result.word().to_owned(), result.cosine_similarity()))
+                .collect())
+        };
+
+        match self {
+            TextPipeline::DatasetContext => nearest(prototype, limit),
+            TextPipeline::Word2Vec { word_map } => {
+                if prototype.len() != word_map.n * word_map.m {
+                    return Err(format!("Prototype length {} doesn't match the word map size {}", prototype.len(), word_map.n * word_map.m));
+                }
+
+                // The heaviest units of the histogram stand for the words, each is named after the word closest to its prototype
+                let mut units: Vec<(usize, f32)> = prototype.iter().copied().enumerate().filter(|(_, weight)| *weight > 0.0).collect();
+                units.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+                let mut words = vec![];
+                for (unit, weight) in units.into_iter().take(limit) {
+                    let unit_prototype = word_map.som().slice(s![unit / word_map.m, unit % word_map.m, ..]);
+                    if let Some((word, _)) = nearest(unit_prototype, 1)?.into_iter().next() {
+                        words.push((word, weight));
+                    }
+                }
+                Ok(words)
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            TextPipeline::Word2Vec { .. } => "Word2Vec",
+            TextPipeline::DatasetContext => "DatasetContext",
+        }
+    }
+}
+
+fn process_word2vec(dataset: Arc<Mutex<DataSet>>, params: SOMParams) -> (Vec<Array1<f32>>, TextPipeline) {
     // let n = 10;
     // let m = 10;
     // let map_input_size = 25;
@@ -30,30 +147,18 @@ fn process_word2vec(dataset: Arc<Mutex<DataSet>>, params: SOMParams) -> Vec<Arra
     // let gauss_width_squared_base = 10000.0; 
     // let time_constant = 200.0;
 
-    let mut reader =
-        BufReader::new(File::open("./resources/glove-twitter-25.txt").unwrap());
-    let embeddings = Embeddings::read_text_dims(&mut reader).unwrap();
+    let embeddings = embeddings().unwrap();
 
     let lines = dataset.lock().unwrap().raw_data.clone();
-    let mut processed_texts = vec![];
 
     let mut dictionary: HashSet<String> = HashSet::new();
 
-    for sample in lines {
-        let stripped_contents = sample
-            .to_lowercase()
-            .chars()
-            .filter(|c| c.is_alphanumeric() || c.is_whitespace())
-            .collect::<String>();
-
-        let words: Vec<&str> = stripped_contents.split_whitespace().into_iter().collect();
-        for word in words {
-            if embeddings.embedding(word).is_some() {
-                dictionary.insert(word.to_owned());
+    for sample in lines.iter() {
+        for word in tokenize(sample) {
+            if embeddings.embedding(&word).is_some() {
+                dictionary.insert(word);
             }
         }
-        
-        processed_texts.push(stripped_contents);
     }
     
     let words: Vec<String> = dictionary.drain().collect();
@@ -65,72 +170,26 @@ fn process_word2vec(dataset: Arc<Mutex<DataSet>>, params: SOMParams) -> Vec<Arra
 
     let mut word_map = MSOM::new(params.n, params.m, params.map_input_size, params.a, params.b, params.gamma);
     word_map.fit(&word_vecs.iter().map(|sample| sample.view()).collect(), 
-        params.train_iterations, params.learning_rate_base, params.gauss_width_squared_base, params.time_constant);
+        params.train_iterations, params.learning_rate_base, params.gauss_width_squared_base, params.time_constant, 0);
 
     println!("Word map, text vec sizes {}", words.len());
     
+    let pipeline = TextPipeline::Word2Vec { word_map };
+    let text_vecs = tqdm(lines.iter()).map(|text| pipeline.embed(text).unwrap()).collect();
 
-    let mut text_vecs = vec![];
-    for text in tqdm(processed_texts.iter()) {
-        let text_words: Vec<&str> = text.split_whitespace().into_iter().collect();
-        let mut cur_vec: Vec<f32> = vec![0.0; params.n * params.m];
-
-        for word in text_words {
-            if let Some(word_vec) = embeddings.embedding(word) {
-                let map_pos = word_map.evaluate(word_vec.view());
-                cur_vec[map_pos.0 * params.m + map_pos.1] += 1.0;
-            }
-        }
-        let vec_sum  = cur_vec.iter().sum::<f32>();
-        if vec_sum != 0.0 {
-            text_vecs.push(Array1::from_vec(cur_vec) / vec_sum);
-        }
-        else {
-            text_vecs.push(Array1::from_vec(cur_vec));
-        }
-    }
-
-    text_vecs
+    (text_vecs, pipeline)
 }
 
-fn process_dataset_context(dataset: Arc<Mutex<DataSet>>) -> Vec<Array1<f32>> {
-    let mut reader =
-        BufReader::new(File::open("./resources/glove-twitter-25.txt").unwrap());
-    let embeddings = Embeddings::read_text_dims(&mut reader).unwrap();
-
-    let mut result = vec![];
-    let lines = dataset.lock().unwrap().raw_data.clone();
-
-    for sample in lines {
-        let stripped_contents = sample
-            .to_lowercase()
-            .chars()
-            .filter(|c| c.is_alphanumeric() || c.is_whitespace())
-            .collect::<String>();
-
-        let words: Vec<&str> = stripped_contents.split_whitespace().into_iter().collect();
-        if words.len() > 0 {
-            let vecs: Vec<_> = words
-                .iter()
-                .filter_map(|word| embeddings.embedding(word))
-                .collect();
-
-            let view_vec = vecs.iter().map(|a| a.view()).collect::<Vec<_>>();
-            let final_vec = concatenate(Axis(0), view_vec.as_slice())
-                .unwrap()
-                .map(|x| *x as f32);
-
-            result.push(final_vec);
-        }
-    }
-    vec![]
+// Not done yet, the texts aren't turned into samples so far
+fn process_dataset_context(_dataset: Arc<Mutex<DataSet>>) -> (Vec<Array1<f32>>, TextPipeline) {
+    (vec![], TextPipeline::DatasetContext)
 }
 
 // Mutex gives interior mutability!
 // This finally makes sense
 pub fn process_dataset(dataset: Arc<Mutex<DataSet>>, processing_type: ProcessingType, params: SOMParams) {
     dataset.lock().unwrap().is_being_processed = true;
-    let result = match processing_type {
+    let (result, pipeline) = match processing_type {
         ProcessingType::Word2Vec => process_word2vec(dataset.clone(), params),
         ProcessingType::DatasetContext => process_dataset_context(dataset.clone()),
     };
@@ -142,78 +201,238 @@ pub fn process_dataset(dataset: Arc<Mutex<DataSet>>, processing_type: Processing
 
     dataset.lock().unwrap().is_being_processed = false;
     println!("{result:?}");
-    dataset.lock().unwrap().processed_data = Some(result);
+    dataset.lock().unwrap().processed_data = Some(SampleStore::Owned(Arc::new(result)));
+    dataset.lock().unwrap().samples_hash = None;
+    dataset.lock().unwrap().pipeline = Some(pipeline);
+}
+
+// Processed samples, either computed in memory or mapped from a binary file.
+// Cloning only clones the handle
+#[derive(Debug, Clone)]
+pub enum SampleStore {
+    Owned(Arc<Vec<Array1<f32>>>),
+    Mapped {
+        file: Arc<BinaryFile>,
+        data: ChunkInfo,
+        // Sample i is data[offsets[i]..offsets[i + 1]]
+        offsets: Arc<Vec<u64>>,
+    },
+}
+
+impl SampleStore {
+    pub fn len(&self) -> usize {
+        match self {
+            SampleStore::Owned(samples) => samples.len(),
+            SampleStore::Mapped { offsets, .. } => offsets.len() - 1,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<ArrayView1<'_, f32>> {
+        match self {
+            SampleStore::Owned(samples) => samples.get(index).map(|sample| sample.view()),
+            SampleStore::Mapped { file, data, offsets } => {
+                if index + 1 >= offsets.len() {
+                    return None;
+                }
+                let values = file.f32_slice(data).ok()?;
+                values.get(offsets[index] as usize..offsets[index + 1] as usize).map(ArrayView1::from)
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ArrayView1<'_, f32>> {
+        (0..self.len()).map(|index| self.get(index).unwrap())
+    }
+
+    pub fn views(&self) -> Vec<ArrayView1<'_, f32>> {
+        self.iter().collect()
+    }
+}
+
+impl PartialEq for SampleStore {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+// How a store is written to json files. Owned samples keep the representation of the plain Vec<Array1<f32>>
+// that used to be stored, so json files stay compatible. Mapped samples only refer to their binary file,
+// writing them out would copy the whole file into every save and autosave
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredSamples {
+    Owned(Vec<Array1<f32>>),
+    BinaryFile { binary_file: PathBuf, len: usize },
+}
+
+impl Serialize for SampleStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SampleStore::Owned(_) => serializer.collect_seq(self.iter()),
+            SampleStore::Mapped { file, .. } => {
+                StoredSamples::BinaryFile { binary_file: file.path().to_path_buf(), len: self.len() }.serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SampleStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match StoredSamples::deserialize(deserializer)? {
+            StoredSamples::Owned(samples) => Ok(SampleStore::Owned(Arc::new(samples))),
+            StoredSamples::BinaryFile { binary_file, len } => {
+                let store = DataSet::from_binary_file(&binary_file)
+                    .and_then(|dataset| dataset.processed_data.ok_or(format!("{} holds no samples", binary_file.display())))
+                    .map_err(serde::de::Error::custom)?;
+                // The file was overwritten with another dataset since
+                if store.len() != len {
+                    return Err(serde::de::Error::custom(format!("{} holds {} samples instead of {len}", binary_file.display(), store.len())));
+                }
+                Ok(store)
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct DataSet {
-    pub raw_data: Vec<String>,
-    pub processed_data: Option<Vec<Array1<f32>>>,
+    // Stays the same through renames and saves, visualizations refer to their dataset by it
+    #[serde(default = "new_id")]
+    pub id: String,
+    // Shared with the copies that background work takes, so that copying a dataset stays cheap
+    pub raw_data: Arc<Vec<String>>,
+    pub processed_data: Option<SampleStore>,
     pub name: String,
+    #[serde(skip)]
     is_being_processed: bool,
+
+    #[serde(default)]
+    pub pipeline: Option<TextPipeline>,
+    // Class labels of the samples, e.g. read from a SOM_PAK data file
+    #[serde(default)]
+    pub labels: Option<Arc<Vec<String>>>,
+    #[serde(skip)]
+    samples_hash: Option<String>,
+}
+
+impl Versioned for DataSet {
+    fn format() -> String {
+        "dataset".to_owned()
+    }
+
+    fn migrate(payload: &mut Value, from_version: u32) -> Result<(), String> {
+        match from_version {
+            // Runtime status used to be saved along with the data
+            0 => remove_field(payload, "is_being_processed"),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl DataSet {
+    pub fn new(name: String, raw_data: Vec<String>) -> Self {
+        Self {
+            id: new_id(),
+            raw_data: Arc::new(raw_data),
+            processed_data: None,
+            name,
+            is_being_processed: false,
+            pipeline: None,
+            labels: None,
+            samples_hash: None,
+        }
+    }
+
+    // For data that comes already vectorized, there is no pipeline to embed new texts then
+    pub fn from_samples(name: String, raw_data: Vec<String>, samples: Vec<Array1<f32>>, labels: Option<Vec<String>>) -> Self {
+        Self {
+            processed_data: Some(SampleStore::Owned(Arc::new(samples))),
+            labels: labels.map(Arc::new),
+            ..Self::new(name, raw_data)
+        }
+    }
+
     pub fn is_processed(&self) -> bool {
         self.processed_data.is_some()
     }
 
-    fn from_file(filename: &PathBuf) -> Result<Self, &str> {
-        let writer = File::options().read(true).open(filename);
-        if writer.is_err() {
-            return Err("Error while opening the file");
+    // Of the processed samples, worked out once
+    pub fn samples_hash(&mut self) -> Option<String> {
+        if self.samples_hash.is_none() {
+            let samples = self.processed_data.as_ref()?;
+            self.samples_hash = Some(content_hash(samples.iter().flat_map(|sample| sample.into_iter().copied())));
         }
+        self.samples_hash.clone()
+    }
 
-        let res: Result<Self, serde_json::Error> = serde_json::from_reader(writer.unwrap());
+    fn from_file(filename: &Path) -> Result<Self, String> {
+        file_format::from_file(filename)
+    }
 
-        if res.is_err() {
-            Err("Error parsing the dataset")
-        }
-        else {
-            Ok(res.unwrap())
-        }
+    fn to_file(&self, filename: &Path) -> Result<(), String> {
+        file_format::to_file(self, filename)
     }
 
-    fn to_file(&self, filename: &PathBuf) -> Result<(), &str> {
-        // let json = serde_json::to_vec(&self.map_weights)?;
-        let writer = File::options().write(true).create(true).open(filename);
-        if writer.is_err() {
-            return Err("Error while opening the file");
+    // Everything except the processed samples goes into the header, the samples are stored
+    // back to back as one f32 chunk and are memory-mapped on load
+    fn to_binary_file(&self, filename: &Path) -> Result<(), String> {
+        let mut writer = BinaryWriter::default();
+        if let Some(samples) = &self.processed_data {
+            let mut offsets = vec![0];
+            for sample in samples.iter() {
+                offsets.push(offsets.last().unwrap() + sample.len() as u64);
+            }
+
+            writer.add_f32("processed_data", &[*offsets.last().unwrap() as usize], samples.iter().flatten());
+            writer.add_u64("sample_offsets", &offsets);
         }
 
-        let res = serde_json::to_writer_pretty(writer.unwrap(), self);
+        let metadata = DataSet { processed_data: None, ..self.clone() };
+        writer.write(&metadata, filename)
+    }
 
-        if res.is_err() {
-            Err("Error while serializing the dataset")
-        }
-        else {
-            Ok(())
+    fn from_binary_file(filename: &Path) -> Result<Self, String> {
+        let (mut dataset, file): (DataSet, _) = BinaryFile::open(filename)?;
+        let file = Arc::new(file);
+
+        if let (Ok(data), Ok(offsets)) = (file.chunk("processed_data", ChunkType::F32), file.chunk("sample_offsets", ChunkType::U64)) {
+            let data = data.clone();
+            file.f32_slice(&data)?;
+            let offsets = file.u64_values(offsets);
+            // Checked against the chunk itself, so that every sample can be sliced out of it
+            if offsets.is_empty() || offsets.windows(2).any(|pair| pair[0] > pair[1]) || *offsets.last().unwrap() > data.value_count() as u64 {
+                return Err(format!("Invalid sample offsets in {}", filename.display()));
+            }
+
+            dataset.processed_data = Some(SampleStore::Mapped { file: file.clone(), data, offsets: Arc::new(offsets) });
         }
+
+        Ok(dataset)
     }
 }
 
 #[derive(Debug)]
 pub struct DataProcessingUI {
-    pub datasets: Vec<Arc<Mutex<DataSet>>>,
-    shown_dataset_index: Option<usize>, 
+    shown_dataset_id: Option<String>, 
     current_processing_type: ProcessingType,
     current_params: SOMParams,
 }
 
 impl Default for DataProcessingUI {
     fn default() -> Self {
-        Self { datasets: vec![], shown_dataset_index: None, current_processing_type: ProcessingType::Word2Vec, 
+        Self { shown_dataset_id: None, current_processing_type: ProcessingType::Word2Vec, 
             current_params: SOMParams::default() }
     }
 }
 
 impl DataProcessingUI {
-    fn dataset_list(&mut self, ui: &mut Ui) {
-        for (index, dataset) in self.datasets.iter().enumerate() {
+    fn dataset_list(&mut self, ui: &mut Ui, registry: &mut Registry) {
+        let mut removed = None;
+        for (_, dataset) in registry.datasets.iter() {
             let dataset = dataset.lock().unwrap();
 
             let frame_style = Style::default();
-            let is_current = Some(index) == self.shown_dataset_index;
+            let is_current = self.shown_dataset_id.as_ref() == Some(&dataset.id);
             let stroke_color = if is_current {
                 Color32::DARK_GRAY
             }
@@ -255,7 +474,7 @@ impl DataProcessingUI {
 
             let response = frame.allocate_space(ui).on_hover_cursor(egui::CursorIcon::PointingHand).interact(Sense::click());
             if response.clicked() {
-                self.shown_dataset_index = Some(index);
+                self.shown_dataset_id = Some(dataset.id.clone());
             }
 
             response.context_menu(|ui| {
@@ -274,6 +493,43 @@ impl DataProcessingUI {
 
                     ui.close_menu();
                 }
+
+                if ui.button("Save to binary file").clicked() {
+                    let files = FileDialog::new()
+                        .add_filter("Binary dataset with memory-mapped samples", &["bin_set"])
+                        .set_directory(".")
+                        .save_file();
+                    
+                    if let Some(path) = files {
+                        let res = dataset.to_binary_file(&path);
+                        if res.is_err() {
+                            println!("{}", res.err().unwrap());
+                        }
+                    }
+
+                    ui.close_menu();
+                }
+
+                if dataset.is_processed() && ui.button("Export to SOM_PAK data").clicked() {
+                    let files = FileDialog::new()
+                        .add_filter("SOM_PAK data file", &["dat"])
+                        .set_directory(".")
+                        .save_file();
+                    
+                    if let Some(path) = files {
+                        let res = som_pak::write_dat(&dataset, &path);
+                        if res.is_err() {
+                            println!("{}", res.err().unwrap());
+                        }
+                    }
+
+                    ui.close_menu();
+                }
+
+                if ui.add_enabled(!dataset.is_being_processed, egui::Button::new("Remove")).clicked() {
+                    removed = Some(dataset.id.clone());
+                    ui.close_menu();
+                }
             });
 
             if response.hovered() {
@@ -281,25 +537,33 @@ impl DataProcessingUI {
             }
             frame.paint(ui);
         }
+
+        if let Some(id) = removed {
+            registry.remove_dataset(&id);
+        }
     }
 
-    pub fn show(&mut self, ui: &mut Ui) {
+    pub fn show(&mut self, ui: &mut Ui, registry: &mut Registry) {
         ui.painter().rect_filled(ui.max_rect(), Rounding::ZERO, Color32::WHITE);
-        if let Some(ind) = self.shown_dataset_index {
+        let shown_dataset = self.shown_dataset_id.as_ref().and_then(|id| registry.dataset(id)).cloned();
+        if shown_dataset.is_none() {
+            self.shown_dataset_id = None;
+        }
+        if let Some(shown_dataset) = shown_dataset {
             SidePanel::right("tooltip_data")
             .resizable(true)
             .show_inside(ui, |ui| {
-                let chosen_dataset = &mut self.datasets[ind].lock().unwrap();
+                let chosen_dataset = &mut shown_dataset.lock().unwrap();
                 ui.text_edit_singleline(&mut chosen_dataset.name);
                 
                 Grid::new("Parameters").show(ui, |ui| {
                     ui.label("Processing Type: ");
-                    if chosen_dataset.is_processed() {
-                        ui.label("Word2Vec 100");
-                    }
-                    else {
-                        ui.label("Unprocessed (Raw)");
-                    }
+                    match &chosen_dataset.pipeline {
+                        Some(pipeline) => ui.label(pipeline.name()),
+                        // Processed elsewhere, e.g. a SOM_PAK data file
+                        None if chosen_dataset.is_processed() => ui.label("Imported"),
+                        None => ui.label("Unprocessed (Raw)"),
+                    };
                     ui.end_row();
 
                     let n = 10;
@@ -353,7 +617,7 @@ impl DataProcessingUI {
 
                 if ui.button("Apply chosen processing").clicked() {
                     // ToDo: Add the actual processing and maybe add processing types to dataset struct
-                    let cloned_dataset = self.datasets[ind].clone();
+                    let cloned_dataset = shown_dataset.clone();
                     let cloned_processing_type = self.current_processing_type.clone();
                     let cloned_params = self.current_params.clone();
                     thread::spawn(|| {
@@ -367,10 +631,10 @@ impl DataProcessingUI {
 
         ScrollArea::vertical().show(ui, |ui| {
             ui.vertical_centered(|ui| {
-                if self.datasets.len() == 0 {
+                if registry.datasets.is_empty() {
                     ui.label("No datasets loaded");
                 }
-                self.dataset_list(ui);
+                self.dataset_list(ui, registry);
         
                 if ui.button("Create a new dataset from file").clicked() {
                     let files = FileDialog::new()
@@ -385,13 +649,8 @@ impl DataProcessingUI {
                             let res = open_file.read_to_string(&mut file_contents);
                             if res.is_ok() {
                                 let raw_data = file_contents.split(DATASET_SEPARATOR).map(|val| val.to_string()).collect();
-                                self.datasets.push(Arc::new(Mutex::new(DataSet {
-                                    raw_data,
-                                    processed_data: None,
-                                    name: path.file_name().unwrap().to_os_string().into_string().unwrap(),
-                                    is_being_processed: false,
-                                })));
-                                self.shown_dataset_index = Some(self.datasets.len() - 1);
+                                let name = path.file_name().unwrap().to_os_string().into_string().unwrap();
+                                self.shown_dataset_id = Some(registry.add_dataset(DataSet::new(name, raw_data)));
                             }
                         }
                     }
@@ -403,16 +662,37 @@ impl DataProcessingUI {
 
                     let files = FileDialog::new()
                             .add_filter("Serde json file with dataset structure", &["json_set"])
+                            .add_filter("Binary dataset with memory-mapped samples", &["bin_set"])
                             .set_directory(".")
                             .pick_file();
                         
                     if let Some(path) = files {
-                        let res = DataSet::from_file(&path);
+                        let res = if path.extension().is_some_and(|extension| extension == "bin_set") {
+                            DataSet::from_binary_file(&path)
+                        } else {
+                            DataSet::from_file(&path)
+                        };
                         if res.is_err() {
                             println!("{}", res.err().unwrap());
                         }
                         else {
-                            self.datasets.push(Arc::new(Mutex::new(res.unwrap())));
+                            registry.add_dataset(res.unwrap());
+                        }
+                    }
+                }
+
+                if ui.button("Import SOM_PAK data").clicked() {
+                    let files = FileDialog::new()
+                            .add_filter("SOM_PAK data file", &["dat"])
+                            .set_directory(".")
+                            .pick_file();
+
+                    if let Some(path) = files {
+                        match som_pak::read_dat(&path) {
+                            Ok(dataset) => {
+                                self.shown_dataset_id = Some(registry.add_dataset(dataset));
+                            }
+                            Err(err) => println!("{err}"),
                         }
                     }
                 }
@@ -421,3 +701,69 @@ impl DataProcessingUI {
         
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+
+    #[test]
+    fn binary_round_trip() {
+        let path = std::env::temp_dir().join(format!("msom_dataset_{}_round_trip.bin", std::process::id()));
+        let samples = vec![array![1.0, 2.0], array![3.0], array![], array![4.0, 5.0, 6.0]];
+        let labels = vec!["a".to_owned(), "b".to_owned(), "c".to_owned(), "d".to_owned()];
+        let dataset = DataSet::from_samples("test".to_owned(), vec!["one".to_owned()], samples.clone(), Some(labels));
+        dataset.to_binary_file(&path).unwrap();
+
+        let loaded = DataSet::from_binary_file(&path).unwrap();
+        assert!(matches!(loaded.processed_data, Some(SampleStore::Mapped { .. })));
+        assert_eq!(loaded, dataset);
+        let store = loaded.processed_data.as_ref().unwrap();
+        assert_eq!(store.views(), samples.iter().map(|sample| sample.view()).collect::<Vec<_>>());
+        assert!(store.get(4).is_none());
+
+        drop(loaded);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_refers_to_the_binary_file() {
+        let path = std::env::temp_dir().join(format!("msom_dataset_{}_json_reference.bin", std::process::id()));
+        let dataset = DataSet::from_samples("test".to_owned(), vec![], vec![array![1.0, 2.0], array![3.0]], None);
+        dataset.to_binary_file(&path).unwrap();
+
+        let loaded = DataSet::from_binary_file(&path).unwrap();
+        let json = serde_json::to_value(&loaded).unwrap();
+        assert_eq!(json["processed_data"]["len"], 2);
+        assert!(json["processed_data"]["binary_file"].is_string());
+        assert_eq!(serde_json::from_value::<DataSet>(json.clone()).unwrap(), dataset);
+
+        // Owned samples are still written out in full
+        let owned = serde_json::to_value(&dataset).unwrap();
+        assert_eq!(owned["processed_data"].as_array().map(Vec::len), Some(2));
+        assert_eq!(serde_json::from_value::<DataSet>(owned).unwrap(), dataset);
+
+        let mut wrong_len = json;
+        wrong_len["processed_data"]["len"] = 3.into();
+        assert!(serde_json::from_value::<DataSet>(wrong_len).is_err());
+
+        drop(loaded);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn offsets_past_the_samples() {
+        let path = std::env::temp_dir().join(format!("msom_dataset_{}_bad_offsets.bin", std::process::id()));
+        for offsets in [vec![0, 2, 6], vec![0, 3, 1], vec![]] {
+            let mut writer = BinaryWriter::default();
+            writer.add_f32("processed_data", &[4], &[1.0, 2.0, 3.0, 4.0]);
+            writer.add_u64("sample_offsets", &offsets);
+            writer.write(&DataSet::new("test".to_owned(), vec![]), &path).unwrap();
+
+            let err = DataSet::from_binary_file(&path).expect_err("the offsets should have been rejected");
+            assert!(err.contains("Invalid sample offsets"), "{offsets:?}: {err}");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
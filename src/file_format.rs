@@ -0,0 +1,201 @@
+use std::{fs::{self, File}, io::{BufReader, BufWriter, Write}, path::Path, sync::{Arc, Mutex}};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+// Bumped when a saved field is removed, renamed or changes meaning, together with a step in the matching
+// `Versioned::migrate`. Adding a field with #[serde(default)] doesn't bump it, older files simply lack the field.
+// 1: runtime status is no longer saved
+// 2: snapshots only go into binary map files, visualizations read the anomaly calibration from their map and
+//    store the input-space quantization error instead of the combined one
+pub const FORMAT_VERSION: u32 = 2;
+
+pub trait Versioned: Serialize + DeserializeOwned {
+    fn format() -> String;
+
+    // Upgrades a payload written with `from_version` to `from_version + 1`
+    fn migrate(payload: &mut Value, from_version: u32) -> Result<(), String>;
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    format: String,
+    format_version: u32,
+    producer_version: &'static str,
+    created_at: String,
+    payload: &'a T,
+}
+
+pub fn to_file<T: Versioned>(value: &T, filename: &Path) -> Result<(), String> {
+    write_json(filename, &new_envelope(value))
+}
+
+// The envelope as a JSON value, for containers that embed it in their own header
+pub fn envelope<T: Versioned>(value: &T) -> Result<Value, String> {
+    serde_json::to_value(new_envelope(value))
+        .map_err(|err| format!("Error while serializing the {}: {err}", T::format()))
+}
+
+fn new_envelope<T: Versioned>(value: &T) -> Envelope<'_, T> {
+    Envelope {
+        format: T::format(),
+        format_version: FORMAT_VERSION,
+        producer_version: env!("CARGO_PKG_VERSION"),
+        created_at: chrono::Utc::now().to_rfc3339(),
+        payload: value,
+    }
+}
+
+pub fn from_file<T: Versioned>(filename: &Path) -> Result<T, String> {
+    from_value(read_json(filename)?)
+}
+
+pub fn from_value<T: Versioned>(value: Value) -> Result<T, String> {
+    let (version, mut payload) = match value {
+        Value::Object(mut envelope) if envelope.contains_key("format_version") => {
+            let format = envelope.get("format").and_then(|format| format.as_str()).unwrap_or_default();
+            if format != T::format() {
+                return Err(format!("Expected a {} file, found a {format} file", T::format()));
+            }
+
+            let version = envelope.get("format_version").and_then(|version| version.as_u64())
+                .ok_or("Invalid envelope at `format_version`: expected an unsigned integer")? as u32;
+            if version > FORMAT_VERSION {
+                let producer = envelope.get("producer_version").and_then(|producer| producer.as_str()).unwrap_or("unknown");
+                return Err(format!("The file has format version {version} (written by version {producer}), \
+                    only versions up to {FORMAT_VERSION} are supported"));
+            }
+
+            let payload = envelope.remove("payload").ok_or("Invalid envelope: missing field `payload`")?;
+            (version, payload)
+        }
+        // Files written before the envelope existed are bare dumps of the structs
+        value => (0, value),
+    };
+
+    for from_version in version..FORMAT_VERSION {
+        T::migrate(&mut payload, from_version)
+            .map_err(|err| format!("Error migrating the {} from version {from_version}: {err}", T::format()))?;
+    }
+
+    serde_path_to_error::deserialize(payload)
+        .map_err(|err| format!("Invalid {} at `{}`: {}", T::format(), err.path(), err.inner()))
+}
+
+pub fn read_json<T: DeserializeOwned>(filename: &Path) -> Result<T, String> {
+    let file = File::open(filename)
+        .map_err(|err| format!("Error while opening {}: {err}", filename.display()))?;
+
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| format!("Error parsing {}: {err}", filename.display()))
+}
+
+pub fn write_json<T: Serialize>(filename: &Path, value: &T) -> Result<(), String> {
+    if let Some(parent) = filename.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|err| format!("Error while creating {}: {err}", parent.display()))?;
+    }
+
+    // Written next to the target first, so that a crash mid-write doesn't destroy the previous file
+    let temp_filename = filename.with_extension("tmp");
+    let file = File::create(&temp_filename)
+        .map_err(|err| format!("Error while opening {}: {err}", temp_filename.display()))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, value)
+        .map_err(|err| format!("Error while serializing {}: {err}", filename.display()))?;
+    writer.flush()
+        .map_err(|err| format!("Error while writing {}: {err}", temp_filename.display()))?;
+
+    fs::rename(&temp_filename, filename)
+        .map_err(|err| format!("Error while writing {}: {err}", filename.display()))
+}
+
+// Drops a field that is no longer stored, missing fields are fine since the file could be older still
+pub fn remove_field(payload: &mut Value, field: &str) -> Result<(), String> {
+    match payload {
+        Value::Object(object) => {
+            object.remove(field);
+            Ok(())
+        }
+        _ => Err("expected an object".to_owned()),
+    }
+}
+
+impl<T: Versioned> Versioned for Vec<T> {
+    fn format() -> String {
+        format!("{}_list", T::format())
+    }
+
+    fn migrate(payload: &mut Value, from_version: u32) -> Result<(), String> {
+        match payload {
+            Value::Array(items) => items.iter_mut()
+                .enumerate()
+                .try_for_each(|(index, item)| T::migrate(item, from_version).map_err(|err| format!("item {index}: {err}"))),
+            _ => Err("expected an array".to_owned()),
+        }
+    }
+}
+
+impl<T: Versioned> Versioned for Arc<Mutex<T>> {
+    fn format() -> String {
+        T::format()
+    }
+
+    fn migrate(payload: &mut Value, from_version: u32) -> Result<(), String> {
+        T::migrate(payload, from_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{data_processing::DataSet, visualizations::Visualization};
+
+    #[test]
+    fn bare_dump_is_version_0() {
+        // As written before the envelope existed, with the runtime status in it and without IDs
+        let dump = json!({
+            "raw_data": ["first text", "second text"],
+            "processed_data": null,
+            "name": "old dataset",
+            "is_being_processed": true,
+        });
+        let dataset: DataSet = from_value(dump).unwrap();
+        assert_eq!(dataset.name, "old dataset");
+        assert_eq!(*dataset.raw_data, vec!["first text".to_owned(), "second text".to_owned()]);
+        assert!(!dataset.id.is_empty());
+
+        let dump = json!({
+            "name": "old visualization",
+            "data": [[2.0]],
+            "word_clusters": [[["a", "b"]]],
+            "is_calculating": true,
+            "quantization_errors": [[0.5]],
+        });
+        let visualization: Visualization = from_value(dump).unwrap();
+        // Combined errors from before version 2 are dropped
+        assert_eq!(visualization.quantization_error(0, 0), None);
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let dataset = DataSet::new("test".to_owned(), vec!["text".to_owned()]);
+        let value = envelope(&dataset).unwrap();
+        assert_eq!(value["format"], "dataset");
+        assert_eq!(value["format_version"], FORMAT_VERSION);
+        assert_eq!(from_value::<DataSet>(value).unwrap(), dataset);
+    }
+
+    #[test]
+    fn rejects_other_formats_and_newer_versions() {
+        let dataset = DataSet::new("test".to_owned(), vec![]);
+        let value = envelope(&dataset).unwrap();
+        assert!(from_value::<Visualization>(value.clone()).unwrap_err().contains("Expected a visualization file"));
+
+        let mut newer = value;
+        newer["format_version"] = (FORMAT_VERSION + 1).into();
+        assert!(from_value::<DataSet>(newer).unwrap_err().contains("only versions up to"));
+    }
+}
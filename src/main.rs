@@ -3,8 +3,15 @@ mod data_processing;
 mod maps;
 mod visualizations;
 mod msom;
+mod file_format;
+mod binary_format;
+mod som_pak;
+mod assignments;
+mod workspace;
+mod provenance;
+mod registry;
 
-use std::iter::Map;
+use std::{path::PathBuf, thread::JoinHandle, time::{Duration, Instant}};
 
 use data_processing::{*};
 use env_logger::fmt::style::Color;
@@ -12,23 +19,32 @@ use maps::{*};
 use visualizations::{*};
 
 use egui::{Button, Color32, Id, ScrollArea, Sense};
+use rfd::FileDialog;
+use serde::{Deserialize, Serialize};
+use registry::Registry;
+use workspace::Workspace;
 
 const DATA_PROCESSING_SAVE_PATH: &str = "./data/dp.sv";
 const MAPS_SAVE_PATH: &str = "./data/mp.sv";
 const VISUALIZATIONS_SAVE_PATH: &str = "./data/vz.sv";
+const LAYOUT_SAVE_PATH: &str = "./data/layout.sv";
 
-#[derive(Debug)]
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum PaneType {
     DataProcessing,
     Maps,
     Visualizations
 }
 
-struct Pane {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Pane {
     p_type: PaneType,
 }
 
 struct TreeBehavior {
+    registry: Registry,
     data_processing_state: DataProcessingUI,
     maps_state: MapsUI,
     visualizations_state: VisualizationsUI,
@@ -51,9 +67,9 @@ impl egui_tiles::Behavior<Pane> for TreeBehavior {
     ) -> egui_tiles::UiResponse {
 
         match &mut pane.p_type {
-            PaneType::DataProcessing => self.data_processing_state.show(ui),
-            PaneType::Maps => self.maps_state.show(ui, &self.data_processing_state.datasets),
-            PaneType::Visualizations => self.visualizations_state.show(ui, self.maps_state.maps.clone(), &self.data_processing_state.datasets),
+            PaneType::DataProcessing => self.data_processing_state.show(ui, &mut self.registry),
+            PaneType::Maps => self.maps_state.show(ui, &mut self.registry),
+            PaneType::Visualizations => self.visualizations_state.show(ui, &mut self.registry),
         }
 
         // You can make your pane draggable like so:
@@ -66,28 +82,135 @@ impl egui_tiles::Behavior<Pane> for TreeBehavior {
     }
 }
 
+struct App {
+    tree: egui_tiles::Tree<Pane>,
+    behavior: TreeBehavior,
+    last_autosave: Instant,
+    // The autosave still being written, if any
+    autosave_thread: Option<JoinHandle<()>>,
+}
+
+impl App {
+    fn from_workspace(workspace: Workspace) -> Self {
+        let behavior = TreeBehavior {
+            registry: Registry::new(workspace.datasets, workspace.maps, workspace.visualizations),
+            maps_state: MapsUI::default(),
+            data_processing_state: DataProcessingUI::default(),
+            visualizations_state: VisualizationsUI::default(),
+        };
+
+        Self { tree: workspace.tree.unwrap_or_else(create_tree), behavior, last_autosave: Instant::now(), autosave_thread: None }
+    }
+
+    fn workspace(&self) -> Workspace {
+        Workspace {
+            datasets: self.behavior.registry.datasets.iter().map(|(_, dataset)| dataset.clone()).collect(),
+            maps: self.behavior.registry.maps.clone(),
+            visualizations: self.behavior.registry.visualizations.iter().map(|(_, visualization)| visualization.clone()).collect(),
+            tree: Some(self.tree.clone()),
+        }
+    }
+
+    fn autosave(workspace: &Workspace) {
+        let res = workspace.to_autosave(DATA_PROCESSING_SAVE_PATH, MAPS_SAVE_PATH, VISUALIZATIONS_SAVE_PATH, LAYOUT_SAVE_PATH);
+        if let Err(err) = res {
+            println!("{err}");
+        }
+    }
+
+    fn file_menu(&mut self, ui: &mut egui::Ui) {
+        ui.menu_button("File", |ui| {
+            if ui.button("Open workspace…").clicked() {
+                let files = FileDialog::new()
+                    .add_filter("Serde json file with the whole workspace", &["json_workspace"])
+                    .set_directory(".")
+                    .pick_file();
+
+                if let Some(path) = files {
+                    match Workspace::from_file(&path) {
+                        Ok(workspace) => *self = App::from_workspace(workspace),
+                        Err(err) => println!("{err}"),
+                    }
+                }
+
+                ui.close_menu();
+            }
+
+            if ui.button("Save workspace as…").clicked() {
+                let files: Option<PathBuf> = FileDialog::new()
+                    .add_filter("Serde json file with the whole workspace", &["json_workspace"])
+                    .set_directory(".")
+                    .save_file();
+
+                if let Some(path) = files {
+                    let workspace = self.workspace();
+                    std::thread::spawn(move || {
+                        if let Err(err) = workspace.to_file(&path) {
+                            println!("{err}");
+                        }
+                    });
+                }
+
+                ui.close_menu();
+            }
+        });
+    }
+}
+
+impl eframe::App for App {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        egui_extras::install_image_loaders(ctx);
+
+        egui::TopBottomPanel::top("menu_bar").show(ctx, |ui| {
+            egui::menu::bar(ui, |ui| {
+                self.file_menu(ui);
+            });
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            self.tree.ui(&mut self.behavior, ui);
+        });
+
+        // Kept off the UI thread, serializing big datasets takes a while. Maps being trained are saved without
+        // their weights, as on exit, and a tick is skipped while the previous autosave is still writing the files
+        let autosave_running = self.autosave_thread.as_ref().is_some_and(|thread| !thread.is_finished());
+        if self.last_autosave.elapsed() >= AUTOSAVE_INTERVAL && !autosave_running {
+            self.last_autosave = Instant::now();
+            let workspace = self.workspace().without_locked_maps();
+            self.autosave_thread = Some(std::thread::spawn(move || App::autosave(&workspace)));
+        }
+        ctx.request_repaint_after(AUTOSAVE_INTERVAL);
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        // Both would write the same files
+        if let Some(thread) = self.autosave_thread.take() {
+            let _ = thread.join();
+        }
+        App::autosave(&self.workspace().without_locked_maps());
+    }
+}
+
 fn main() -> Result<(), eframe::Error> {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
 
+    // Headless rendering of a saved visualization, for scripts building figures
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("render") {
+        if let Err(err) = visualizations::render::run_cli(&args[2..]) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size([520.0, 440.0]),
         ..Default::default()
     };
 
-    let mut tree = create_tree();
-
-    let mut behavior = TreeBehavior {
-        maps_state: MapsUI::default(),
-        data_processing_state: DataProcessingUI::default(),
-        visualizations_state: VisualizationsUI::default(),
-    };
-    eframe::run_simple_native("SOMs with recurrence", options, move |ctx, _frame| {
-        egui_extras::install_image_loaders(ctx);
-
-        egui::CentralPanel::default().show(ctx, |ui| {
-            tree.ui(&mut behavior, ui);
-        });
-    })
+    let workspace = Workspace::from_autosave(DATA_PROCESSING_SAVE_PATH, MAPS_SAVE_PATH, VISUALIZATIONS_SAVE_PATH, LAYOUT_SAVE_PATH);
+    eframe::run_native("SOMs with recurrence", options, Box::new(|_cc| Box::new(App::from_workspace(workspace))))
 }
 
 fn create_tree() -> egui_tiles::Tree<Pane> {
@@ -1,15 +1,20 @@
-use std::{fs::File, io::{self, Read, Write}, path::PathBuf, sync::{Arc, Mutex, MutexGuard}};
+use std::{io::{self, Read, Write}, path::Path, sync::{Arc, Mutex, MutexGuard}};
 
-use egui::{include_image, Color32, ComboBox, DragValue, Frame, Grid, Image, Layout, Rounding, ScrollArea, Sense, SidePanel, Stroke, Style, Ui, Vec2};
+use egui::{include_image, Color32, ComboBox, DragValue, Frame, Grid, Image, Label, Layout, Rounding, ScrollArea, Sense, SidePanel, Stroke, Style, Ui, Vec2};
+use ndarray::{Array3, ArrayView3, ArrayView4, Axis};
 use ndarray_ndimage::label;
 use rfd::FileDialog;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::{msom::MSOM, DataSet};
+use crate::{assignments, binary_format::{BinaryFile, BinaryWriter, ChunkType}, data_processing::TextPipeline, file_format::{self, remove_field, Versioned}, msom::{anomaly::{AnomalyCalibration, DEFAULT_ANOMALY_QUANTILE}, clustering::{ClusteringMethod, SuperClusters}, history::TrainingSnapshot, prediction::NextStepPredictor, Topology, MSOM}, provenance::{map_hash, new_id}, registry::{find_dataset, Registry}, som_pak};
 use egui_modal::{Modal};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SOMParams {
+    // Stays the same through renames, saves and refits, visualizations refer to their map by it
+    #[serde(default = "new_id")]
+    pub id: String,
     pub name: String,
     pub n: usize,
     pub m: usize,
@@ -17,19 +22,47 @@ pub struct SOMParams {
     pub a: f32,
     pub b: f32,
     pub gamma: f32,
+    #[serde(default)]
+    pub topology: Topology,
 
     pub train_iterations: usize,
     pub learning_rate_base: f32,
     pub gauss_width_squared_base: f32,
     pub time_constant: f32,
+    // Weights are copied every this many iterations of the fit, 0 turns it off
+    #[serde(default)]
+    pub snapshot_every: usize,
 
     pub map_weights: Option<Arc<Mutex<MSOM>>>,
+    #[serde(skip)]
     pub is_training: Arc<Mutex<bool>>,
+    // Copy of the weights from before the last fit, what gets saved while the training thread holds them.
+    // Kept after the fit, so that a save racing with the end of training still finds it
+    #[serde(skip)]
+    pub pre_training_weights: Arc<Mutex<Option<MSOM>>>,
+    // Of the current weights, cleared when they change
+    #[serde(skip)]
+    weights_hash: Arc<Mutex<Option<String>>>,
+
+    #[serde(default)]
+    pub anomaly_calibration: Arc<Mutex<Option<AnomalyCalibration>>>,
+    #[serde(default)]
+    pub predictor: Arc<Mutex<Option<NextStepPredictor>>>,
+    #[serde(default)]
+    pub super_clusters: Arc<Mutex<Option<SuperClusters>>>,
+    // Taken during the last fit, oldest first. They are as big as the map itself times their count,
+    // so they are only kept in binary map files, json files drop them
+    #[serde(skip)]
+    pub snapshots: Arc<Mutex<Vec<TrainingSnapshot>>>,
+    // Processing of the dataset the map was last fitted on, to embed new texts the same way
+    #[serde(default)]
+    pub pipeline: Option<TextPipeline>,
 }
 
 impl Default for SOMParams {
     fn default() -> Self {
         Self {
+            id: new_id(),
             name: "Name".to_owned(),
             n: 10,
             m: 10,
@@ -38,74 +71,200 @@ impl Default for SOMParams {
             a: 1.0,
             b: 1.0,
             gamma: 0.5,
+            topology: Topology::Rectangular,
 
             train_iterations: 100,
             learning_rate_base: 0.1,
             gauss_width_squared_base: 10000.0,
             time_constant: 200.0,
+            snapshot_every: 0,
 
             map_weights: None,
             is_training: Arc::new(Mutex::new(false)),
+            pre_training_weights: Arc::new(Mutex::new(None)),
+            weights_hash: Arc::new(Mutex::new(None)),
+
+            anomaly_calibration: Arc::new(Mutex::new(None)),
+            predictor: Arc::new(Mutex::new(None)),
+            super_clusters: Arc::new(Mutex::new(None)),
+            snapshots: Arc::new(Mutex::new(vec![])),
+            pipeline: None,
         }
     }
 }
 
-impl SOMParams {
-    fn from_file(filename: &PathBuf) -> Result<Self, &str> {
-        let writer = File::options().read(true).open(filename);
-        if writer.is_err() {
-            return Err("Error while opening the file");
+impl Versioned for SOMParams {
+    fn format() -> String {
+        "map".to_owned()
+    }
+
+    fn migrate(payload: &mut Value, from_version: u32) -> Result<(), String> {
+        match from_version {
+            // Runtime status used to be saved along with the data
+            0 => remove_field(payload, "is_training"),
+            // Only kept in binary map files now
+            1 => remove_field(payload, "snapshots"),
+            _ => Ok(()),
         }
+    }
+}
 
-        let res: Result<Self, serde_json::Error> = serde_json::from_reader(writer.unwrap());
+impl SOMParams {
+    fn from_file(filename: &Path) -> Result<Self, String> {
+        file_format::from_file(filename)
+    }
+
+    // None without weights or while they are being trained
+    pub fn weights_hash(&self) -> Option<String> {
+        if *self.is_training.lock().unwrap() {
+            return None;
+        }
 
-        if res.is_err() {
-            Err("Error parsing the file")
+        let mut cached = self.weights_hash.lock().unwrap();
+        if cached.is_none() {
+            let weights = self.map_weights.as_ref()?.try_lock().ok()?;
+            *cached = Some(map_hash(&weights));
         }
-        else {
-            Ok(res.unwrap())
+        cached.clone()
+    }
+
+    fn to_file(&self, filename: &Path) -> Result<(), String> {
+        file_format::to_file(self, filename)
+    }
+
+    // Training parameters stay at their defaults, only the shape and the MSOM parameters come from the weights
+    fn from_weights(name: String, weights: MSOM) -> Self {
+        Self {
+            name,
+            n: weights.n,
+            m: weights.m,
+            map_input_size: weights.map_input_size,
+            a: weights.a,
+            b: weights.b,
+            gamma: weights.gamma,
+            topology: weights.topology,
+            map_weights: Some(Arc::new(Mutex::new(weights))),
+            ..Default::default()
         }
     }
 
-    fn to_file(&self, filename: &PathBuf) -> Result<(), &str> {
-        // let json = serde_json::to_vec(&self.map_weights)?;
-        let writer = File::options().write(true).create(true).open(filename);
-        if writer.is_err() {
-            return Err("Error while opening the file");
+    // The parameters go into the header, som, context and the training snapshots are stored as raw f32 chunks
+    fn to_binary_file(&self, filename: &Path) -> Result<(), String> {
+        let mut writer = BinaryWriter::default();
+        if let Some(weights) = &self.map_weights {
+            let Ok(weights) = weights.try_lock() else {
+                return Err(format!("Map {} is being trained, try again once it's done", self.name));
+            };
+
+            writer.add_f32("som", weights.som().shape(), weights.som().iter());
+            writer.add_f32("context", weights.context().shape(), weights.context().iter());
+            writer.add_f32("weights_params", &[3], &[weights.a, weights.b, weights.gamma]);
         }
 
-        let res = serde_json::to_writer_pretty(writer.unwrap(), self);
+        // Stacked along a new first axis
+        let snapshots = self.snapshots.lock().unwrap();
+        if let Some(first) = snapshots.first() {
+            let shape = [&[snapshots.len()], first.som.shape()].concat();
+            writer.add_f32("snapshots_som", &shape, snapshots.iter().flat_map(|snapshot| snapshot.som.iter()));
+            writer.add_f32("snapshots_context", &shape, snapshots.iter().flat_map(|snapshot| snapshot.context.iter()));
+            let iterations: Vec<u64> = snapshots.iter().map(|snapshot| snapshot.iteration as u64).collect();
+            writer.add_u64("snapshot_iterations", &iterations);
+        }
 
-        if res.is_err() {
-            Err("Error while serializing the map")
+        let metadata = SOMParams { map_weights: None, ..self.clone() };
+        writer.write(&metadata, filename)
+    }
+
+    fn from_binary_file(filename: &Path) -> Result<Self, String> {
+        let (mut params, file): (SOMParams, _) = BinaryFile::open(filename)?;
+
+        if let Ok(weights_params) = file.chunk("weights_params", ChunkType::F32) {
+            let read_weights = |name| -> Result<Array3<f32>, String> {
+                let chunk = file.chunk(name, ChunkType::F32)?;
+                let shape: [usize; 3] = chunk.shape.as_slice().try_into()
+                    .map_err(|_| format!("Chunk `{name}` has shape {:?}, expected 3 dimensions", chunk.shape))?;
+                ArrayView3::from_shape(shape, file.f32_slice(chunk)?)
+                    .map(|view| view.to_owned())
+                    .map_err(|err| format!("Chunk `{name}`: {err}"))
+            };
+
+            let &[a, b, gamma] = file.f32_slice(weights_params)? else {
+                return Err(format!("Invalid chunk `weights_params` in {}", filename.display()));
+            };
+            let mut weights = MSOM::from_weights(a, b, gamma, read_weights("som")?, read_weights("context")?)?;
+            weights.topology = params.topology;
+            params.map_weights = Some(Arc::new(Mutex::new(weights)));
         }
-        else {
-            Ok(())
+
+        if let Ok(iterations) = file.chunk("snapshot_iterations", ChunkType::U64) {
+            let read_snapshots = |name| -> Result<Vec<Array3<f32>>, String> {
+                let chunk = file.chunk(name, ChunkType::F32)?;
+                let shape: [usize; 4] = chunk.shape.as_slice().try_into()
+                    .map_err(|_| format!("Chunk `{name}` has shape {:?}, expected 4 dimensions", chunk.shape))?;
+                ArrayView4::from_shape(shape, file.f32_slice(chunk)?)
+                    .map(|view| view.axis_iter(Axis(0)).map(|weights| weights.to_owned()).collect())
+                    .map_err(|err| format!("Chunk `{name}`: {err}"))
+            };
+
+            let snapshots = file.u64_values(iterations).into_iter()
+                .zip(read_snapshots("snapshots_som")?)
+                .zip(read_snapshots("snapshots_context")?)
+                .map(|((iteration, som), context)| TrainingSnapshot { iteration: iteration as usize, som, context })
+                .collect();
+            params.snapshots = Arc::new(Mutex::new(snapshots));
         }
+
+        Ok(params)
     }
 }
 
 
 #[derive(Debug)]
 pub struct MapsUI {
-    pub maps: Vec<SOMParams>,
-    
     current_params: SOMParams,
-    shown_map_index: Option<usize>,
-    current_dataset_index: Option<usize>,
+    shown_map_id: Option<String>,
+    current_dataset_id: Option<String>,
+
+    held_out_dataset_id: Option<String>,
+    rollout_steps: usize,
+    // Error for every rollout horizon, None while it is being calculated
+    prediction_errors: Option<Arc<Mutex<Option<Vec<f32>>>>>,
+    rollout_sample_index: usize,
+    rollout_prefix_chunks: usize,
+    rollout_preview: Vec<String>,
+
+    export_dataset_id: Option<String>,
+    // Set while an export of the sample assignments runs
+    is_exporting: Arc<Mutex<bool>>,
+
+    // For maps that were imported rather than trained here, or to calibrate on other data than the training set
+    calibration_dataset_id: Option<String>,
+    is_calibrating: Arc<Mutex<bool>>,
+
+    clustering_method: ClusteringMethod,
+    cluster_count: usize,
+    cluster_with_context: bool,
+    is_clustering: Arc<Mutex<bool>>,
 }
 
 impl Default for MapsUI {
     fn default() -> Self {
-        Self { current_params: SOMParams::default(), maps: vec![], shown_map_index: None, current_dataset_index: None }
+        Self { current_params: SOMParams::default(), shown_map_id: None, current_dataset_id: None,
+            held_out_dataset_id: None, rollout_steps: 3, prediction_errors: None,
+            rollout_sample_index: 0, rollout_prefix_chunks: 1, rollout_preview: vec![],
+            export_dataset_id: None, is_exporting: Arc::new(Mutex::new(false)),
+            calibration_dataset_id: None, is_calibrating: Arc::new(Mutex::new(false)),
+            clustering_method: ClusteringMethod::KMeans, cluster_count: 8, cluster_with_context: false,
+            is_clustering: Arc::new(Mutex::new(false)) }
     }
 }
 
 impl MapsUI {
-    fn map_list(&mut self, ui: &mut Ui) {
-        for (index, map) in self.maps.iter().enumerate() {
+    fn map_list(&mut self, ui: &mut Ui, registry: &mut Registry) {
+        let mut removed = None;
+        for map in registry.maps.iter() {
             let frame_style = Style::default();
-            let is_current = Some(index) == self.shown_map_index;
+            let is_current = self.shown_map_id.as_ref() == Some(&map.id);
             let stroke_color = if is_current {
                 Color32::DARK_GRAY
             }
@@ -139,7 +298,11 @@ impl MapsUI {
 
             let response = frame.allocate_space(ui).on_hover_cursor(egui::CursorIcon::PointingHand).interact(Sense::click());
             if response.clicked() {
-                self.shown_map_index = Some(index);
+                if self.shown_map_id.as_ref() != Some(&map.id) {
+                    self.prediction_errors = None;
+                    self.rollout_preview.clear();
+                }
+                self.shown_map_id = Some(map.id.clone());
             }
 
             response.context_menu(|ui| {
@@ -161,6 +324,81 @@ impl MapsUI {
 
                     ui.close_menu();
                 }
+
+                if ui.button("Save to binary file").clicked() {
+                    let files = FileDialog::new()
+                        .add_filter("Binary map with raw weights", &["bin_map"])
+                        .set_directory(".")
+                        .save_file();
+                    
+                    if let Some(path) = files {
+                        let res = map.to_binary_file(&path);
+                        if res.is_err() {
+                            println!("{}", res.err().unwrap());
+                        }
+                    }
+
+                    ui.close_menu();
+                }
+
+                // The training thread holds on to the weights until it is done
+                let is_training = *map.is_training.lock().unwrap();
+                if let Some(weights) = &map.map_weights {
+                    if ui.add_enabled(!is_training, egui::Button::new("Export to NPZ")).clicked() {
+                        let files = FileDialog::new()
+                            .add_filter("NumPy archive with som, context and params", &["npz"])
+                            .set_directory(".")
+                            .save_file();
+
+                        if let Some(path) = files {
+                            let res = weights.lock().unwrap().dump_to_npz(&path);
+                            if res.is_err() {
+                                println!("{}", res.err().unwrap());
+                            }
+                        }
+
+                        ui.close_menu();
+                    }
+
+                    for (label, with_context) in [("Export to SOM_PAK codebook", false), ("Export to SOM_PAK codebook with context", true)] {
+                        if ui.add_enabled(!is_training, egui::Button::new(label)).clicked() {
+                            let files = FileDialog::new()
+                                .add_filter("SOM_PAK codebook", &["cod"])
+                                .set_directory(".")
+                                .save_file();
+
+                            if let Some(path) = files {
+                                let res = som_pak::write_cod(&weights.lock().unwrap(), with_context, &path);
+                                if res.is_err() {
+                                    println!("{}", res.err().unwrap());
+                                }
+                            }
+
+                            ui.close_menu();
+                        }
+                    }
+
+                    if ui.add_enabled(!is_training, egui::Button::new("Export MiniSom weights")).clicked() {
+                        let files = FileDialog::new()
+                            .add_filter("NumPy array of shape (n, m, dim)", &["npy"])
+                            .set_directory(".")
+                            .save_file();
+
+                        if let Some(path) = files {
+                            let res = weights.lock().unwrap().dump_to_minisom_npy(&path);
+                            if res.is_err() {
+                                println!("{}", res.err().unwrap());
+                            }
+                        }
+
+                        ui.close_menu();
+                    }
+                }
+
+                if ui.add_enabled(!is_training, egui::Button::new("Remove")).clicked() {
+                    removed = Some(map.id.clone());
+                    ui.close_menu();
+                }
             });
 
             if response.hovered() {
@@ -168,21 +406,27 @@ impl MapsUI {
             }
             frame.paint(ui);
         }
+
+        if let Some(id) = removed {
+            registry.remove_map(&id);
+        }
     }
 
-    pub fn show(&mut self, ui: &mut Ui, datasets: &Vec<Arc<Mutex<DataSet>>>) {
+    pub fn show(&mut self, ui: &mut Ui, registry: &mut Registry) {
         ui.painter().rect_filled(ui.max_rect(), Rounding::ZERO, Color32::WHITE);
         let modal = Modal::new(ui.ctx(), "map modal");
 
-        if let Some(ind) = self.shown_map_index {
+        let datasets = &registry.datasets;
+        let shown_map = self.shown_map_id.as_ref().and_then(|id| registry.maps.iter_mut().find(|map| map.id == *id));
+        if shown_map.is_none() {
+            self.shown_map_id = None;
+        }
+        if let Some(chosen_map) = shown_map {
             SidePanel::right("tooltip_maps")
             .show_inside(ui, |ui| {
-                let chosen_map = &mut self.maps[ind];
                 ui.text_edit_singleline(&mut chosen_map.name);
-                if let Some(dataset_index) = self.current_dataset_index {
-                    if dataset_index >= datasets.len() || !datasets[dataset_index].lock().unwrap().is_processed() {
-                        self.current_dataset_index = None;
-                    }
+                if !self.current_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)).is_some_and(|dataset| dataset.lock().unwrap().is_processed()) {
+                    self.current_dataset_id = None;
                 }
                 
                 Grid::new("Parameters").show(ui, |ui| {
@@ -198,6 +442,11 @@ impl MapsUI {
                     ui.add(DragValue::new(&mut chosen_map.gamma));
                     ui.end_row();
 
+                    // Fixed once the map has weights
+                    ui.label("topology:");
+                    ui.label(chosen_map.topology.name());
+                    ui.end_row();
+
                     ui.label("train_iterations:");
                     ui.add(DragValue::new(&mut chosen_map.train_iterations));
                     ui.end_row();
@@ -214,24 +463,32 @@ impl MapsUI {
                     ui.add(DragValue::new(&mut chosen_map.time_constant));
                     ui.end_row();
 
+                    ui.label("snapshot_every:")
+                        .on_hover_text("Keep a copy of the weights every this many iterations to play the training back, 0 keeps none");
+                    ui.add(DragValue::new(&mut chosen_map.snapshot_every));
+                    ui.end_row();
+
+                    let snapshot_count = chosen_map.snapshots.lock().unwrap().len();
+                    if snapshot_count != 0 {
+                        ui.label("Snapshots of the last fit:");
+                        ui.label(snapshot_count.to_string());
+                        ui.end_row();
+                    }
+
                     ui.label("Dataset to fit:");
                     let mut cur_dataset_label = "".to_owned();
-                    if let Some(dataset_index) = self.current_dataset_index {
-                        cur_dataset_label = datasets[dataset_index].lock().unwrap().name.clone();
+                    if let Some(dataset) = self.current_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                        cur_dataset_label = dataset.lock().unwrap().name.clone();
                     }
 
                     ComboBox::from_id_source("Dataset selection")
                     .selected_text(cur_dataset_label)
                     .show_ui(ui, |ui| {
-                        // ToDo: decide how to pass the dataset to this function
-                        // Maybe a Box or Cell? It's kinda infuriating to work with references stored in structs
-                        // Or I could switch to Rc<RefCell>
-
-                        for (index, dataset) in datasets.iter().enumerate() {
+                        for (_, dataset) in datasets.iter() {
                             let locked_dataset = dataset.lock().unwrap();
                             if locked_dataset.is_processed() {
-                                ui.selectable_value(&mut self.current_dataset_index, 
-                                    Some(index), locked_dataset.name.as_str());
+                                ui.selectable_value(&mut self.current_dataset_id, 
+                                    Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
                             }
                         }
                     });
@@ -240,13 +497,23 @@ impl MapsUI {
 
                 if ui.button("Fit the map").clicked() {
                     // ToDo: the actual training
-                    if let Some(dataset_index) = self.current_dataset_index {
+                    if let Some(dataset) = self.current_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
                         *chosen_map.is_training.lock().unwrap() = true;
+                        *chosen_map.weights_hash.lock().unwrap() = None;
+                        // Made for the old prototypes, a new one is fitted once the training is done
+                        *chosen_map.predictor.lock().unwrap() = None;
+                        self.prediction_errors = None;
+                        self.rollout_preview.clear();
+
+                        *chosen_map.pre_training_weights.lock().unwrap() = chosen_map.map_weights.as_ref()
+                            .map(|weights| weights.lock().unwrap().clone());
 
                         let weights;
                         if let None = chosen_map.map_weights {
-                            weights = Arc::new(Mutex::new(MSOM::new(chosen_map.n, chosen_map.m, chosen_map.map_input_size, 
-                                chosen_map.a, chosen_map.b, chosen_map.gamma)));
+                            let mut new_weights = MSOM::new(chosen_map.n, chosen_map.m, chosen_map.map_input_size, 
+                                chosen_map.a, chosen_map.b, chosen_map.gamma);
+                            new_weights.topology = chosen_map.topology;
+                            weights = Arc::new(Mutex::new(new_weights));
                         }
                         else {
                             weights = Arc::clone(chosen_map.map_weights.as_ref().unwrap());
@@ -259,34 +526,333 @@ impl MapsUI {
                         let learning_rate_base = chosen_map.learning_rate_base;
                         let gauss_width_squared_base = chosen_map.gauss_width_squared_base;
                         let time_constant = chosen_map.time_constant;
+                        let snapshot_every = chosen_map.snapshot_every;
 
-                        let cloned_dataset = datasets[dataset_index].lock().unwrap().processed_data.clone().unwrap();
+                        let cloned_dataset = dataset.lock().unwrap().processed_data.clone().unwrap();
+                        chosen_map.pipeline = dataset.lock().unwrap().pipeline.clone();
                         let cloned_status = chosen_map.is_training.clone();
+                        let cloned_calibration = chosen_map.anomaly_calibration.clone();
+                        let cloned_predictor = chosen_map.predictor.clone();
+                        let cloned_clusters = chosen_map.super_clusters.clone();
+                        let cloned_snapshots = chosen_map.snapshots.clone();
+                        let quantile = cloned_calibration.lock().unwrap().as_ref()
+                            .map_or(DEFAULT_ANOMALY_QUANTILE, |calibration| calibration.quantile);
 
                         let handle = std::thread::spawn(move || {
+                            let samples = cloned_dataset.views();
 
                             // ToDo: Add progress tracking and maybe thread termination
-                            cloned_weights.lock().unwrap().fit(&cloned_dataset.iter().map(|sample| sample.view()).collect(), 
+                            let snapshots = cloned_weights.lock().unwrap().fit(&samples, 
                                 train_iterations, learning_rate_base, 
-                                gauss_width_squared_base, time_constant);
+                                gauss_width_squared_base, time_constant, snapshot_every);
+                            *cloned_snapshots.lock().unwrap() = snapshots;
 
                             println!("TRAINED!");
+                            let calibration = AnomalyCalibration::new(&cloned_weights.lock().unwrap(), &samples, quantile);
+                            *cloned_calibration.lock().unwrap() = Some(calibration);
+                            let predictor = NextStepPredictor::new(&cloned_weights.lock().unwrap(), &samples);
+                            *cloned_predictor.lock().unwrap() = Some(predictor);
+                            // Redone with the same settings, the old ones belong to the old prototypes
+                            let settings = cloned_clusters.lock().unwrap().as_ref()
+                                .map(|clusters| (clusters.method, clusters.k, clusters.with_context));
+                            if let Some((method, k, with_context)) = settings {
+                                let clusters = SuperClusters::new(&cloned_weights.lock().unwrap(), method, k, with_context);
+                                *cloned_clusters.lock().unwrap() = Some(clusters);
+                            }
+                            *cloned_status.lock().unwrap() = false;
+                        });
+                    }
+                }
+
+                if !self.calibration_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)).is_some_and(|dataset| dataset.lock().unwrap().is_processed()) {
+                    self.calibration_dataset_id = None;
+                }
+
+                if let Some(weights) = chosen_map.map_weights.as_ref().filter(|_| !*chosen_map.is_training.lock().unwrap()) {
+                    ui.separator();
+                    Grid::new("Calibration dataset").show(ui, |ui| {
+                        ui.label("Calibration dataset:");
+                        let mut cur_dataset_label = "".to_owned();
+                        if let Some(dataset) = self.calibration_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                            cur_dataset_label = dataset.lock().unwrap().name.clone();
+                        }
+
+                        ComboBox::from_id_source("Calibration dataset selection")
+                        .selected_text(cur_dataset_label)
+                        .show_ui(ui, |ui| {
+                            for (_, dataset) in datasets.iter() {
+                                let locked_dataset = dataset.lock().unwrap();
+                                if locked_dataset.is_processed() {
+                                    ui.selectable_value(&mut self.calibration_dataset_id, 
+                                        Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
+                                }
+                            }
+                        });
+                        ui.end_row();
+                    });
+
+                    let dataset = self.calibration_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id));
+                    if *self.is_calibrating.lock().unwrap() {
+                        ui.spinner();
+                    }
+                    else if ui.add_enabled(dataset.is_some(), egui::Button::new("Calibrate on dataset")).clicked() {
+                        let samples = dataset.unwrap().lock().unwrap().processed_data.clone().unwrap();
+                        let cloned_weights = weights.clone();
+                        let cloned_calibration = chosen_map.anomaly_calibration.clone();
+                        let cloned_status = self.is_calibrating.clone();
+                        let quantile = cloned_calibration.lock().unwrap().as_ref()
+                            .map_or(DEFAULT_ANOMALY_QUANTILE, |calibration| calibration.quantile);
+                        *cloned_status.lock().unwrap() = true;
+
+                        std::thread::spawn(move || {
+                            let map = cloned_weights.lock().unwrap().clone();
+                            let calibration = AnomalyCalibration::new(&map, &samples.views(), quantile);
+                            *cloned_calibration.lock().unwrap() = Some(calibration);
                             *cloned_status.lock().unwrap() = false;
                         });
                     }
                 }
 
+                if let Some(calibration) = chosen_map.anomaly_calibration.lock().unwrap().as_mut() {
+                    ui.separator();
+                    Grid::new("Anomaly calibration").show(ui, |ui| {
+                        ui.label("Anomaly quantile:");
+                        let mut quantile = calibration.quantile;
+                        if ui.add(DragValue::new(&mut quantile).speed(0.005).clamp_range(0.0..=1.0)).changed() {
+                            calibration.set_quantile(quantile);
+                        }
+                        ui.end_row();
+
+                        ui.label("Anomaly threshold:");
+                        ui.label(format!("{:.4}", calibration.threshold));
+                        ui.end_row();
+
+                        ui.label("Calibrated on:");
+                        ui.label(format!("{} samples", calibration.training_set_size()));
+                        ui.end_row();
+                    });
+                }
+
+                if !self.held_out_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)).is_some_and(|dataset| dataset.lock().unwrap().is_processed()) {
+                    self.held_out_dataset_id = None;
+                }
+
+                // Hidden during training, rolling out would wait on the weights until the training is done
+                let predictor = chosen_map.predictor.lock().unwrap().clone().filter(|_| !*chosen_map.is_training.lock().unwrap());
+                if let (Some(predictor), Some(weights)) = (predictor, chosen_map.map_weights.as_ref()) {
+                    ui.separator();
+                    Grid::new("Next-step prediction").show(ui, |ui| {
+                        ui.label("Held-out dataset:");
+                        let mut cur_dataset_label = "".to_owned();
+                        if let Some(dataset) = self.held_out_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                            cur_dataset_label = dataset.lock().unwrap().name.clone();
+                        }
+
+                        ComboBox::from_id_source("Held-out dataset selection")
+                        .selected_text(cur_dataset_label)
+                        .show_ui(ui, |ui| {
+                            for (_, dataset) in datasets.iter() {
+                                let locked_dataset = dataset.lock().unwrap();
+                                if locked_dataset.is_processed() {
+                                    ui.selectable_value(&mut self.held_out_dataset_id, 
+                                        Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
+                                }
+                            }
+                        });
+                        ui.end_row();
+
+                        ui.label("Rollout steps:");
+                        ui.add(DragValue::new(&mut self.rollout_steps).clamp_range(1..=100));
+                        ui.end_row();
+
+                        ui.label("Rollout sample:");
+                        ui.add(DragValue::new(&mut self.rollout_sample_index));
+                        ui.end_row();
+
+                        ui.label("Prefix chunks:");
+                        ui.add(DragValue::new(&mut self.rollout_prefix_chunks).clamp_range(1..=usize::MAX));
+                        ui.end_row();
+                    });
+
+                    if ui.button("Roll out from the sample prefix").clicked() {
+                        self.rollout_preview.clear();
+                        if let Some(dataset) = self.held_out_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                            let locked_dataset = dataset.lock().unwrap();
+                            let samples = locked_dataset.processed_data.as_ref().unwrap();
+                            if let Some(sample) = samples.get(self.rollout_sample_index) {
+                                let map = weights.lock().unwrap();
+                                let prefix_len = (self.rollout_prefix_chunks * map.map_input_size).min(sample.len());
+                                let actual = sample.slice(ndarray::s![prefix_len..]);
+
+                                let predictions = predictor.rollout(&map, sample.slice(ndarray::s![..prefix_len]), self.rollout_steps);
+                                for (step, prediction) in predictions.iter().enumerate() {
+                                    let chunk_pos = step * map.map_input_size;
+                                    let actual_chunk = if chunk_pos < actual.len() {
+                                        format!("{:.3}", actual.slice(ndarray::s![chunk_pos..(chunk_pos + map.map_input_size).min(actual.len())]))
+                                    }
+                                    else {
+                                        "-".to_owned()
+                                    };
+                                    self.rollout_preview.push(format!("{}: {prediction:.3} (actual {actual_chunk})", step + 1));
+                                }
+                            }
+                        }
+                    }
+
+                    for line in &self.rollout_preview {
+                        ui.add(Label::new(line).truncate(true));
+                    }
+
+                    if ui.button("Evaluate prediction error").clicked() {
+                        if let Some(dataset) = self.held_out_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                            let cloned_weights = weights.clone();
+                            let cloned_dataset = dataset.lock().unwrap().processed_data.clone().unwrap();
+                            let steps = self.rollout_steps;
+                            let prediction_errors = Arc::new(Mutex::new(None));
+                            let cloned_errors = prediction_errors.clone();
+                            self.prediction_errors = Some(prediction_errors);
+
+                            std::thread::spawn(move || {
+                                let map = cloned_weights.lock().unwrap().clone();
+                                let samples = cloned_dataset.views();
+                                *cloned_errors.lock().unwrap() = Some(predictor.prediction_error(&map, &samples, steps));
+                            });
+                        }
+                    }
+
+                    if let Some(prediction_errors) = &self.prediction_errors {
+                        match prediction_errors.lock().unwrap().as_ref() {
+                            Some(errors) => {
+                                Grid::new("Prediction errors").show(ui, |ui| {
+                                    for (horizon, error) in errors.iter().enumerate() {
+                                        ui.label(format!("MSE at step {}:", horizon + 1));
+                                        ui.label(format!("{error:.5}"));
+                                        ui.end_row();
+                                    }
+                                });
+                            }
+                            None => {
+                                ui.spinner();
+                            }
+                        }
+                    }
+                }
+
+                if let Some(weights) = chosen_map.map_weights.as_ref() {
+                    ui.separator();
+                    Grid::new("Super-clusters").show(ui, |ui| {
+                        ui.label("Super-clustering:");
+                        ComboBox::from_id_source("Clustering method")
+                        .selected_text(self.clustering_method.name())
+                        .show_ui(ui, |ui| {
+                            for method in ClusteringMethod::ALL {
+                                ui.selectable_value(&mut self.clustering_method, method, method.name());
+                            }
+                        });
+                        ui.end_row();
+
+                        ui.label("Clusters:");
+                        ui.add_enabled(self.clustering_method != ClusteringMethod::Watershed, 
+                            DragValue::new(&mut self.cluster_count).clamp_range(1..=chosen_map.n * chosen_map.m));
+                        ui.end_row();
+
+                        ui.label("Include context:");
+                        ui.checkbox(&mut self.cluster_with_context, "");
+                        ui.end_row();
+
+                        if let Some(clusters) = chosen_map.super_clusters.lock().unwrap().as_ref() {
+                            ui.label("Current:");
+                            ui.label(format!("{} clusters by {}", clusters.count, clusters.method.name()));
+                            ui.end_row();
+                        }
+                    });
+
+                    if *self.is_clustering.lock().unwrap() {
+                        ui.spinner();
+                    }
+                    else if ui.button("Compute super-clusters").clicked() {
+                        let cloned_weights = weights.clone();
+                        let cloned_clusters = chosen_map.super_clusters.clone();
+                        let cloned_status = self.is_clustering.clone();
+                        let (method, k, with_context) = (self.clustering_method, self.cluster_count, self.cluster_with_context);
+                        *cloned_status.lock().unwrap() = true;
+
+                        std::thread::spawn(move || {
+                            let map = cloned_weights.lock().unwrap().clone();
+                            *cloned_clusters.lock().unwrap() = Some(SuperClusters::new(&map, method, k, with_context));
+                            *cloned_status.lock().unwrap() = false;
+                        });
+                    }
+                }
+
+                if !self.export_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)).is_some_and(|dataset| dataset.lock().unwrap().is_processed()) {
+                    self.export_dataset_id = None;
+                }
+
+                if let Some(weights) = chosen_map.map_weights.as_ref() {
+                    ui.separator();
+                    Grid::new("Sample assignments").show(ui, |ui| {
+                        ui.label("Dataset to export:");
+                        let mut cur_dataset_label = "".to_owned();
+                        if let Some(dataset) = self.export_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                            cur_dataset_label = dataset.lock().unwrap().name.clone();
+                        }
+
+                        ComboBox::from_id_source("Export dataset selection")
+                        .selected_text(cur_dataset_label)
+                        .show_ui(ui, |ui| {
+                            for (_, dataset) in datasets.iter() {
+                                let locked_dataset = dataset.lock().unwrap();
+                                if locked_dataset.is_processed() {
+                                    ui.selectable_value(&mut self.export_dataset_id, 
+                                        Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
+                                }
+                            }
+                        });
+                        ui.end_row();
+                    });
+
+                    if *self.is_exporting.lock().unwrap() {
+                        ui.spinner();
+                    }
+                    else if let Some(dataset) = self.export_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                        if ui.button("Export sample assignments").clicked() {
+                            let files = FileDialog::new()
+                                .add_filter("CSV", &["csv"])
+                                .add_filter("JSON lines", &["jsonl"])
+                                .set_directory(".")
+                                .save_file();
+
+                            if let Some(path) = files {
+                                let cloned_weights = weights.clone();
+                                let cloned_dataset = dataset.lock().unwrap().clone();
+                                let clusters = chosen_map.super_clusters.lock().unwrap().clone();
+                                let cloned_status = self.is_exporting.clone();
+                                *cloned_status.lock().unwrap() = true;
+
+                                std::thread::spawn(move || {
+                                    let map = cloned_weights.lock().unwrap().clone();
+                                    if let Err(err) = assignments::export(&map, clusters.as_ref(), &cloned_dataset, &path) {
+                                        println!("{err}");
+                                    }
+                                    *cloned_status.lock().unwrap() = false;
+                                });
+                            }
+                        }
+                    }
+                }
+
                 ui.separator();
             });
         }
 
         ScrollArea::vertical().show(ui, |ui| {
             ui.vertical_centered(|ui| {
-                if self.maps.len() == 0 {
+                if registry.maps.is_empty() {
                     ui.label("MAP ALL THE MAPS");
                     // ui.label("No datasets loaded");
                 }
-                self.map_list(ui);
+                self.map_list(ui, registry);
                 
                 modal.show(|ui| {
                     modal.title(ui, "Choose the parameters for the Map");
@@ -329,6 +895,16 @@ impl MapsUI {
                             ui.label("gamma:");
                             ui.add(DragValue::new(&mut self.current_params.gamma));
                             ui.end_row();
+
+                            ui.label("Topology:");
+                            ComboBox::from_id_source("Topology")
+                                .selected_text(self.current_params.topology.name())
+                                .show_ui(ui, |ui| {
+                                    for topology in [Topology::Rectangular, Topology::Hexagonal] {
+                                        ui.selectable_value(&mut self.current_params.topology, topology, topology.name());
+                                    }
+                                });
+                            ui.end_row();
                         });
                     });
 
@@ -338,8 +914,7 @@ impl MapsUI {
 
                         // ToDo: Implement Map creation
                         if modal.button(ui, "Create").clicked() {
-                            self.maps.push(self.current_params.clone());
-                            self.shown_map_index = Some(self.maps.len() - 1)
+                            self.shown_map_id = Some(registry.add_map(self.current_params.clone()));
                         }
                     }); 
                 });
@@ -355,16 +930,61 @@ impl MapsUI {
 
                     let files = FileDialog::new()
                             .add_filter("Serde json file with map structure", &["json_map"])
+                            .add_filter("Binary map with raw weights", &["bin_map"])
                             .set_directory(".")
                             .pick_file();
                         
                     if let Some(path) = files {
-                        let res = SOMParams::from_file(&path);
+                        let res = if path.extension().is_some_and(|extension| extension == "bin_map") {
+                            SOMParams::from_binary_file(&path)
+                        } else {
+                            SOMParams::from_file(&path)
+                        };
                         if res.is_err() {
                             println!("{}", res.err().unwrap());
                         }
                         else {
-                            self.maps.push(res.unwrap());
+                            registry.add_map(res.unwrap());
+                        }
+                    }
+                }
+
+                if ui.button("Import a SOM_PAK codebook").clicked() {
+                    let files = FileDialog::new()
+                            .add_filter("SOM_PAK codebook", &["cod"])
+                            .set_directory(".")
+                            .pick_file();
+
+                    if let Some(path) = files {
+                        match som_pak::read_cod(&path) {
+                            Ok(weights) => {
+                                let name = path.file_stem().map_or("Imported".to_owned(), |stem| stem.to_string_lossy().into_owned());
+                                registry.add_map(SOMParams::from_weights(name, weights));
+                            }
+                            Err(err) => println!("{err}"),
+                        }
+                    }
+                }
+
+                if ui.button("Import a map from NumPy").clicked() {
+                    let files = FileDialog::new()
+                            .add_filter("NumPy archive with som, context and params", &["npz"])
+                            .add_filter("MiniSom weights of shape (n, m, dim)", &["npy"])
+                            .set_directory(".")
+                            .pick_file();
+
+                    if let Some(path) = files {
+                        let res = if path.extension().is_some_and(|extension| extension == "npy") {
+                            MSOM::load_from_minisom_npy(&path)
+                        } else {
+                            MSOM::load_from_npz(&path)
+                        };
+                        match res {
+                            Ok(weights) => {
+                                let name = path.file_stem().map_or("Imported".to_owned(), |stem| stem.to_string_lossy().into_owned());
+                                registry.add_map(SOMParams::from_weights(name, weights));
+                            }
+                            Err(err) => println!("{err}"),
                         }
                     }
                 }
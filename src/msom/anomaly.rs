@@ -0,0 +1,127 @@
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+
+use super::MSOM;
+
+pub const DEFAULT_ANOMALY_QUANTILE: f32 = 0.95;
+
+impl MSOM {
+    // Mean combined BMU error along the sequence, so that long and short sequences stay comparable
+    pub fn anomaly_score(&self, sample: ArrayView1<f32>) -> f32 {
+        self.anomaly_score_of(&self.evaluate_trajectory(sample))
+    }
+
+    // For callers that evaluated the sample already
+    pub fn anomaly_score_of(&self, trajectory: &[((usize, usize), f32)]) -> f32 {
+        if trajectory.is_empty() {
+            return 0.0;
+        }
+
+        trajectory.iter().map(|(_, err)| err).sum::<f32>() / (trajectory.len() as f32)
+    }
+
+    pub fn anomaly_scores(&self, samples: &[ArrayView1<f32>]) -> Vec<f32> {
+        samples.iter().map(|sample| self.anomaly_score(*sample)).collect()
+    }
+}
+
+// Distribution of the anomaly scores on the training set, the threshold is its chosen quantile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnomalyCalibration {
+    training_scores: Vec<f32>,
+    pub quantile: f32,
+    pub threshold: f32,
+}
+
+impl AnomalyCalibration {
+    pub fn new(map: &MSOM, training_samples: &[ArrayView1<f32>], quantile: f32) -> Self {
+        // Empty samples have no BMU and would all score 0
+        let training_samples: Vec<_> = training_samples.iter().copied().filter(|sample| !sample.is_empty()).collect();
+        let mut training_scores = map.anomaly_scores(&training_samples);
+        training_scores.sort_by(|a, b| a.total_cmp(b));
+
+        let mut calibration = Self { training_scores, quantile, threshold: 0.0 };
+        calibration.set_quantile(quantile);
+        calibration
+    }
+
+    pub fn set_quantile(&mut self, quantile: f32) {
+        self.quantile = quantile.clamp(0.0, 1.0);
+        if self.training_scores.is_empty() {
+            self.threshold = f32::INFINITY;
+            return;
+        }
+
+        let last = self.training_scores.len() - 1;
+        let index = ((last as f32) * self.quantile).round() as usize;
+        self.threshold = self.training_scores[index.min(last)];
+    }
+
+    // Fraction of the training samples that scored lower than the given score
+    pub fn percentile(&self, score: f32) -> f32 {
+        if self.training_scores.is_empty() {
+            return 0.0;
+        }
+
+        let lower = self.training_scores.partition_point(|training_score| *training_score < score);
+        (lower as f32) / (self.training_scores.len() as f32)
+    }
+
+    pub fn is_anomalous(&self, score: f32) -> bool {
+        score > self.threshold
+    }
+
+    pub fn training_set_size(&self) -> usize {
+        self.training_scores.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{arr1, Array3};
+
+    use super::*;
+
+    // One neuron at 0 and one at 10, without context so that a single chunk x scores 0.99 * x^2
+    fn two_neuron_map() -> MSOM {
+        let som = Array3::from_shape_vec((1, 2, 1), vec![0.0, 10.0]).unwrap();
+        MSOM::from_weights(1.0, 0.0, 0.5, som, Array3::zeros((1, 2, 1))).unwrap()
+    }
+
+    #[test]
+    fn calibration_quantile_and_percentile() {
+        let map = two_neuron_map();
+        let samples: Vec<_> = [0.0, 4.0, 1.0, 3.0, 2.0].iter().map(|x| arr1(&[*x])).collect();
+        let mut views: Vec<_> = samples.iter().map(|sample| sample.view()).collect();
+        let empty = arr1(&[]);
+        views.push(empty.view());
+        let score = |x: f32| map.anomaly_score(arr1(&[x]).view());
+
+        let mut calibration = AnomalyCalibration::new(&map, &views, 0.5);
+        assert_eq!(calibration.training_set_size(), 5);
+        assert_eq!(calibration.threshold, score(2.0));
+        assert!(!calibration.is_anomalous(score(2.0)));
+        assert!(calibration.is_anomalous(score(3.0)));
+
+        calibration.set_quantile(1.0);
+        assert_eq!(calibration.threshold, score(4.0));
+        calibration.set_quantile(0.0);
+        assert_eq!(calibration.threshold, score(0.0));
+        calibration.set_quantile(2.0);
+        assert_eq!(calibration.quantile, 1.0);
+
+        assert_eq!(calibration.percentile(-1.0), 0.0);
+        assert_eq!(calibration.percentile(score(0.0)), 0.0);
+        assert_eq!(calibration.percentile(score(2.0)), 0.4);
+        assert_eq!(calibration.percentile(score(2.5)), 0.6);
+        assert_eq!(calibration.percentile(1000.0), 1.0);
+    }
+
+    #[test]
+    fn calibration_without_samples() {
+        let calibration = AnomalyCalibration::new(&two_neuron_map(), &[], DEFAULT_ANOMALY_QUANTILE);
+        assert_eq!(calibration.training_set_size(), 0);
+        assert!(!calibration.is_anomalous(1000.0));
+        assert_eq!(calibration.percentile(1000.0), 0.0);
+    }
+}
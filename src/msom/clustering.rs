@@ -0,0 +1,282 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap, HashSet}};
+
+use ndarray::{Array1, Array2, Array3, Axis};
+use serde::{Deserialize, Serialize};
+
+use super::{u_matrix_basins, MSOM};
+
+// Groups of neighbouring neurons that read as topics, built from the prototypes of a trained map
+
+const KMEANS_ITERATIONS: usize = 100;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClusteringMethod {
+    #[default]
+    KMeans,
+    // Agglomerative, only clusters that touch on the map are merged so the result stays contiguous
+    Ward,
+    // Basins of the U-matrix, the number of clusters follows from the map itself
+    Watershed,
+}
+
+impl ClusteringMethod {
+    pub const ALL: [ClusteringMethod; 3] = [ClusteringMethod::KMeans, ClusteringMethod::Ward, ClusteringMethod::Watershed];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClusteringMethod::KMeans => "k-means",
+            ClusteringMethod::Ward => "Ward",
+            ClusteringMethod::Watershed => "U-matrix watershed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SuperClusters {
+    pub method: ClusteringMethod,
+    // Requested number of clusters, ignored by the watershed
+    pub k: usize,
+    pub with_context: bool,
+    // Cluster of every neuron, numbered from 0 in the order they are first met going through the map
+    pub labels: Array2<usize>,
+    pub count: usize,
+}
+
+impl SuperClusters {
+    pub fn new(map: &MSOM, method: ClusteringMethod, k: usize, with_context: bool) -> Self {
+        let prototypes = map.prototypes(with_context);
+        let (n, m, dim) = prototypes.dim();
+        // Rows in (i, j) order whatever the memory layout the concatenation left
+        let features = prototypes.as_standard_layout().into_owned().into_shape((n * m, dim)).unwrap();
+        let k = k.clamp(1, n * m);
+
+        let labels = match method {
+            ClusteringMethod::KMeans => k_means(&features, k),
+            ClusteringMethod::Ward => ward(&features, k, |index| {
+                map.topology.neighbours(index / m, index % m, n, m).into_iter().map(|(i, j)| i * m + j).collect()
+            }),
+            ClusteringMethod::Watershed => {
+                let u_matrix = super::u_matrix_of(&features.into_shape((n, m, dim)).unwrap(), map.topology);
+                u_matrix_basins(&u_matrix, map.topology).into_raw_vec()
+            }
+        };
+
+        let (labels, count) = renumber(&labels);
+        Self { method, k, with_context, labels: Array2::from_shape_vec((n, m), labels).unwrap(), count }
+    }
+
+    pub fn cluster_of(&self, cell: (usize, usize)) -> Option<usize> {
+        self.labels.get(cell).copied()
+    }
+
+    // Member cell closest to the mean position of every cluster, where its label goes
+    pub fn label_cells(&self) -> Vec<(usize, usize)> {
+        let mut sums = vec![(0.0, 0.0, 0usize); self.count];
+        for ((i, j), label) in self.labels.indexed_iter() {
+            sums[*label].0 += i as f32;
+            sums[*label].1 += j as f32;
+            sums[*label].2 += 1;
+        }
+
+        sums.iter().enumerate()
+            .map(|(label, (i_sum, j_sum, count))| {
+                let center = (i_sum / *count as f32, j_sum / *count as f32);
+                self.labels.indexed_iter()
+                    .filter(|(_, cell_label)| **cell_label == label)
+                    .map(|(cell, _)| cell)
+                    .min_by(|a, b| squared_distance(*a, center).total_cmp(&squared_distance(*b, center)))
+                    .unwrap()
+            })
+            .collect()
+    }
+}
+
+impl MSOM {
+    // som (and context) prototypes scaled by sqrt(a) (and sqrt(b)), so that euclidean distances between
+    // them are weighted the same way as in the BMU search
+    pub fn prototypes(&self, with_context: bool) -> Array3<f32> {
+        let som = &self.som * self.a.sqrt();
+        if !with_context {
+            return som;
+        }
+
+        ndarray::concatenate(Axis(2), &[som.view(), (&self.context * self.b.sqrt()).view()]).unwrap()
+    }
+}
+
+fn squared_distance(cell: (usize, usize), center: (f32, f32)) -> f32 {
+    (cell.0 as f32 - center.0).powi(2) + (cell.1 as f32 - center.1).powi(2)
+}
+
+// Labels numbered by first occurrence, and their count
+fn renumber(labels: &[usize]) -> (Vec<usize>, usize) {
+    let mut numbers = HashMap::new();
+    let renumbered = labels.iter()
+        .map(|label| {
+            let next = numbers.len();
+            *numbers.entry(*label).or_insert(next)
+        })
+        .collect();
+
+    (renumbered, numbers.len())
+}
+
+// Lloyd's algorithm started from the point closest to the mean followed by the farthest points, which
+// keeps the result the same from run to run
+fn k_means(features: &Array2<f32>, k: usize) -> Vec<usize> {
+    let squared = |a: ndarray::ArrayView1<f32>, b: ndarray::ArrayView1<f32>| {
+        let diff = &a - &b;
+        diff.dot(&diff)
+    };
+
+    let mean = features.mean_axis(Axis(0)).unwrap();
+    let first = (0..features.nrows())
+        .min_by(|a, b| squared(features.row(*a), mean.view()).total_cmp(&squared(features.row(*b), mean.view())))
+        .unwrap();
+    let mut centers = vec![features.row(first).to_owned()];
+    let mut nearest: Vec<f32> = features.rows().into_iter().map(|row| squared(row, centers[0].view())).collect();
+    while centers.len() < k {
+        let farthest = (0..nearest.len()).max_by(|a, b| nearest[*a].total_cmp(&nearest[*b])).unwrap();
+        centers.push(features.row(farthest).to_owned());
+        for (index, row) in features.rows().into_iter().enumerate() {
+            nearest[index] = nearest[index].min(squared(row, centers.last().unwrap().view()));
+        }
+    }
+
+    let mut labels = vec![usize::MAX; features.nrows()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (index, row) in features.rows().into_iter().enumerate() {
+            let closest = (0..k)
+                .min_by(|a, b| squared(row, centers[*a].view()).total_cmp(&squared(row, centers[*b].view())))
+                .unwrap();
+            changed |= labels[index] != closest;
+            labels[index] = closest;
+        }
+        if !changed {
+            break;
+        }
+
+        // Empty clusters keep their old center
+        for (cluster, center) in centers.iter_mut().enumerate() {
+            let members: Vec<usize> = (0..labels.len()).filter(|index| labels[*index] == cluster).collect();
+            if !members.is_empty() {
+                *center = features.select(Axis(0), &members).mean_axis(Axis(0)).unwrap();
+            }
+        }
+    }
+
+    labels
+}
+
+// Ward linkage restricted to `neighbours`: repeatedly merges the two touching clusters whose merge adds the
+// least variance, until k are left or nothing touches anymore
+fn ward(features: &Array2<f32>, k: usize, neighbours: impl Fn(usize) -> Vec<usize>) -> Vec<usize> {
+    let count = features.nrows();
+    let mut sizes = vec![1.0_f32; count];
+    let mut centroids: Vec<Array1<f32>> = features.rows().into_iter().map(|row| row.to_owned()).collect();
+    let mut members: Vec<Vec<usize>> = (0..count).map(|index| vec![index]).collect();
+    let mut adjacency: Vec<HashSet<usize>> = (0..count).map(|index| neighbours(index).into_iter().collect()).collect();
+    // Bumped on every merge, heap entries with an old version are outdated
+    let mut versions = vec![0usize; count];
+
+    let cost = |sizes: &[f32], centroids: &[Array1<f32>], a: usize, b: usize| {
+        let diff = &centroids[a] - &centroids[b];
+        sizes[a] * sizes[b] / (sizes[a] + sizes[b]) * diff.dot(&diff)
+    };
+
+    // Non-negative floats order the same as their bit patterns, which unlike f32 are Ord
+    let mut heap = BinaryHeap::new();
+    for (a, a_neighbours) in adjacency.iter().enumerate() {
+        for &b in a_neighbours.iter().filter(|b| **b > a) {
+            heap.push(Reverse((cost(&sizes, &centroids, a, b).to_bits(), a, b, 0, 0)));
+        }
+    }
+
+    let mut active = count;
+    while active > k {
+        let Some(Reverse((_, a, b, version_a, version_b))) = heap.pop() else {
+            break;
+        };
+        if members[a].is_empty() || members[b].is_empty() || versions[a] != version_a || versions[b] != version_b {
+            continue;
+        }
+
+        let merged_size = sizes[a] + sizes[b];
+        centroids[a] = (&centroids[a] * sizes[a] + &centroids[b] * sizes[b]) / merged_size;
+        sizes[a] = merged_size;
+        let moved = std::mem::take(&mut members[b]);
+        members[a].extend(moved);
+        versions[a] += 1;
+        active -= 1;
+
+        let b_neighbours = std::mem::take(&mut adjacency[b]);
+        for neighbour in b_neighbours {
+            adjacency[neighbour].remove(&b);
+            if neighbour != a {
+                adjacency[neighbour].insert(a);
+                adjacency[a].insert(neighbour);
+            }
+        }
+        adjacency[a].remove(&a);
+
+        for &neighbour in &adjacency[a] {
+            let (first, second) = (a.min(neighbour), a.max(neighbour));
+            heap.push(Reverse((cost(&sizes, &centroids, first, second).to_bits(), first, second, versions[first], versions[second])));
+        }
+    }
+
+    let mut labels = vec![0; count];
+    for (cluster, cluster_members) in members.iter().enumerate() {
+        for member in cluster_members {
+            labels[*member] = cluster;
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Top row around 0 and bottom row around 20, rectangular
+    fn two_row_map() -> MSOM {
+        let som = Array3::from_shape_vec((2, 3, 1), vec![0.0, 1.0, 3.0, 20.0, 22.0, 23.0]).unwrap();
+        MSOM::from_weights(1.0, 1.0, 0.5, som, Array3::zeros((2, 3, 1))).unwrap()
+    }
+
+    #[test]
+    fn methods_find_the_rows() {
+        let map = two_row_map();
+        let rows = Array2::from_shape_vec((2, 3), vec![0, 0, 0, 1, 1, 1]).unwrap();
+
+        for method in ClusteringMethod::ALL {
+            let clusters = SuperClusters::new(&map, method, 2, false);
+            assert_eq!(clusters.labels, rows, "{}", method.name());
+            assert_eq!(clusters.count, 2, "{}", method.name());
+            assert_eq!(clusters.cluster_of((1, 2)), Some(1));
+            assert_eq!(clusters.cluster_of((2, 0)), None);
+        }
+    }
+
+    #[test]
+    fn ward_stays_contiguous() {
+        // The two ends are alike but don't touch
+        let som = Array3::from_shape_vec((1, 3, 1), vec![0.0, 10.0, 0.0]).unwrap();
+        let map = MSOM::from_weights(1.0, 1.0, 0.5, som, Array3::zeros((1, 3, 1))).unwrap();
+
+        let k_means = SuperClusters::new(&map, ClusteringMethod::KMeans, 2, false);
+        assert_eq!(k_means.labels.into_raw_vec(), vec![0, 1, 0]);
+
+        let ward = SuperClusters::new(&map, ClusteringMethod::Ward, 2, false);
+        assert_eq!(ward.count, 2);
+        assert_ne!(ward.labels[(0, 0)], ward.labels[(0, 2)]);
+    }
+
+    #[test]
+    fn k_is_clamped() {
+        let map = two_row_map();
+        assert_eq!(SuperClusters::new(&map, ClusteringMethod::KMeans, 0, false).count, 1);
+        assert_eq!(SuperClusters::new(&map, ClusteringMethod::Ward, 100, true).count, 6);
+    }
+}
@@ -0,0 +1,25 @@
+use ndarray::Array3;
+use serde::{Deserialize, Serialize};
+
+use super::MSOM;
+
+// Weights of a map part way through its training, to watch how it organizes itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrainingSnapshot {
+    // Number of iterations done when it was taken, 0 for the weights before training
+    pub iteration: usize,
+    pub som: Array3<f32>,
+    pub context: Array3<f32>,
+}
+
+impl TrainingSnapshot {
+    pub fn of(map: &MSOM, iteration: usize) -> Self {
+        Self { iteration, som: map.som.clone(), context: map.context.clone() }
+    }
+
+    // `map` with the weights it had back then
+    pub fn to_map(&self, map: &MSOM) -> MSOM {
+        MSOM { n: map.n, m: map.m, map_input_size: map.map_input_size, a: map.a, b: map.b, gamma: map.gamma,
+            topology: map.topology, som: self.som.clone(), context: self.context.clone() }
+    }
+}
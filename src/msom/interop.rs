@@ -0,0 +1,91 @@
+use std::{fs::File, path::Path};
+
+use ndarray::{prelude::*, OwnedRepr};
+use ndarray_npy::{read_npy, write_npy, NpzReader, NpzWriter};
+
+use super::MSOM;
+
+// a, b and gamma of a fresh map, for files that only carry the weights
+const DEFAULT_PARAMS: [f32; 3] = [1.0, 1.0, 0.5];
+
+impl MSOM {
+    // Arrays are named the way numpy's savez names them, so `np.load(path)["som"]` works in a notebook
+    pub fn dump_to_npz(&self, path: &Path) -> Result<(), String> {
+        let file = File::create(path)
+            .map_err(|err| format!("Error while opening {}: {err}", path.display()))?;
+
+        let mut npz = NpzWriter::new(file);
+        npz.add_array("som.npy", &self.som)
+            .and_then(|_| npz.add_array("context.npy", &self.context))
+            .and_then(|_| npz.add_array("params.npy", &array![self.a, self.b, self.gamma]))
+            .map_err(|err| format!("Error while writing {}: {err}", path.display()))?;
+        npz.finish()
+            .map_err(|err| format!("Error while writing {}: {err}", path.display()))?;
+
+        Ok(())
+    }
+
+    // Reads our own dumps as well as archives saved from numpy, only `som` is required.
+    // A missing context starts at zero like in a new map
+    pub fn load_from_npz(path: &Path) -> Result<MSOM, String> {
+        let file = File::open(path)
+            .map_err(|err| format!("Error while opening {}: {err}", path.display()))?;
+        let mut npz = NpzReader::new(file)
+            .map_err(|err| format!("Error while reading {}: {err}", path.display()))?;
+
+        let som = read_npz_array(&mut npz, "som")?
+            .ok_or_else(|| format!("{} has no `som` array", path.display()))?;
+        let context = read_npz_array(&mut npz, "context")?;
+        let [a, b, gamma] = match read_npz_array::<Ix1>(&mut npz, "params")? {
+            Some(params) => params.as_slice().and_then(|params| params.try_into().ok())
+                .ok_or("Array `params` should hold a, b and gamma")?,
+            None => DEFAULT_PARAMS,
+        };
+
+        MSOM::from_minisom_weights(som, context, a, b, gamma)
+    }
+
+    // MiniSom's `get_weights()` is an (n, m, dim) array indexed by grid position, same as our som.
+    // It has no notion of context, that one travels as a separate tensor of the same shape
+    pub fn to_minisom_weights(&self) -> (Array3<f32>, Array3<f32>) {
+        (self.som.clone(), self.context.clone())
+    }
+
+    pub fn from_minisom_weights(weights: Array3<f32>, context: Option<Array3<f32>>, a: f32, b: f32, gamma: f32) -> Result<MSOM, String> {
+        let context = context.unwrap_or_else(|| Array3::zeros(weights.raw_dim()));
+        MSOM::from_weights(a, b, gamma, weights, context)
+    }
+
+    // Plain .npy of the MiniSom weights, e.g. from `np.save(path, som.get_weights())`
+    pub fn load_from_minisom_npy(path: &Path) -> Result<MSOM, String> {
+        let weights = match read_npy::<_, Array3<f32>>(path) {
+            Ok(weights) => weights,
+            // numpy saves float64 unless asked otherwise
+            Err(f32_err) => read_npy::<_, Array3<f64>>(path)
+                .map(|weights| weights.mapv(|value| value as f32))
+                .map_err(|_| format!("Error while reading {}: {f32_err}", path.display()))?,
+        };
+
+        let [a, b, gamma] = DEFAULT_PARAMS;
+        MSOM::from_minisom_weights(weights, None, a, b, gamma)
+    }
+
+    pub fn dump_to_minisom_npy(&self, path: &Path) -> Result<(), String> {
+        write_npy(path, &self.to_minisom_weights().0)
+            .map_err(|err| format!("Error while writing {}: {err}", path.display()))
+    }
+}
+
+fn read_npz_array<D: Dimension>(npz: &mut NpzReader<File>, name: &str) -> Result<Option<Array<f32, D>>, String> {
+    let names = npz.names().map_err(|err| err.to_string())?;
+    let Some(entry) = names.iter().find(|entry| *entry == name || **entry == format!("{name}.npy")) else {
+        return Ok(None);
+    };
+
+    match npz.by_name::<OwnedRepr<f32>, D>(entry) {
+        Ok(array) => Ok(Some(array)),
+        Err(f32_err) => npz.by_name::<OwnedRepr<f64>, D>(entry)
+            .map(|array| Some(array.mapv(|value| value as f32)))
+            .map_err(|_| format!("Array `{name}`: {f32_err}")),
+    }
+}
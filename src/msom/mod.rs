@@ -1,58 +1,147 @@
 use core::prelude;
 use meshgridrs::{meshgrid, Indexing};
 use ndarray::{prelude::*, stack, Dimension, OwnedRepr};
-use ndarray_ndimage::{pad, PadMode};
-use ndarray_npy::NpzWriter;
 use ndarray_stats::{QuantileExt, SummaryStatisticsExt};
 use std::cmp;
-use std::collections::VecDeque;
-use std::fs::File;
+use std::collections::{HashMap, VecDeque};
 use tqdm::tqdm;
 use serde::{Serialize, Deserialize};
 
-pub fn get_vec_median(samples: &Vec<ArrayView1<f32>>) -> Array1<f32> {
-    let mut cur_median = Array1::zeros(0);
-
-    for sample in samples {
-        let new_len = sample.len();
-        let cur_len = cur_median.len();
-        println!("cur_len {cur_len}, new_len {new_len}");
-
-        if new_len > cur_len {
-            cur_median = &pad(
-                &cur_median,
-                &[[0, new_len - cur_len]],
-                PadMode::Constant(0.0),
-            ) + sample;
-        } else if new_len < cur_len {
-            cur_median += &pad(sample, &[[0, cur_len - new_len]], PadMode::Constant(0.0));
-        } else {
-            cur_median += sample;
+use history::TrainingSnapshot;
+
+pub mod anomaly;
+pub mod clustering;
+pub mod history;
+pub mod interop;
+pub mod prediction;
+pub mod projection;
+
+// Source BMU, target BMU and how many times the evaluation went from one to the other
+pub type Transition = ((usize, usize), (usize, usize), usize);
+
+// BMU-to-BMU transition counts along evaluation trajectories, sorted by source then target
+pub fn count_transitions(trajectories: &[Vec<((usize, usize), f32)>]) -> Vec<Transition> {
+    let mut counts = HashMap::new();
+    for trajectory in trajectories {
+        for (from, to) in trajectory.iter().zip(trajectory.iter().skip(1)) {
+            *counts.entry((from.0, to.0)).or_insert(0usize) += 1;
         }
     }
 
-    cur_median / (samples.len() as f32)
+    let mut transitions: Vec<_> = counts.into_iter().map(|((from, to), count)| (from, to, count)).collect();
+    transitions.sort();
+    transitions
 }
 
-pub fn get_vec_std(samples: &Vec<ArrayView1<f32>>) -> f32 {
-    println!("Before Median");
-    let median = get_vec_median(samples);
-    let med_len = median.len();
+// Recurrent state carried between the chunks of a sequence during evaluation
+#[derive(Debug, Clone)]
+pub struct EvaluationState {
+    previous_w: Array1<f32>,
+    previous_c: Array1<f32>,
+    cur_a: f32,
+}
 
-    let mut diff_sq_sum: Array1<f32> = Array1::zeros(median.len());
+// Lattice the neighbourhood distances are measured on, neuron (i, j) sits at x = i, y = j.
+// Hexagonal follows SOM_PAK: odd rows are shifted right by half a unit
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Topology {
+    #[default]
+    Rectangular,
+    Hexagonal,
+}
+
+impl Topology {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Topology::Rectangular => "rect",
+            Topology::Hexagonal => "hexa",
+        }
+    }
 
-    for sample in samples {
-        println!("Sample {sample}, median {median}");
-        let cur_len = sample.len();
-        let diff = &pad(sample, &[[0, med_len - cur_len]], PadMode::Minimum) - &median;
+    pub fn position(&self, i: usize, j: usize) -> (f32, f32) {
+        match self {
+            Topology::Rectangular => (i as f32, j as f32),
+            Topology::Hexagonal => (i as f32 + 0.5 * (j % 2) as f32, j as f32 * 3.0_f32.sqrt() / 2.0),
+        }
+    }
 
-        diff_sq_sum += &(&diff * &diff);
+    // Directly adjacent neurons on an n x m lattice, 4 for rectangular and 6 for hexagonal
+    pub fn neighbours(&self, i: usize, j: usize, n: usize, m: usize) -> Vec<(usize, usize)> {
+        let (i, j) = (i as isize, j as isize);
+        let offsets: &[(isize, isize)] = match self {
+            Topology::Rectangular => &[(-1, 0), (1, 0), (0, -1), (0, 1)],
+            // Rows above and below are shifted towards the odd rows
+            Topology::Hexagonal if j % 2 == 0 => &[(-1, 0), (1, 0), (-1, -1), (0, -1), (-1, 1), (0, 1)],
+            Topology::Hexagonal => &[(-1, 0), (1, 0), (0, -1), (1, -1), (0, 1), (1, 1)],
+        };
+
+        offsets.iter()
+            .map(|(di, dj)| (i + di, j + dj))
+            .filter(|(i, j)| *i >= 0 && *j >= 0 && *i < n as isize && *j < m as isize)
+            .map(|(i, j)| (i as usize, j as usize))
+            .collect()
     }
+}
+
+// Mean input sequence of each cell's samples, grouped by the neuron they end on. Shorter samples count as zeros
+// past their end, empty cells get an empty sequence
+pub fn receptive_fields_of(samples_per_cell: &[Vec<Vec<ArrayView1<f32>>>]) -> Vec<Vec<Array1<f32>>> {
+    samples_per_cell.iter()
+        .map(|column| column.iter()
+            .map(|samples| {
+                let len = samples.iter().map(|sample| sample.len()).max().unwrap_or(0);
+                let mut field = Array1::zeros(len);
+                for sample in samples {
+                    let mut prefix = field.slice_mut(s![..sample.len()]);
+                    prefix += sample;
+                }
+                field / cmp::max(1, samples.len()) as f32
+            })
+            .collect())
+        .collect()
+}
 
-    diff_sq_sum.sum().sqrt() / (samples.len() as f32)
+// U-matrix of any (n, m, dim) prototypes
+pub fn u_matrix_of(prototypes: &Array3<f32>, topology: Topology) -> Array2<f32> {
+    let (n, m, _) = prototypes.dim();
+    Array2::from_shape_fn((n, m), |(i, j)| {
+        let neighbours = topology.neighbours(i, j, n, m);
+        let total: f32 = neighbours.iter()
+            .map(|(k, l)| {
+                let diff = &prototypes.slice(s![i, j, ..]) - &prototypes.slice(s![*k, *l, ..]);
+                diff.dot(&diff).sqrt()
+            })
+            .sum();
+
+        total / neighbours.len().max(1) as f32
+    })
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Labels every neuron with the local minimum of the U-matrix it drains to by steepest descent, labels
+// are numbered from 0 in the order the minima are found
+pub fn u_matrix_basins(u_matrix: &Array2<f32>, topology: Topology) -> Array2<usize> {
+    let (n, m) = u_matrix.dim();
+    let mut minima = HashMap::new();
+    let mut basins = Array2::zeros((n, m));
+
+    for i in 0..n {
+        for j in 0..m {
+            let mut cell = (i, j);
+            while let Some(lower) = topology.neighbours(cell.0, cell.1, n, m).into_iter()
+                .filter(|neighbour| u_matrix[*neighbour] < u_matrix[cell])
+                .min_by(|a, b| u_matrix[*a].total_cmp(&u_matrix[*b])) {
+                cell = lower;
+            }
+
+            let next_label = minima.len();
+            basins[(i, j)] = *minima.entry(cell).or_insert(next_label);
+        }
+    }
+
+    basins
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct MSOM {
     pub n: usize,
     pub m: usize,
@@ -60,6 +149,8 @@ pub struct MSOM {
     pub a: f32,
     pub b: f32,
     pub gamma: f32,
+    #[serde(default)]
+    pub topology: Topology,
 
     som: ArrayBase<OwnedRepr<f32>, Dim<[usize; 3]>>,
     context: ArrayBase<OwnedRepr<f32>, Dim<[usize; 3]>>,
@@ -76,9 +167,28 @@ impl MSOM {
             gamma,
             a,
             b,
+            topology: Topology::Rectangular,
         }
     }
 
+    // Builds a map from already trained weights, som and context both have the shape (n, m, map_input_size)
+    pub fn from_weights(a: f32, b: f32, gamma: f32, som: Array3<f32>, context: Array3<f32>) -> Result<MSOM, String> {
+        if som.shape() != context.shape() {
+            return Err(format!("The som has shape {:?} while the context has shape {:?}", som.shape(), context.shape()));
+        }
+
+        let (n, m, map_input_size) = som.dim();
+        Ok(MSOM { n, m, map_input_size, a, b, gamma, topology: Topology::Rectangular, som, context })
+    }
+
+    pub fn som(&self) -> &Array3<f32> {
+        &self.som
+    }
+
+    pub fn context(&self) -> &Array3<f32> {
+        &self.context
+    }
+
     pub fn fit(
         &mut self,
         dataset: &Vec<ArrayView1<f32>>,
@@ -86,7 +196,8 @@ impl MSOM {
         learning_rate_base: f32,
         gauss_width_squared_base: f32,
         time_constant: f32,
-    ) {
+        snapshot_every: usize,
+    ) -> Vec<TrainingSnapshot> {
         let grid_ = meshgrid(
             &vec![
                 Array::range(0.0, self.m as f32, 1.0),
@@ -95,7 +206,22 @@ impl MSOM {
             Indexing::Xy,
         )
         .unwrap();
-        let grid = stack![Axis(2), grid_[1], grid_[0]];
+        let mut grid = stack![Axis(2), grid_[1], grid_[0]];
+        if self.topology != Topology::Rectangular {
+            for i in 0..self.n {
+                for j in 0..self.m {
+                    let (x, y) = self.topology.position(i, j);
+                    grid[[i, j, 0]] = x;
+                    grid[[i, j, 1]] = y;
+                }
+            }
+        }
+
+        // Every `snapshot_every` iterations and once more at the end, none at all if it's 0
+        let mut snapshots = vec![];
+        if snapshot_every != 0 {
+            snapshots.push(TrainingSnapshot::of(self, 0));
+        }
 
         // println!("{grid:?}");
         for i in tqdm(0..train_iterations) {
@@ -125,7 +251,7 @@ impl MSOM {
                     let best_unit_coords = errs.argmin().unwrap();
 
                     let shifted_grid =
-                        &grid - &array![best_unit_coords.0 as f32, best_unit_coords.1 as f32];
+                        &grid - &grid.slice(s![best_unit_coords.0, best_unit_coords.1, ..]);
                     let distances = (&shifted_grid * &shifted_grid).sum_axis(Axis(2));
 
                     let neighbourhood_func_values = distances
@@ -151,123 +277,157 @@ impl MSOM {
                 }
                 self.a = temp_a;
             }
-        }
-    }
 
-    pub fn evaluate(&self, sample: ArrayView1<f32>) -> (usize, usize) {
-        let mut best_unit_coords = (0, 0);
-
-        let mut previous_w: ArrayBase<ndarray::OwnedRepr<f32>, ndarray::Dim<[usize; 1]>> =
-            ArrayBase::zeros(self.map_input_size);
-        let mut previous_c: ArrayBase<ndarray::OwnedRepr<f32>, ndarray::Dim<[usize; 1]>> =
-            ArrayBase::zeros(self.map_input_size);
-
-        let mut cur_a = self.a;
-        for chunk_pos in (0..sample.len()).step_by(self.map_input_size) {
-            cur_a *= 0.99;
-            let chunk = sample.slice(s![chunk_pos..(chunk_pos + self.map_input_size)]);
-
-            let cur_diff = -(self.som.clone() - chunk);
-            let cur_errs = (&cur_diff * &cur_diff).sum_axis(Axis(2));
-            let prev_diff = -(self.context.clone()
-                - (self.gamma * previous_w + (1.0 - self.gamma) * previous_c));
-            let prev_errs = (&prev_diff * &prev_diff).sum_axis(Axis(2));
-            let errs = cur_a * cur_errs + self.b * prev_errs;
-
-            best_unit_coords = errs.argmin().unwrap();
-
-            previous_w = self
-                .som
-                .slice(s![best_unit_coords.0, best_unit_coords.1, ..])
-                .to_owned();
-            previous_c = self
-                .context
-                .slice(s![best_unit_coords.0, best_unit_coords.1, ..])
-                .to_owned();
+            if snapshot_every != 0 && ((i + 1) % snapshot_every == 0 || i + 1 == train_iterations) {
+                snapshots.push(TrainingSnapshot::of(self, i + 1));
+            }
         }
 
-        return best_unit_coords;
+        snapshots
     }
 
-    pub fn dump_to_npz(&self, path: &str) {
-        let mut npz = NpzWriter::new(File::create(path).unwrap());
-        npz.add_array("som", &self.som).unwrap();
-        npz.add_array("context", &self.context).unwrap();
-        npz.finish().unwrap();
+    // None for an empty sample, e.g. a text without any known words, which has no BMU
+    pub fn evaluate(&self, sample: ArrayView1<f32>) -> Option<(usize, usize)> {
+        self.evaluate_trajectory(sample).last().map(|(best_unit_coords, _)| *best_unit_coords)
     }
 
-    pub fn reception_field_averaged(
-        &self,
-        samples: &Vec<ArrayView1<f32>>,
-    ) -> Vec<Vec<Array1<f32>>> {
-        let mut counts = vec![vec![0; self.m]; self.n];
-        let mut vector_sums: Vec<Vec<Array1<f32>>> = vec![vec![Array1::zeros(0); self.m]; self.n];
-        for sample in tqdm(samples.iter()) {
-            let prediction = self.evaluate(*sample);
-            counts[prediction.0][prediction.1] += 1;
-
-            let new_len = sample.len();
-            let cur_len = vector_sums[prediction.0][prediction.1].len();
-
-            if new_len > cur_len {
-                vector_sums[prediction.0][prediction.1] = &pad(
-                    &vector_sums[prediction.0][prediction.1],
-                    &[[0, new_len - cur_len]],
-                    PadMode::Minimum,
-                ) + sample;
-            } else if new_len < cur_len {
-                vector_sums[prediction.0][prediction.1] +=
-                    &pad(sample, &[[0, new_len - cur_len]], PadMode::Minimum);
-            } else {
-                vector_sums[prediction.0][prediction.1] += sample;
-            }
-        }
-
-        for row_i in 0..self.n {
-            for vector_sum_i in 0..self.m {
-                vector_sums[row_i][vector_sum_i] /= cmp::max(1, counts[row_i][vector_sum_i]) as f32;
-            }
+    pub fn initial_state(&self) -> EvaluationState {
+        EvaluationState {
+            previous_w: ArrayBase::zeros(self.map_input_size),
+            previous_c: ArrayBase::zeros(self.map_input_size),
+            cur_a: self.a,
         }
+    }
 
-        vector_sums
+    // Feeds one chunk to the map, returns the BMU and its combined error (a * cur_errs + b * prev_errs)
+    pub fn evaluate_step(&self, state: &mut EvaluationState, chunk: ArrayView1<f32>) -> ((usize, usize), f32) {
+        state.cur_a *= 0.99;
+
+        let cur_diff = -(self.som.clone() - chunk);
+        let cur_errs = (&cur_diff * &cur_diff).sum_axis(Axis(2));
+        let prev_diff = -(self.context.clone()
+            - (self.gamma * &state.previous_w + (1.0 - self.gamma) * &state.previous_c));
+        let prev_errs = (&prev_diff * &prev_diff).sum_axis(Axis(2));
+        let errs = state.cur_a * cur_errs + self.b * prev_errs;
+
+        let best_unit_coords = errs.argmin().unwrap();
+
+        state.previous_w = self
+            .som
+            .slice(s![best_unit_coords.0, best_unit_coords.1, ..])
+            .to_owned();
+        state.previous_c = self
+            .context
+            .slice(s![best_unit_coords.0, best_unit_coords.1, ..])
+            .to_owned();
+
+        (best_unit_coords, errs[best_unit_coords])
     }
 
-    pub fn reception_field_count(&self, samples: &Vec<ArrayView1<f32>>) -> Array2<usize> {
-        let mut counts = Array2::zeros((self.n, self.m));
-        for sample in tqdm(samples.into_iter()) {
-            let prediction = self.evaluate(sample.view());
-            counts[(prediction.0, prediction.1)] += 1;
-        }
+    // BMU and its combined error for every chunk of the sample
+    pub fn evaluate_trajectory(&self, sample: ArrayView1<f32>) -> Vec<((usize, usize), f32)> {
+        let mut state = self.initial_state();
+
+        (0..sample.len())
+            .step_by(self.map_input_size)
+            .map(|chunk_pos| {
+                let chunk = sample.slice(s![chunk_pos..(chunk_pos + self.map_input_size)]);
+                self.evaluate_step(&mut state, chunk)
+            })
+            .collect()
+    }
 
-        counts
+    // Squared distance between the final BMU's context weights and the context it was fed, i.e. the part of its
+    // error that comes from the history of the sequence
+    pub fn context_error(&self, trajectory: &[((usize, usize), f32)]) -> f32 {
+        let Some(((i, j), _)) = trajectory.last() else {
+            return 0.0;
+        };
+
+        let fed_context = match trajectory.len().checked_sub(2).map(|index| trajectory[index].0) {
+            Some((k, l)) => self.gamma * &self.som.slice(s![k, l, ..]) + (1.0 - self.gamma) * &self.context.slice(s![k, l, ..]),
+            None => Array1::zeros(self.map_input_size),
+        };
+        let diff = &self.context.slice(s![*i, *j, ..]) - &fed_context;
+        diff.dot(&diff)
     }
 
-    pub fn quantization_error(&self, samples: &Vec<ArrayView1<f32>>) -> Array2<f32> {
-        let mut vector_occurences: Vec<Vec<Vec<ArrayView1<f32>>>> =
-            vec![vec![vec![]; self.m]; self.n];
-        for sample in tqdm(samples.iter()) {
-            let prediction = self.evaluate(*sample);
-            vector_occurences[prediction.0][prediction.1].push(*sample);
-        }
+    // Squared distance between the last chunk of the sample and the final BMU's input weights, the part of its error
+    // that comes from the input itself. Unlike the combined error it is not scaled by a
+    pub fn quantization_error(&self, sample: ArrayView1<f32>, trajectory: &[((usize, usize), f32)]) -> f32 {
+        let Some(((i, j), _)) = trajectory.last() else {
+            return 0.0;
+        };
 
-        let mut quantization_errors: Array2<f32> = Array2::zeros((self.n, self.m));
-        for row_i in 0..self.n {
-            for col_i in 0..self.m {
-                let vec_array1 = &vector_occurences[row_i][col_i];
-                if vec_array1.len() == 0 {
-                    continue;
-                }
+        let chunk_pos = (trajectory.len() - 1) * self.map_input_size;
+        let diff = &sample.slice(s![chunk_pos..chunk_pos + self.map_input_size]) - &self.som.slice(s![*i, *j, ..]);
+        diff.dot(&diff)
+    }
 
-                let cur_std = get_vec_std(vec_array1);
-                quantization_errors[(row_i, col_i)] = cur_std;
-                if cur_std.is_nan() {
-                    println!("FUUUUUUUUUUUUUUuuCk {row_i} {col_i}");
-                }
+    // Mean distance of every neuron to its neighbours, with the same a/b weighting of the input and context
+    // parts as the BMU search. High values are borders between clusters
+    pub fn u_matrix(&self) -> Array2<f32> {
+        u_matrix_of(&self.prototypes(true), self.topology)
+    }
+}
 
-                // let cur_arr: Array2<f32> = vector_occurences[row_i][col_i];
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions_are_counted() {
+        let trajectories = vec![
+            vec![((0, 0), 1.0), ((0, 1), 1.0), ((0, 0), 1.0), ((0, 1), 1.0)],
+            vec![((0, 1), 1.0), ((1, 1), 1.0), ((1, 1), 1.0)],
+            vec![((1, 0), 1.0)],
+            vec![],
+        ];
+
+        assert_eq!(count_transitions(&trajectories), vec![
+            ((0, 0), (0, 1), 2),
+            ((0, 1), (0, 0), 1),
+            ((0, 1), (1, 1), 1),
+            ((1, 1), (1, 1), 1),
+        ]);
+    }
+    #[test]
+    fn hexagonal_neighbours_at_edges() {
+        let hexagonal = Topology::Hexagonal;
+        // Even rows lean left, odd rows right
+        assert_eq!(hexagonal.neighbours(0, 0, 3, 3), vec![(1, 0), (0, 1)]);
+        assert_eq!(hexagonal.neighbours(2, 0, 3, 3), vec![(1, 0), (1, 1), (2, 1)]);
+        assert_eq!(hexagonal.neighbours(0, 1, 3, 3), vec![(1, 1), (0, 0), (1, 0), (0, 2), (1, 2)]);
+        assert_eq!(hexagonal.neighbours(2, 1, 3, 3), vec![(1, 1), (2, 0), (2, 2)]);
+        assert_eq!(hexagonal.neighbours(1, 1, 3, 3).len(), 6);
+        assert_eq!(hexagonal.neighbours(0, 0, 1, 1), vec![]);
+
+        assert_eq!(Topology::Rectangular.neighbours(0, 0, 3, 3), vec![(1, 0), (0, 1)]);
+        assert_eq!(Topology::Rectangular.neighbours(2, 1, 3, 3), vec![(1, 1), (2, 0), (2, 2)]);
+
+        // Adjacency goes both ways everywhere on the map
+        for topology in [Topology::Rectangular, Topology::Hexagonal] {
+            for (n, m) in [(3, 4), (4, 3), (1, 5)] {
+                for i in 0..n {
+                    for j in 0..m {
+                        for (k, l) in topology.neighbours(i, j, n, m) {
+                            assert!(topology.neighbours(k, l, n, m).contains(&(i, j)), "{topology:?} ({i}, {j}) ({k}, {l})");
+                        }
+                    }
+                }
             }
         }
-
-        quantization_errors
+    }
+    #[test]
+    fn receptive_field_with_shorter_sample() {
+        // The shorter sample comes after the longer one, it used to underflow the padding
+        let long = arr1(&[1.0, 2.0, 3.0]);
+        let short = arr1(&[3.0]);
+        let samples_per_cell = vec![vec![vec![long.view(), short.view()], vec![short.view()], vec![]]];
+
+        let fields = receptive_fields_of(&samples_per_cell);
+        assert_eq!(fields[0][0], arr1(&[2.0, 1.0, 1.5]));
+        assert_eq!(fields[0][1], arr1(&[3.0]));
+        assert!(fields[0][2].is_empty());
     }
 }
@@ -0,0 +1,159 @@
+use ndarray::{prelude::*, Zip};
+use serde::{Deserialize, Serialize};
+
+use super::{EvaluationState, MSOM};
+
+// Per neuron average of the chunks that followed a visit to it on the training data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NextStepPredictor {
+    next_chunk_sums: Array3<f32>,
+    transition_counts: Array2<f32>,
+}
+
+impl NextStepPredictor {
+    pub fn new(map: &MSOM, training_samples: &[ArrayView1<f32>]) -> Self {
+        let mut next_chunk_sums = Array3::zeros((map.n, map.m, map.map_input_size));
+        let mut transition_counts = Array2::zeros((map.n, map.m));
+
+        for sample in training_samples {
+            let mut state = map.initial_state();
+            let chunks = sample_chunks(map, *sample);
+
+            for (chunk, next_chunk) in chunks.iter().zip(chunks.iter().skip(1)) {
+                let (best_unit_coords, _) = map.evaluate_step(&mut state, *chunk);
+
+                let mut sum = next_chunk_sums.slice_mut(s![best_unit_coords.0, best_unit_coords.1, ..]);
+                sum += next_chunk;
+                transition_counts[best_unit_coords] += 1.0;
+            }
+        }
+
+        Self { next_chunk_sums, transition_counts }
+    }
+
+    // Falls back to the neuron prototype when nothing ever followed the neuron
+    pub fn expected_next(&self, map: &MSOM, best_unit_coords: (usize, usize)) -> Array1<f32> {
+        let count = self.transition_counts[best_unit_coords];
+        if count == 0.0 {
+            return map.som.slice(s![best_unit_coords.0, best_unit_coords.1, ..]).to_owned();
+        }
+
+        self.next_chunk_sums.slice(s![best_unit_coords.0, best_unit_coords.1, ..]).to_owned() / count
+    }
+
+    // Predicts `steps` chunks ahead, each prediction is fed back into the map as the next input
+    pub fn rollout(&self, map: &MSOM, prefix: ArrayView1<f32>, steps: usize) -> Vec<Array1<f32>> {
+        let mut state = map.initial_state();
+        let mut last_unit = None;
+        for chunk in sample_chunks(map, prefix) {
+            last_unit = Some(map.evaluate_step(&mut state, chunk).0);
+        }
+
+        match last_unit {
+            Some(best_unit_coords) => self.continue_rollout(map, state, best_unit_coords, steps),
+            None => vec![],
+        }
+    }
+
+    fn continue_rollout(&self, map: &MSOM, mut state: EvaluationState,
+        mut best_unit_coords: (usize, usize), steps: usize) -> Vec<Array1<f32>> {
+        let mut predictions = vec![];
+        for _ in 0..steps {
+            let prediction = self.expected_next(map, best_unit_coords);
+            best_unit_coords = map.evaluate_step(&mut state, prediction.view()).0;
+            predictions.push(prediction);
+        }
+
+        predictions
+    }
+
+    // Mean squared error per element for every horizon 1..=steps, over all positions of the held-out samples
+    pub fn prediction_error(&self, map: &MSOM, samples: &[ArrayView1<f32>], steps: usize) -> Vec<f32> {
+        let mut error_sums = vec![0.0; steps];
+        let mut error_counts = vec![0usize; steps];
+
+        for sample in samples {
+            let mut state = map.initial_state();
+            let chunks = sample_chunks(map, *sample);
+
+            for (chunk_i, chunk) in chunks.iter().enumerate() {
+                let (best_unit_coords, _) = map.evaluate_step(&mut state, *chunk);
+                let predictions = self.continue_rollout(map, state.clone(), best_unit_coords, steps);
+
+                for (horizon, (prediction, actual)) in predictions.iter().zip(&chunks[chunk_i + 1..]).enumerate() {
+                    let mut squared_error = 0.0;
+                    Zip::from(prediction).and(actual).for_each(|p, a| squared_error += (p - a) * (p - a));
+
+                    error_sums[horizon] += squared_error / (map.map_input_size as f32);
+                    error_counts[horizon] += 1;
+                }
+            }
+        }
+
+        error_sums.iter().zip(error_counts)
+            .map(|(sum, count)| if count == 0 { f32::NAN } else { sum / (count as f32) })
+            .collect()
+    }
+}
+
+fn sample_chunks<'a>(map: &MSOM, sample: ArrayView1<'a, f32>) -> Vec<ArrayView1<'a, f32>> {
+    (0..sample.len())
+        .step_by(map.map_input_size)
+        .map(|chunk_pos| sample.slice_move(s![chunk_pos..(chunk_pos + map.map_input_size)]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr1;
+
+    use super::*;
+
+    // One neuron at 0 and one at 10, without context so that the BMU is always the nearest prototype
+    fn two_neuron_map() -> MSOM {
+        let som = Array3::from_shape_vec((1, 2, 1), vec![0.0, 10.0]).unwrap();
+        MSOM::from_weights(1.0, 0.0, 0.5, som, Array3::zeros((1, 2, 1))).unwrap()
+    }
+
+    #[test]
+    fn predicts_alternating_sequence() {
+        let map = two_neuron_map();
+        let training = arr1(&[0.0, 10.0, 0.0, 10.0, 1.0, 9.0]);
+        let predictor = NextStepPredictor::new(&map, &[training.view()]);
+
+        assert_eq!(predictor.expected_next(&map, (0, 0)), arr1(&[29.0 / 3.0]));
+        assert_eq!(predictor.expected_next(&map, (0, 1)), arr1(&[0.5]));
+
+        let rollout = predictor.rollout(&map, arr1(&[1.0]).view(), 3);
+        assert_eq!(rollout, vec![arr1(&[29.0 / 3.0]), arr1(&[0.5]), arr1(&[29.0 / 3.0])]);
+        assert!(predictor.rollout(&map, arr1(&[]).view(), 3).is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_prototype() {
+        let map = two_neuron_map();
+        let training = arr1(&[0.0]);
+        let predictor = NextStepPredictor::new(&map, &[training.view()]);
+
+        assert_eq!(predictor.expected_next(&map, (0, 0)), arr1(&[0.0]));
+        assert_eq!(predictor.expected_next(&map, (0, 1)), arr1(&[10.0]));
+    }
+
+    #[test]
+    fn prediction_error_per_horizon() {
+        let map = two_neuron_map();
+        let training = arr1(&[0.0, 10.0, 0.0, 10.0]);
+        let predictor = NextStepPredictor::new(&map, &[training.view()]);
+
+        let exact = arr1(&[0.0, 10.0, 0.0]);
+        let errors = predictor.prediction_error(&map, &[exact.view()], 3);
+        assert_eq!(errors[..2], [0.0, 0.0]);
+        // Nothing is 3 chunks after any position of a 3 chunk sample
+        assert!(errors[2].is_nan());
+
+        // Every position predicts 10 and then 0 while the sample stays at 0
+        let constant = arr1(&[0.0, 0.0, 0.0]);
+        let errors = predictor.prediction_error(&map, &[constant.view()], 2);
+        assert_eq!(errors, vec![100.0, 0.0]);
+    }
+}
@@ -0,0 +1,228 @@
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+use serde::{Deserialize, Serialize};
+
+use super::MSOM;
+
+// Embeddings of the som prototypes in 2 or 3 dimensions, to see how the lattice lies in input space
+
+const POWER_ITERATIONS: usize = 300;
+const SAMMON_ITERATIONS: usize = 200;
+// Magic factor of Sammon's pseudo-Newton steps, 0.3 to 0.4 in the original paper
+const SAMMON_STEP: f32 = 0.3;
+// Prototypes whose positions are blended to place an input with the non-linear methods
+const INPUT_NEIGHBOURS: usize = 3;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProjectionMethod {
+    #[default]
+    Pca,
+    // Keeps small distances first, started from the PCA layout
+    Sammon,
+    ClassicalMds,
+}
+
+impl ProjectionMethod {
+    pub const ALL: [ProjectionMethod; 3] = [ProjectionMethod::Pca, ProjectionMethod::Sammon, ProjectionMethod::ClassicalMds];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProjectionMethod::Pca => "PCA",
+            ProjectionMethod::Sammon => "Sammon mapping",
+            ProjectionMethod::ClassicalMds => "Classical MDS",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Projection {
+    pub method: ProjectionMethod,
+    pub dimensions: usize,
+    // One row per neuron, in (i, j) order
+    pub points: Array2<f32>,
+    prototypes: Array2<f32>,
+    mean: Array1<f32>,
+    // Principal axes as rows
+    components: Array2<f32>,
+}
+
+impl Projection {
+    pub fn new(map: &MSOM, method: ProjectionMethod, dimensions: usize) -> Self {
+        let (n, m, dim) = map.som().dim();
+        let prototypes = map.som().as_standard_layout().into_owned().into_shape((n * m, dim)).unwrap();
+        let mean = prototypes.mean_axis(Axis(0)).unwrap();
+        let centered = &prototypes - &mean;
+        let covariance = centered.t().dot(&centered) / (n * m) as f32;
+        let (components, _) = top_eigenvectors(&covariance, dimensions);
+        let pca = centered.dot(&components.t());
+
+        let points = match method {
+            ProjectionMethod::Pca => pca,
+            ProjectionMethod::Sammon => sammon(&distances(&prototypes), pca),
+            ProjectionMethod::ClassicalMds => classical_mds(&distances(&prototypes), dimensions),
+        };
+
+        Self { method, dimensions, points, prototypes, mean, components }
+    }
+
+    // Exact for PCA. The other methods have no mapping for new points, there an input goes between its
+    // nearest prototypes, weighted by inverse distance
+    pub fn project(&self, input: ArrayView1<f32>) -> Array1<f32> {
+        if self.method == ProjectionMethod::Pca {
+            return (&input - &self.mean).dot(&self.components.t());
+        }
+
+        let mut nearest: Vec<(usize, f32)> = self.prototypes.rows().into_iter()
+            .map(|prototype| {
+                let diff = &prototype - &input;
+                diff.dot(&diff).sqrt()
+            })
+            .enumerate()
+            .collect();
+        nearest.sort_by(|a, b| a.1.total_cmp(&b.1));
+        nearest.truncate(INPUT_NEIGHBOURS);
+
+        if let Some((index, _)) = nearest.iter().find(|(_, distance)| *distance <= f32::EPSILON) {
+            return self.points.row(*index).to_owned();
+        }
+        let total: f32 = nearest.iter().map(|(_, distance)| 1.0 / distance).sum();
+        nearest.iter()
+            .fold(Array1::zeros(self.dimensions), |sum, (index, distance)| sum + &self.points.row(*index) * (1.0 / distance / total))
+    }
+}
+
+fn distances(points: &Array2<f32>) -> Array2<f32> {
+    let count = points.nrows();
+    Array2::from_shape_fn((count, count), |(a, b)| {
+        let diff = &points.row(a) - &points.row(b);
+        diff.dot(&diff).sqrt()
+    })
+}
+
+// Largest `count` eigenvectors of a symmetric positive semi-definite matrix as rows, and their eigenvalues.
+// Power iteration kept orthogonal to the vectors already found, those a too small matrix doesn't have stay 0
+fn top_eigenvectors(matrix: &Array2<f32>, count: usize) -> (Array2<f32>, Vec<f32>) {
+    let size = matrix.nrows();
+    let mut vectors = Array2::zeros((count, size));
+    let mut values = vec![0.0; count];
+
+    // What's left once the real eigenvectors are used up is rounding noise, the trace gives its scale
+    let tolerance = 1e-5 * matrix.diag().sum().max(f32::EPSILON);
+    for (k, value) in values.iter_mut().enumerate().take(size) {
+        // Uneven, so that it's unlikely to start orthogonal to the eigenvector
+        let mut vector = Array1::from_shape_fn(size, |index| 1.0 + ((index + k) % 7) as f32 / 7.0);
+        for _ in 0..POWER_ITERATIONS {
+            let mut next = matrix.dot(&vector);
+            for previous in vectors.rows().into_iter().take(k) {
+                next = &next - &(&previous * previous.dot(&next));
+            }
+
+            let norm = next.dot(&next).sqrt();
+            if norm <= tolerance {
+                vector.fill(0.0);
+                break;
+            }
+            vector = next / norm;
+        }
+
+        // Same orientation from run to run, the largest component positive
+        let largest = vector.iter().copied().fold(0.0_f32, |largest, value| if value.abs() > largest.abs() { value } else { largest });
+        if largest < 0.0 {
+            vector.mapv_inplace(|value| -value);
+        }
+        *value = vector.dot(&matrix.dot(&vector));
+        vectors.row_mut(k).assign(&vector);
+    }
+
+    (vectors, values)
+}
+
+// Coordinates from the top eigenvectors of the double centered squared distances
+fn classical_mds(distances: &Array2<f32>, dimensions: usize) -> Array2<f32> {
+    let squared = distances.mapv(|distance| distance * distance);
+    let row_means = squared.mean_axis(Axis(1)).unwrap();
+    let column_means = squared.mean_axis(Axis(0)).unwrap();
+    let grand_mean = row_means.mean().unwrap_or(0.0);
+    let centered = Array2::from_shape_fn(squared.dim(), |(a, b)| {
+        -0.5 * (squared[(a, b)] - row_means[a] - column_means[b] + grand_mean)
+    });
+
+    let (vectors, values) = top_eigenvectors(&centered, dimensions);
+    let mut points = vectors.reversed_axes();
+    for (mut column, value) in points.columns_mut().into_iter().zip(values) {
+        column *= value.max(0.0).sqrt();
+    }
+    points
+}
+
+// Sammon's pseudo-Newton descent on his stress, which divides every error by the original distance so that
+// neighbourhoods are kept better than far apart pairs. Pairs of identical prototypes are left out.
+fn sammon(distances: &Array2<f32>, mut points: Array2<f32>) -> Array2<f32> {
+    let (count, dimensions) = points.dim();
+    for _ in 0..SAMMON_ITERATIONS {
+        let projected = self::distances(&points);
+        let mut step = Array2::zeros((count, dimensions));
+
+        for p in 0..count {
+            for q in 0..dimensions {
+                let (mut gradient, mut curvature) = (0.0, 0.0);
+                for j in (0..count).filter(|j| *j != p && distances[(p, *j)] > f32::EPSILON) {
+                    let (original, current) = (distances[(p, j)], projected[(p, j)].max(f32::EPSILON));
+                    let diff = points[(p, q)] - points[(j, q)];
+                    let error = original - current;
+                    gradient += error / (original * current) * diff;
+                    curvature += (error - diff * diff / current * (1.0 + error / current)) / (original * current);
+                }
+
+                if curvature.abs() > f32::EPSILON {
+                    step[(p, q)] = SAMMON_STEP * gradient / curvature.abs();
+                }
+            }
+        }
+
+        points += &step;
+    }
+
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{arr1, Array3};
+
+    use super::*;
+
+    // Prototypes on the line y = 2x at uneven spacing
+    fn collinear_map() -> MSOM {
+        let som = Array3::from_shape_vec((1, 4, 2), vec![0.0, 0.0, 1.0, 2.0, 3.0, 6.0, 6.0, 12.0]).unwrap();
+        MSOM::from_weights(1.0, 1.0, 0.5, som, Array3::zeros((1, 4, 2))).unwrap()
+    }
+
+    #[test]
+    fn collinear_distances_are_kept() {
+        let map = collinear_map();
+        let original = distances(&map.som().clone().into_shape((4, 2)).unwrap());
+
+        for method in ProjectionMethod::ALL {
+            for dimensions in [2, 3] {
+                let projection = Projection::new(&map, method, dimensions);
+                assert_eq!(projection.points.dim(), (4, dimensions));
+
+                let projected = distances(&projection.points);
+                for (a, b) in original.iter().zip(projected.iter()) {
+                    assert!((a - b).abs() < 1e-3, "{} in {dimensions}D: {original} vs {projected}", method.name());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pca_projects_prototypes_onto_their_points() {
+        let map = collinear_map();
+        let projection = Projection::new(&map, ProjectionMethod::Pca, 2);
+
+        let projected = projection.project(arr1(&[3.0, 6.0]).view());
+        assert!((&projected - &projection.points.row(2)).iter().all(|diff| diff.abs() < 1e-4));
+        // The line is the first axis
+        assert!(projection.points.column(1).iter().all(|value| value.abs() < 1e-4));
+    }
+}
@@ -0,0 +1,50 @@
+use std::{sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::msom::MSOM;
+
+// What a result was computed from: the objects by their IDs, with hashes of their contents at the time, so
+// that it can tell when they have changed since
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Creation time and a counter, unique within a session and in practice across sessions too
+pub fn new_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    format!("{nanos:x}-{:x}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+// FNV-1a over the bit patterns, unlike std's hasher it is guaranteed to stay the same between Rust versions
+pub fn content_hash(values: impl IntoIterator<Item = f32>) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for value in values {
+        for byte in value.to_bits().to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+pub fn map_hash(map: &MSOM) -> String {
+    content_hash([map.a, map.b, map.gamma].into_iter().chain(map.som().iter().copied()).chain(map.context().iter().copied()))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceRef {
+    pub id: String,
+    // As it was called back then, for when it is no longer loaded
+    pub name: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub map: SourceRef,
+    pub dataset: SourceRef,
+    pub comparison_dataset: Option<SourceRef>,
+    // Summary of the map's shape and training parameters
+    pub parameters: String,
+    pub computed_at: String,
+}
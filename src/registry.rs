@@ -0,0 +1,103 @@
+use std::{collections::HashSet, sync::{Arc, Mutex}};
+
+use crate::{data_processing::DataSet, maps::SOMParams, provenance::new_id, visualizations::Visualization};
+
+// Datasets, maps and visualizations, shared by all panes. Panes hold on to IDs rather than places in the
+// lists, so that removing an object never leaves them pointing at another one.
+// The IDs of datasets and visualizations are kept next to them, looking one up doesn't lock all the others
+// (and wait for the ones being processed)
+#[derive(Debug, Default)]
+pub struct Registry {
+    pub datasets: Vec<(String, Arc<Mutex<DataSet>>)>,
+    pub maps: Vec<SOMParams>,
+    pub visualizations: Vec<(String, Arc<Mutex<Visualization>>)>,
+}
+
+impl Registry {
+    pub fn new(datasets: Vec<Arc<Mutex<DataSet>>>, maps: Vec<SOMParams>, visualizations: Vec<Arc<Mutex<Visualization>>>) -> Self {
+        let mut registry = Self::default();
+        for dataset in datasets {
+            registry.insert_dataset(dataset);
+        }
+        for map in maps {
+            registry.add_map(map);
+        }
+        for visualization in visualizations {
+            registry.insert_visualization(visualization);
+        }
+        registry
+    }
+
+    pub fn dataset(&self, id: &str) -> Option<&Arc<Mutex<DataSet>>> {
+        find_dataset(&self.datasets, id)
+    }
+
+    pub fn map(&self, id: &str) -> Option<&SOMParams> {
+        find_map(&self.maps, id)
+    }
+
+    pub fn visualization(&self, id: &str) -> Option<&Arc<Mutex<Visualization>>> {
+        self.visualizations.iter().find(|(visualization_id, _)| visualization_id == id).map(|(_, visualization)| visualization)
+    }
+
+    // The same file loaded twice would share an ID, the copy gets a new one. Each returns the ID it was added under
+    pub fn add_dataset(&mut self, dataset: DataSet) -> String {
+        self.insert_dataset(Arc::new(Mutex::new(dataset)))
+    }
+
+    pub fn add_map(&mut self, mut map: SOMParams) -> String {
+        if self.map(&map.id).is_some() {
+            map.id = new_id();
+        }
+        self.maps.push(map);
+        self.maps.last().unwrap().id.clone()
+    }
+
+    pub fn add_visualization(&mut self, visualization: Visualization) -> String {
+        self.insert_visualization(Arc::new(Mutex::new(visualization)))
+    }
+
+    // IDs never change once added, so the ones kept in the lists stay those of the objects
+    fn insert_dataset(&mut self, dataset: Arc<Mutex<DataSet>>) -> String {
+        let ids: HashSet<&str> = self.datasets.iter().map(|(id, _)| id.as_str()).collect();
+        let id = unique_id(&mut dataset.lock().unwrap().id, &ids);
+        self.datasets.push((id.clone(), dataset));
+        id
+    }
+
+    pub fn insert_visualization(&mut self, visualization: Arc<Mutex<Visualization>>) -> String {
+        let ids: HashSet<&str> = self.visualizations.iter().map(|(id, _)| id.as_str()).collect();
+        let id = unique_id(&mut visualization.lock().unwrap().id, &ids);
+        self.visualizations.push((id.clone(), visualization));
+        id
+    }
+
+    // Visualizations made from a removed map or dataset stay, they show as stale
+    pub fn remove_dataset(&mut self, id: &str) {
+        self.datasets.retain(|(dataset_id, _)| dataset_id != id);
+    }
+
+    pub fn remove_map(&mut self, id: &str) {
+        self.maps.retain(|map| map.id != id);
+    }
+
+    pub fn remove_visualization(&mut self, id: &str) {
+        self.visualizations.retain(|(visualization_id, _)| visualization_id != id);
+    }
+}
+
+fn unique_id(id: &mut String, taken: &HashSet<&str>) -> String {
+    if taken.contains(id.as_str()) {
+        *id = new_id();
+    }
+    id.clone()
+}
+
+// Lookups for panes that only get the lists
+pub fn find_dataset<'a>(datasets: &'a [(String, Arc<Mutex<DataSet>>)], id: &str) -> Option<&'a Arc<Mutex<DataSet>>> {
+    datasets.iter().find(|(dataset_id, _)| dataset_id == id).map(|(_, dataset)| dataset)
+}
+
+pub fn find_map<'a>(maps: &'a [SOMParams], id: &str) -> Option<&'a SOMParams> {
+    maps.iter().find(|map| map.id == id)
+}
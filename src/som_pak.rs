@@ -0,0 +1,307 @@
+use std::{fs, path::Path};
+
+use ndarray::{Array1, Array3};
+
+use crate::{data_processing::DataSet, msom::{Topology, MSOM}};
+
+// Readers and writers for the plain text formats of SOM_PAK (and the SOM Toolbox, which reads the same files).
+// A codebook (.cod) starts with `dim topology xdim ydim neighbourhood` followed by one prototype per line,
+// x running fastest. Neuron (i, j) of our maps is x = i, y = j there.
+// Data files (.dat) start with `dim` followed by one vector per line, `x` marks a missing value and anything
+// after the values is a label.
+
+// SOM_PAK skips comment lines, so the MSOM parameters and the context columns can ride along
+const MSOM_COMMENT: &str = "#msom";
+
+pub fn write_cod(map: &MSOM, with_context: bool, path: &Path) -> Result<(), String> {
+    let dim = if with_context { 2 * map.map_input_size } else { map.map_input_size };
+
+    let mut contents = format!("{dim} {} {} {} gaussian\n", map.topology.name(), map.n, map.m);
+    let context_size = if with_context { map.map_input_size } else { 0 };
+    contents += &format!("{MSOM_COMMENT} a={} b={} gamma={} context={context_size}\n", map.a, map.b, map.gamma);
+
+    for j in 0..map.m {
+        for i in 0..map.n {
+            let mut values: Vec<String> = map.som().slice(ndarray::s![i, j, ..]).iter().map(|value| value.to_string()).collect();
+            if with_context {
+                values.extend(map.context().slice(ndarray::s![i, j, ..]).iter().map(|value| value.to_string()));
+            }
+            contents += &values.join(" ");
+            contents += "\n";
+        }
+    }
+
+    fs::write(path, contents).map_err(|err| format!("Error while writing {}: {err}", path.display()))
+}
+
+pub fn read_cod(path: &Path) -> Result<MSOM, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Error while reading {}: {err}", path.display()))?;
+    let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+
+    let (_, header) = lines.next().ok_or("The codebook is empty")?;
+    let header: Vec<&str> = header.split_whitespace().collect();
+    let [dim, topology, xdim, ydim, ..] = header[..] else {
+        return Err(format!("Expected `dim topology xdim ydim` in the header, found `{}`", header.join(" ")));
+    };
+    let dim = parse_size(dim, "dim")?;
+    let n = parse_size(xdim, "xdim")?;
+    let m = parse_size(ydim, "ydim")?;
+    let topology = match topology {
+        "rect" => Topology::Rectangular,
+        "hexa" => Topology::Hexagonal,
+        other => return Err(format!("Unknown topology `{other}`, expected rect or hexa")),
+    };
+
+    // Our comment may come anywhere, also before the header
+    let mut params = [1.0, 1.0, 0.5];
+    let mut context_size = 0;
+    for (line_i, line) in contents.lines().enumerate() {
+        let Some(comment) = line.strip_prefix(MSOM_COMMENT) else {
+            continue;
+        };
+        for (key, value) in comment.split_whitespace().filter_map(|pair| pair.split_once('=')) {
+            let parsed = |value: &str| value.parse::<f32>().map_err(|err| format!("Line {}: `{key}`: {err}", line_i + 1));
+            match key {
+                "a" => params[0] = parsed(value)?,
+                "b" => params[1] = parsed(value)?,
+                "gamma" => params[2] = parsed(value)?,
+                "context" => context_size = parsed(value)? as usize,
+                _ => {}
+            }
+        }
+    }
+
+    let mut prototypes = vec![];
+    for (line_i, line) in lines {
+        let (values, _) = parse_values(line, dim).map_err(|err| format!("Line {}: {err}", line_i + 1))?;
+        let values: Option<Vec<f32>> = values.into_iter().collect();
+        prototypes.push(values.ok_or(format!("Line {}: a codebook can't have missing values", line_i + 1))?);
+    }
+
+    if prototypes.len() != n * m {
+        return Err(format!("Expected {} prototypes for a {n}x{m} map, found {}", n * m, prototypes.len()));
+    }
+    if context_size * 2 != dim && context_size != 0 {
+        return Err(format!("{context_size} context columns don't fit a dimension of {dim}"));
+    }
+
+    let map_input_size = dim - context_size;
+    let som = Array3::from_shape_fn((n, m, map_input_size), |(i, j, k)| prototypes[j * n + i][k]);
+    let context = Array3::from_shape_fn((n, m, map_input_size), |(i, j, k)| {
+        if context_size == 0 { 0.0 } else { prototypes[j * n + i][map_input_size + k] }
+    });
+
+    let [a, b, gamma] = params;
+    let mut map = MSOM::from_weights(a, b, gamma, som, context)?;
+    map.topology = topology;
+    Ok(map)
+}
+
+// Samples of different lengths are padded with missing values up to the longest one
+pub fn write_dat(dataset: &DataSet, path: &Path) -> Result<(), String> {
+    let samples = dataset.processed_data.as_ref().ok_or("The dataset is not processed")?;
+    let dim = samples.iter().map(|sample| sample.len()).max().unwrap_or(0);
+
+    let mut contents = format!("{dim}\n");
+    for (index, sample) in samples.iter().enumerate() {
+        let mut values: Vec<String> = sample.iter().map(|value| value.to_string()).collect();
+        values.resize(dim, "x".to_owned());
+        if let Some(label) = dataset.labels.as_ref().and_then(|labels| labels.get(index)) {
+            values.push(label.clone());
+        }
+        contents += &values.join(" ");
+        contents += "\n";
+    }
+
+    fs::write(path, contents).map_err(|err| format!("Error while writing {}: {err}", path.display()))
+}
+
+// Only trailing missing values are allowed, those end the sample, a sequence can't have holes
+pub fn read_dat(path: &Path) -> Result<DataSet, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Error while reading {}: {err}", path.display()))?;
+    let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+
+    let (_, header) = lines.next().ok_or("The data file is empty")?;
+    let dim = parse_size(header.split_whitespace().next().unwrap(), "dim")?;
+
+    let mut samples = vec![];
+    let mut labels = vec![];
+    for (line_i, line) in lines {
+        let (values, label) = parse_values(line, dim).map_err(|err| format!("Line {}: {err}", line_i + 1))?;
+        let len = values.iter().rposition(|value| value.is_some()).map_or(0, |last| last + 1);
+        let sample: Option<Vec<f32>> = values[..len].iter().copied().collect();
+
+        samples.push(Array1::from(sample.ok_or(format!("Line {}: missing values are only allowed at the end", line_i + 1))?));
+        labels.push(label);
+    }
+
+    let name = path.file_name().map_or("SOM_PAK data".to_owned(), |name| name.to_string_lossy().into_owned());
+    let raw_data = labels.iter().enumerate()
+        .map(|(index, label)| label.clone().unwrap_or_else(|| format!("Sample {index}")))
+        .collect();
+    let labels = if labels.iter().any(|label| label.is_some()) {
+        Some(labels.into_iter().map(|label| label.unwrap_or_default()).collect())
+    } else {
+        None
+    };
+
+    Ok(DataSet::from_samples(name, raw_data, samples, labels))
+}
+
+fn parse_size(value: &str, name: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(format!("Invalid {name} `{value}`, expected a positive integer")),
+        Ok(size) => Ok(size),
+    }
+}
+
+// The first `dim` tokens are values, the rest of the line is the label
+fn parse_values(line: &str, dim: usize) -> Result<(Vec<Option<f32>>, Option<String>), String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.len() < dim {
+        return Err(format!("Expected {dim} values, found {}", tokens.len()));
+    }
+
+    let values = tokens[..dim].iter()
+        .map(|token| match *token {
+            "x" => Ok(None),
+            token => token.parse().map(Some).map_err(|_| format!("`{token}` is not a number")),
+        })
+        .collect::<Result<_, _>>()?;
+    let label = if tokens.len() > dim { Some(tokens[dim..].join(" ")) } else { None };
+
+    Ok((values, label))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("msom_som_pak_{}_{name}", std::process::id()))
+    }
+
+    fn test_map(topology: Topology) -> MSOM {
+        // n != m, so that mixing up x and y shows
+        let som = Array3::from_shape_fn((3, 2, 2), |(i, j, k)| (i * 100 + j * 10 + k) as f32 + 0.25);
+        let context = Array3::from_shape_fn((3, 2, 2), |(i, j, k)| -((i * 100 + j * 10 + k) as f32) - 0.5);
+        let mut map = MSOM::from_weights(0.75, 0.5, 0.25, som, context).unwrap();
+        map.topology = topology;
+        map
+    }
+
+    fn read_cod_str(name: &str, contents: &str) -> Result<MSOM, String> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let res = read_cod(&path);
+        fs::remove_file(&path).unwrap();
+        res
+    }
+
+    fn read_dat_str(name: &str, contents: &str) -> Result<DataSet, String> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let res = read_dat(&path);
+        fs::remove_file(&path).unwrap();
+        res
+    }
+
+    #[test]
+    fn cod_round_trip() {
+        for topology in [Topology::Rectangular, Topology::Hexagonal] {
+            for with_context in [false, true] {
+                let map = test_map(topology);
+                let path = temp_path(&format!("{}_{with_context}.cod", topology.name()));
+                write_cod(&map, with_context, &path).unwrap();
+                let read = read_cod(&path).unwrap();
+                fs::remove_file(&path).unwrap();
+
+                assert_eq!(read.topology, topology);
+                assert_eq!((read.n, read.m, read.map_input_size), (3, 2, 2));
+                assert_eq!((read.a, read.b, read.gamma), (0.75, 0.5, 0.25));
+                assert_eq!(read.som(), map.som());
+                if with_context {
+                    assert_eq!(read.context(), map.context());
+                } else {
+                    assert!(read.context().iter().all(|value| *value == 0.0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cod_without_msom_comment() {
+        // As written by SOM_PAK itself, x runs fastest
+        let map = read_cod_str("plain.cod", "2 hexa 2 1 gaussian\n1 2\n3 4\n").unwrap();
+        assert_eq!(map.topology, Topology::Hexagonal);
+        assert_eq!(map.som().slice(ndarray::s![1, 0, ..]).to_vec(), vec![3.0, 4.0]);
+    }
+
+    #[test]
+    fn cod_comments_before_header() {
+        let map = read_cod_str("comments.cod", "# from SOM_PAK\n#msom a=2 b=3 gamma=0.125 context=1\n\n2 rect 1 2 gaussian\n# prototypes\n1 2\n3 4\n").unwrap();
+        assert_eq!(map.topology, Topology::Rectangular);
+        assert_eq!((map.a, map.b, map.gamma), (2.0, 3.0, 0.125));
+        assert_eq!(map.som().slice(ndarray::s![0, 1, ..]).to_vec(), vec![3.0]);
+        assert_eq!(map.context().slice(ndarray::s![0, 1, ..]).to_vec(), vec![4.0]);
+    }
+
+    #[test]
+    fn cod_errors() {
+        let err = read_cod_str("count.cod", "2 rect 2 2 gaussian\n1 2\n3 4\n5 6\n").unwrap_err();
+        assert!(err.contains("Expected 4 prototypes"), "{err}");
+
+        let err = read_cod_str("context.cod", "3 rect 1 1 gaussian\n#msom a=1 b=1 gamma=0.5 context=2\n1 2 3\n").unwrap_err();
+        assert!(err.contains("context columns don't fit"), "{err}");
+
+        let err = read_cod_str("missing.cod", "2 rect 1 1 gaussian\n1 x\n").unwrap_err();
+        assert!(err.contains("missing values"), "{err}");
+
+        let err = read_cod_str("topology.cod", "2 cube 1 1 gaussian\n1 2\n").unwrap_err();
+        assert!(err.contains("Unknown topology"), "{err}");
+
+        let err = read_cod_str("short.cod", "3 rect 1 1 gaussian\n1 2\n").unwrap_err();
+        assert!(err.contains("Line 2: Expected 3 values"), "{err}");
+    }
+
+    #[test]
+    fn dat_trailing_missing_values_and_labels() {
+        let dataset = read_dat_str("labels.dat", "3\n# comment\n1 2 3 first label\n4 5 x second\n6 x x\n").unwrap();
+        let samples = dataset.processed_data.as_ref().unwrap().views();
+        let samples: Vec<Vec<f32>> = samples.iter().map(|sample| sample.to_vec()).collect();
+        assert_eq!(samples, vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0], vec![6.0]]);
+        assert_eq!(dataset.labels.as_deref(), Some(&vec!["first label".to_owned(), "second".to_owned(), "".to_owned()]));
+        assert_eq!(*dataset.raw_data, vec!["first label".to_owned(), "second".to_owned(), "Sample 2".to_owned()]);
+
+        let dataset = read_dat_str("unlabelled.dat", "2\n1 2\n3 4\n").unwrap();
+        assert!(dataset.labels.is_none());
+    }
+
+    #[test]
+    fn dat_round_trip() {
+        let dataset = read_dat_str("source.dat", "3\n1 2 3 a\n4 5 x b\n").unwrap();
+        let path = temp_path("written.dat");
+        write_dat(&dataset, &path).unwrap();
+        let read = read_dat(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.processed_data, dataset.processed_data);
+        assert_eq!(read.labels, dataset.labels);
+    }
+
+    #[test]
+    fn dat_errors() {
+        let err = read_dat_str("hole.dat", "3\n1 x 3\n").unwrap_err();
+        assert!(err.contains("Line 2: missing values are only allowed at the end"), "{err}");
+
+        let err = read_dat_str("number.dat", "2\n1 y\n").unwrap_err();
+        assert!(err.contains("`y` is not a number"), "{err}");
+
+        let err = read_dat_str("dim.dat", "0\n").unwrap_err();
+        assert!(err.contains("Invalid dim"), "{err}");
+    }
+}
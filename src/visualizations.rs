@@ -1,124 +1,1430 @@
-use egui::{epaint::RectShape, include_image, Color32, ComboBox, DragValue, Frame, Grid, Image, Label, Layout, Pos2, Rect, Rounding, ScrollArea, Sense, Shape, SidePanel, Stroke, Style, Ui, Vec2};
+use egui::{include_image, Align2, Color32, ComboBox, DragValue, FontId, Frame, Grid, Image, Label, Layout, Pos2, Rect, RichText, Rounding, ScrollArea, Sense, Shape, SidePanel, Slider, Stroke, Style, TextEdit, Ui, Vec2};
 use egui_modal::Modal;
 use ndarray::{Array2, ArrayView1};
+use regex::{Regex, RegexBuilder};
 use rfd::FileDialog;
 use tqdm::tqdm;
 
-use crate::{data_processing::DataSet, msom::{get_vec_std, MSOM}, SOMParams};
-use std::{cmp::{max, min}, fs::File, path::PathBuf, sync::{Arc, Mutex}};
+use crate::{data_processing::{embeddings, embeddings_loaded, DataSet}, file_format::{self, remove_field, Versioned}, msom::{anomaly::AnomalyCalibration, count_transitions, receptive_fields_of, u_matrix_basins, Topology, Transition, MSOM}, provenance::{map_hash, new_id, Provenance, SourceRef}, registry::{find_dataset, find_map, Registry}, SOMParams};
+use std::{cmp::{max, min}, collections::{BTreeSet, HashMap}, path::Path, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use color_scale::{text_color, ColorMap, ColorScale, FittedScale, Scaling};
+use canvas::MapCanvas;
+use playback::PlaybackUI;
+use projection::ProjectionUI;
+use render::{Annotation, RenderOptions};
+use terms::TermIndex;
+
+pub mod canvas;
+pub mod color_scale;
+pub mod playback;
+pub mod projection;
+pub mod render;
+pub mod terms;
 
 const TEXT_PREVIEW_CUTOFF: usize = 20;
+const TOOLTIP_TEXTS: usize = 3;
+const NEAREST_WORDS: usize = 8;
+const TOP_TERMS: usize = 12;
+const MIN_LABEL_SIZE: f32 = 6.0;
+const MAX_LABEL_SIZE: f32 = 14.0;
+const MAX_LABEL_LINES: usize = 3;
+const OVER_REPRESENTED_CELLS: usize = 10;
+const RECEPTIVE_FIELD_WORDS: usize = 3;
+const MIN_SPARKLINE_CELL: f32 = 16.0;
+
+type NearestWords = Result<Vec<(String, f32)>, String>;
+type CellValues = Vec<((usize, usize), f32)>;
+type StepWords = Result<Vec<Vec<String>>, String>;
+
+static NEXT_REVISION: AtomicU64 = AtomicU64::new(0);
+
+// Unique across all visualizations, so a revision alone tells whether cached drawings are outdated
+fn next_revision() -> u64 {
+    NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
+}
 
 pub fn calculate_visualization_data(visualization: Arc<Mutex<Visualization>>, map: MSOM, dataset: DataSet) {
     if let Some(samples) = dataset.processed_data {
         // println!("{}, {}", samples.len(), dataset.raw_data.len());
+        // Only what the kind shows is computed and saved. Quantization errors are in the tooltips and region
+        // stats of every kind, so they are always there
+        let kind = visualization.lock().unwrap().kind;
+        let with_context_errors = kind == VisualizationKind::ContextError;
+        let with_label_purity = kind == VisualizationKind::LabelPurity && dataset.labels.is_some();
         
         let mut word_occurences: Vec<Vec<Vec<String>>> =
             vec![vec![vec![]; map.m]; map.n];
+        let mut anomaly_scores: Vec<Vec<Vec<f32>>> =
+            vec![vec![vec![]; map.m]; map.n];
 
         let mut vector_occurences: Vec<Vec<Vec<ArrayView1<f32>>>> =
                 vec![vec![vec![]; map.m]; map.n];
 
+        let mut error_sums: Vec<Vec<f32>> = vec![vec![0.0; map.m]; map.n];
+        let mut context_error_sums: Vec<Vec<f32>> = vec![vec![0.0; map.m]; map.n];
+        let mut label_counts: Vec<Vec<HashMap<&str, usize>>> = vec![vec![HashMap::new(); map.m]; map.n];
+
+        let mut trajectories = vec![];
         for (index, sample) in tqdm(samples.iter().enumerate()) {
-            let prediction = map.evaluate(sample.view());
-            vector_occurences[prediction.0][prediction.1].push(sample.view());
+            let trajectory = map.evaluate_trajectory(sample.view());
+            // Empty samples have no BMU, they stay out of every cell
+            let Some(prediction) = trajectory.last().map(|step| step.0) else {
+                continue;
+            };
+            error_sums[prediction.0][prediction.1] += map.quantization_error(sample.view(), &trajectory);
+            if with_context_errors {
+                context_error_sums[prediction.0][prediction.1] += map.context_error(&trajectory);
+            }
+            if let Some(label) = dataset.labels.as_ref().and_then(|labels| labels.get(index)).filter(|_| with_label_purity) {
+                *label_counts[prediction.0][prediction.1].entry(label.as_str()).or_default() += 1;
+            }
+            anomaly_scores[prediction.0][prediction.1].push(map.anomaly_score_of(&trajectory));
+            trajectories.push(trajectory);
+            vector_occurences[prediction.0][prediction.1].push(sample);
             
             word_occurences[prediction.0][prediction.1].push(dataset.raw_data[index].replace("\n", " "));
 
             // println!("{index}");
         }
 
+        let term_index = TermIndex::new(&word_occurences);
+        visualization.lock().unwrap().top_terms = term_index.top_terms_per_cell(TOP_TERMS);
+        visualization.lock().unwrap().term_index = Some(Arc::new(term_index));
         visualization.lock().unwrap().word_clusters = word_occurences;
+        visualization.lock().unwrap().transitions = count_transitions(&trajectories);
+        visualization.lock().unwrap().anomaly_scores = anomaly_scores;
 
         let mut counts: Vec<Vec<f32>> = vec![vec![0.0; map.m]; map.n];
         for row_i in 0..map.n {
             for col_i in 0..map.m {
                 counts[row_i][col_i] += vector_occurences[row_i][col_i].len() as f32;
+                if counts[row_i][col_i] != 0.0 {
+                    error_sums[row_i][col_i] /= counts[row_i][col_i];
+                    context_error_sums[row_i][col_i] /= counts[row_i][col_i];
+                }
             }
             // println!("{row_i}");
         }
         
+        let receptive_fields = receptive_fields_of(&vector_occurences);
+        visualization.lock().unwrap().receptive_fields = receptive_fields.into_iter()
+            .map(|column| column.into_iter().map(|field| field.to_vec()).collect())
+            .collect();
+        visualization.lock().unwrap().chunk_size = map.map_input_size;
+        visualization.lock().unwrap().quantization_errors = error_sums;
+        if with_context_errors {
+            visualization.lock().unwrap().context_errors = context_error_sums;
+        }
+        if kind == VisualizationKind::UMatrix {
+            visualization.lock().unwrap().u_matrix = map.u_matrix().rows().into_iter().map(|row| row.to_vec()).collect();
+        }
+        if with_label_purity {
+            // Most common label of each cell and the share of the cell's samples that have it
+            visualization.lock().unwrap().label_purity = label_counts.iter()
+                .map(|column| column.iter()
+                    .map(|counts| {
+                        let total: usize = counts.values().sum();
+                        counts.iter()
+                            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+                            .map(|(label, count)| (label.to_string(), *count as f32 / total as f32))
+                    })
+                    .collect())
+                .collect();
+        }
         visualization.lock().unwrap().data = counts;
+        visualization.lock().unwrap().revision = next_revision();
     }
 
 
 }
 
+fn is_inspectable(map: &SOMParams, n: usize, m: usize) -> bool {
+    map.n == n && map.m == m && map.map_weights.is_some()
+}
+
+// Computes everything in the background from copies of the map and the datasets, recording what they were
+pub fn start_calculation(visualization: Arc<Mutex<Visualization>>, map_params: &SOMParams, dataset: &Arc<Mutex<DataSet>>,
+    comparison_dataset: Option<&Arc<Mutex<DataSet>>>) {
+    let map = map_params.map_weights.as_ref().unwrap().lock().unwrap().clone();
+    let mut dataset = dataset.lock().unwrap().clone();
+    let mut comparison_dataset = comparison_dataset.map(|other_dataset| other_dataset.lock().unwrap().clone());
+    let (map_id, map_name) = (map_params.id.clone(), map_params.name.clone());
+    let parameters = format!("{}×{} {}, a = {}, b = {}, gamma = {}, {} iterations, learning rate {}, width² {}, time constant {}",
+        map_params.n, map_params.m, map_params.topology.name(), map_params.a, map_params.b, map_params.gamma,
+        map_params.train_iterations, map_params.learning_rate_base, map_params.gauss_width_squared_base, map_params.time_constant);
+
+    visualization.lock().unwrap().is_calculating = true;
+    // ToDo: Add progress tracking and maybe thread termination
+    std::thread::spawn(move || {
+        let source = |dataset: &mut DataSet| SourceRef {
+            id: dataset.id.clone(), name: dataset.name.clone(), hash: dataset.samples_hash().unwrap_or_default() };
+        let provenance = Provenance {
+            map: SourceRef { id: map_id, name: map_name, hash: map_hash(&map) },
+            dataset: source(&mut dataset),
+            comparison_dataset: comparison_dataset.as_mut().map(source),
+            parameters,
+            computed_at: chrono::Utc::now().to_rfc3339(),
+        };
+
+        let dataset_name = dataset.name.clone();
+        calculate_visualization_data(visualization.clone(), map.clone(), dataset);
+        if let Some(other_dataset) = comparison_dataset {
+            calculate_comparison_data(visualization.clone(), map, dataset_name, other_dataset);
+        }
+
+        let mut locked_visualization = visualization.lock().unwrap();
+        locked_visualization.provenance = Some(provenance);
+        locked_visualization.is_calculating = false;
+    });
+}
+
+// Again with the same sources, as they are now. Settings made by hand are kept, the rest is computed anew
+fn recompute(visualization: &Arc<Mutex<Visualization>>, maps: &[SOMParams], datasets: &[(String, Arc<Mutex<DataSet>>)]) -> Result<(), String> {
+    let provenance = visualization.lock().unwrap().provenance.clone().ok_or("The sources of the visualization are unknown")?;
+    let map = find_map(maps, &provenance.map.id)
+        .filter(|map| map.map_weights.is_some())
+        .ok_or(format!("Map \"{}\" is no longer loaded", provenance.map.name))?;
+    if *map.is_training.lock().unwrap() {
+        return Err(format!("Map \"{}\" is being trained", map.name));
+    }
+    let find_source = |source: &SourceRef| find_dataset(datasets, &source.id)
+        .filter(|dataset| dataset.lock().unwrap().is_processed())
+        .ok_or(format!("Dataset \"{}\" is no longer loaded or not processed", source.name));
+    let dataset = find_source(&provenance.dataset)?;
+    let comparison_dataset = provenance.comparison_dataset.as_ref().map(find_source).transpose()?;
+
+    {
+        let mut locked_visualization = visualization.lock().unwrap();
+        let previous = std::mem::take(&mut *locked_visualization);
+        *locked_visualization = Visualization { name: previous.name, kind: previous.kind, color_scale: previous.color_scale,
+            regions: previous.regions, provenance: previous.provenance, id: previous.id, ..Default::default() };
+    }
+    start_calculation(visualization.clone(), map, dataset, comparison_dataset);
+    Ok(())
+}
+
+// Hits of a second dataset on the same map, the texts and errors stay those of the first one
+pub fn calculate_comparison_data(visualization: Arc<Mutex<Visualization>>, map: MSOM, dataset_name: String, other_dataset: DataSet) {
+    if let Some(samples) = other_dataset.processed_data {
+        let mut other_hits = vec![vec![0.0; map.m]; map.n];
+        for sample in tqdm(samples.iter()) {
+            if let Some((i, j)) = map.evaluate(sample) {
+                other_hits[i][j] += 1.0;
+            }
+        }
+
+        let mut locked_visualization = visualization.lock().unwrap();
+        locked_visualization.comparison = Some(Comparison { dataset_name, other_dataset_name: other_dataset.name, other_hits });
+        locked_visualization.revision = next_revision();
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VisualizationKind {
+    // Samples per cell
+    #[default]
+    Hits,
+    // Hit densities of the dataset against the comparison dataset
+    DatasetDifference(DifferenceMeasure),
+    // Mean squared distance between the last chunk of the cell's samples and the cell's input weights
+    QuantizationError,
+    // Mean distance between the final BMU's context and the context it was fed
+    ContextError,
+    // Of the neurons, so every cell has a value, samples or not
+    UMatrix,
+    // Share of the cell's samples that have its most common label, needs a labelled dataset
+    LabelPurity,
+}
+
+impl VisualizationKind {
+    // Those that need nothing more than the dataset and the map
+    pub const SIMPLE: [VisualizationKind; 5] = [VisualizationKind::Hits, VisualizationKind::QuantizationError, 
+        VisualizationKind::ContextError, VisualizationKind::UMatrix, VisualizationKind::LabelPurity];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VisualizationKind::Hits => "Hits",
+            VisualizationKind::DatasetDifference(_) => "Dataset difference",
+            VisualizationKind::QuantizationError => "Quantization error",
+            VisualizationKind::ContextError => "Context error",
+            VisualizationKind::UMatrix => "U-matrix",
+            VisualizationKind::LabelPurity => "Label purity",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DifferenceMeasure {
+    // Share of the first dataset minus share of the second
+    DensityDifference,
+    // log of the ratio of the shares, with half a hit added to every cell so that empty cells stay finite
+    #[default]
+    LogRatio,
+}
+
+impl DifferenceMeasure {
+    pub const ALL: [DifferenceMeasure; 2] = [DifferenceMeasure::DensityDifference, DifferenceMeasure::LogRatio];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DifferenceMeasure::DensityDifference => "Density difference",
+            DifferenceMeasure::LogRatio => "Log ratio",
+        }
+    }
+
+    fn compare(&self, hits: f32, total: f32, other_hits: f32, other_total: f32, cells: f32) -> f32 {
+        match self {
+            DifferenceMeasure::DensityDifference => hits / total.max(1.0) - other_hits / other_total.max(1.0),
+            DifferenceMeasure::LogRatio => ((hits + 0.5) / (total + 0.5 * cells)).ln() - ((other_hits + 0.5) / (other_total + 0.5 * cells)).ln(),
+        }
+    }
+}
+
+// Hits of a second dataset evaluated on the same map
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    pub dataset_name: String,
+    pub other_dataset_name: String,
+    pub other_hits: Vec<Vec<f32>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Visualization {
+    // Stays the same through renames and saves
+    #[serde(default = "new_id")]
+    pub id: String,
     name: String,
     data: Vec<Vec<f32>>,
     word_clusters: Vec<Vec<Vec<String>>>,
 
+    #[serde(skip)]
     is_calculating: bool,
+    // Changes whenever `data` does
+    #[serde(skip, default = "next_revision")]
+    revision: u64,
+
+    // Same layout as word_clusters. Whether a score is anomalous is up to the map's calibration, see map_calibration
+    #[serde(default)]
+    anomaly_scores: Vec<Vec<Vec<f32>>>,
+    #[serde(default)]
+    transitions: Vec<Transition>,
+    #[serde(default)]
+    color_scale: ColorScale,
+    // Mean input-space error of the samples in each cell (see MSOM::quantization_error), 0 for empty cells
+    #[serde(default)]
+    quantization_errors: Vec<Vec<f32>>,
+    // TF-IDF summary of each cell's texts, best term first
+    #[serde(default)]
+    top_terms: Vec<Vec<Vec<(String, f32)>>>,
+    // What top_terms are made from, for merging the terms of selections. Redone on first use after loading
+    #[serde(skip)]
+    term_index: Option<Arc<TermIndex>>,
+    #[serde(default)]
+    regions: Vec<Region>,
+    #[serde(default)]
+    kind: VisualizationKind,
+    #[serde(default)]
+    comparison: Option<Comparison>,
+    // Mean input sequence of each cell's samples, `chunk_size` values per step
+    #[serde(default)]
+    receptive_fields: Vec<Vec<Vec<f32>>>,
+    // Same layout as quantization_errors
+    #[serde(default)]
+    context_errors: Vec<Vec<f32>>,
+    #[serde(default)]
+    u_matrix: Vec<Vec<f32>>,
+    // Most common label of each cell and its share, empty if the dataset had no labels
+    #[serde(default)]
+    label_purity: Vec<Vec<Option<(String, f32)>>>,
+    #[serde(default)]
+    chunk_size: usize,
+    // What it was computed from, None for visualizations saved before it was recorded
+    #[serde(default)]
+    provenance: Option<Provenance>,
+}
+
+// Named group of cells picked out by hand
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub name: String,
+    pub cells: Vec<(usize, usize)>,
+}
+
+// Nearest words of every step of a cell's receptive field, worked out in the background
+#[derive(Debug)]
+struct ReceptiveWords {
+    cell: (usize, usize),
+    map_id: String,
+    revision: u64,
+    words: Arc<Mutex<Option<StepWords>>>,
+}
+
+// Merged top terms of the selection, kept until the selection or the visualization changes
+#[derive(Debug)]
+struct SelectionTerms {
+    visualization_id: String,
+    revision: u64,
+    cells: Vec<(usize, usize)>,
+    terms: Vec<(String, f32)>,
 }
 
 impl Default for Visualization {
     fn default() -> Self {
-        Self { name: "Name".to_owned(), data: vec![], word_clusters: vec![], is_calculating: false }
+        Self { id: new_id(), name: "Name".to_owned(), data: vec![], word_clusters: vec![], is_calculating: false, revision: next_revision(),
+            anomaly_scores: vec![], transitions: vec![], color_scale: ColorScale::default(),
+            quantization_errors: vec![], top_terms: vec![], term_index: None, regions: vec![],
+            kind: VisualizationKind::Hits, comparison: None, receptive_fields: vec![], chunk_size: 0,
+            context_errors: vec![], u_matrix: vec![], label_purity: vec![], provenance: None }
     }
 }
 
 impl Visualization {
-    fn from_file(filename: &PathBuf) -> Result<Self, &str> {
-        let writer = File::options().read(true).open(filename);
-        if writer.is_err() {
-            return Err("Error while opening the file");
+    // What the cells show depending on the kind, None for cells without samples
+    pub fn cell_values(&self) -> Vec<Vec<Option<f32>>> {
+        match (self.kind, &self.comparison) {
+            (VisualizationKind::DatasetDifference(measure), Some(comparison)) => {
+                let total: f32 = self.data.iter().flatten().sum();
+                let other_total: f32 = comparison.other_hits.iter().flatten().sum();
+                let cells = self.data.iter().map(|column| column.len()).sum::<usize>() as f32;
+
+                self.data.iter().zip(&comparison.other_hits)
+                    .map(|(column, other_column)| column.iter().zip(other_column)
+                        .map(|(hits, other_hits)| (*hits != 0.0 || *other_hits != 0.0)
+                            .then(|| measure.compare(*hits, total, *other_hits, other_total, cells)))
+                        .collect())
+                    .collect()
+            }
+            (VisualizationKind::QuantizationError, _) => self.cells_with_samples(|i, j| self.quantization_errors.get(i)?.get(j).copied()),
+            (VisualizationKind::ContextError, _) => self.cells_with_samples(|i, j| self.context_errors.get(i)?.get(j).copied()),
+            (VisualizationKind::UMatrix, _) => self.data.iter().enumerate()
+                .map(|(i, column)| (0..column.len()).map(|j| self.u_matrix.get(i).and_then(|row| row.get(j)).copied()).collect())
+                .collect(),
+            (VisualizationKind::LabelPurity, _) => self.cells_with_samples(|i, j| self.majority_label(i, j).map(|(_, share)| share)),
+            _ => self.cells_with_samples(|i, j| Some(self.data[i][j])),
         }
+    }
 
-        let res: Result<Visualization, serde_json::Error> = serde_json::from_reader(writer.unwrap());
+    // Why it no longer matches the loaded map and datasets, empty if it does or if its sources are unknown
+    pub fn stale_reasons(&self, maps: &[SOMParams], datasets: &[(String, Arc<Mutex<DataSet>>)]) -> Vec<String> {
+        let Some(provenance) = &self.provenance else {
+            return vec![];
+        };
 
-        if res.is_err() {
-            Err("Error parsing the file")
+        let mut reasons = vec![];
+        match find_map(maps, &provenance.map.id) {
+            None => reasons.push(format!("Map \"{}\" is no longer loaded", provenance.map.name)),
+            Some(map) if *map.is_training.lock().unwrap() => reasons.push(format!("Map \"{}\" is being trained", map.name)),
+            Some(map) => if map.weights_hash().is_some_and(|hash| hash != provenance.map.hash) {
+                reasons.push(format!("Map \"{}\" has different weights now", map.name));
+            }
         }
-        else {
-            Ok(res.unwrap())
+
+        for source in std::iter::once(&provenance.dataset).chain(&provenance.comparison_dataset) {
+            match find_dataset(datasets, &source.id) {
+                None => reasons.push(format!("Dataset \"{}\" is no longer loaded", source.name)),
+                Some(dataset) => {
+                    let mut locked_dataset = dataset.lock().unwrap();
+                    if locked_dataset.samples_hash().as_ref() != Some(&source.hash) {
+                        reasons.push(format!("Dataset \"{}\" was processed differently since", locked_dataset.name));
+                    }
+                }
+            }
+        }
+        reasons
+    }
+
+    fn cells_with_samples(&self, value: impl Fn(usize, usize) -> Option<f32>) -> Vec<Vec<Option<f32>>> {
+        self.data.iter().enumerate()
+            .map(|(i, column)| column.iter().enumerate()
+                .map(|(j, hits)| if *hits != 0.0 { value(i, j) } else { None })
+                .collect())
+            .collect()
+    }
+
+    pub fn majority_label(&self, i: usize, j: usize) -> Option<(&str, f32)> {
+        self.label_purity.get(i)?.get(j)?.as_ref().map(|(label, share)| (label.as_str(), *share))
+    }
+
+    // Empty cells are left out, they are drawn grey whatever the scale. Differences get a range symmetric
+    // around zero, so that the middle of a diverging map means "no difference"
+    pub fn fitted_scale(&self) -> FittedScale {
+        let values: Vec<f32> = self.cell_values().into_iter().flatten().flatten().collect();
+        match self.kind {
+            VisualizationKind::DatasetDifference(_) if self.color_scale.fixed_range.is_none() => {
+                let bound = values.iter().fold(0.0_f32, |bound, value| bound.max(value.abs()));
+                ColorScale { fixed_range: Some((-bound, bound)), ..self.color_scale }.fit(&values)
+            }
+            _ => self.color_scale.fit(&values),
+        }
+    }
+
+    // Cells where each dataset is over-represented the most, strongest first
+    pub fn over_represented(&self, limit: usize) -> (CellValues, CellValues) {
+        let mut values: Vec<((usize, usize), f32)> = self.cell_values().iter().enumerate()
+            .flat_map(|(i, column)| column.iter().enumerate().filter_map(move |(j, value)| value.map(|value| ((i, j), value))))
+            .collect();
+        values.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let first = values.iter().copied().filter(|(_, value)| *value > 0.0).take(limit).collect();
+        let second = values.iter().rev().copied().filter(|(_, value)| *value < 0.0).take(limit).collect();
+        (first, second)
+    }
+
+    // None for empty cells and for visualizations calculated before receptive fields were stored
+    pub fn receptive_field(&self, i: usize, j: usize) -> Option<&[f32]> {
+        if self.data[i][j] == 0.0 {
+            return None;
+        }
+        self.receptive_fields.get(i).and_then(|column| column.get(j)).map(|field| field.as_slice())
+    }
+
+    // Shared by all cells, so that their sparklines can be compared
+    pub fn receptive_field_range(&self) -> (f32, f32) {
+        self.receptive_fields.iter().flatten().flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), value| (min.min(*value), max.max(*value)))
+    }
+
+    // None for empty cells and for visualizations calculated before errors were stored
+    pub fn quantization_error(&self, i: usize, j: usize) -> Option<f32> {
+        if self.data[i][j] == 0.0 {
+            return None;
+        }
+        self.quantization_errors.get(i).and_then(|column| column.get(j)).copied()
+    }
+
+    pub fn top_terms(&self, i: usize, j: usize) -> &[(String, f32)] {
+        self.top_terms.get(i).and_then(|column| column.get(j)).map_or(&[], |terms| terms.as_slice())
+    }
+
+    // Totals over a group of cells: hits, hit-weighted mean quantization error and anomalous texts
+    pub fn region_stats(&self, cells: &[(usize, usize)], calibration: Option<&AnomalyCalibration>) -> (f32, Option<f32>, usize) {
+        let hits: f32 = cells.iter().map(|(i, j)| self.data[*i][*j]).sum();
+        let weighted_errors: Vec<(f32, f32)> = cells.iter()
+            .filter_map(|(i, j)| self.quantization_error(*i, *j).map(|error| (error, self.data[*i][*j])))
+            .collect();
+        let error_hits: f32 = weighted_errors.iter().map(|(_, hits)| hits).sum();
+        let mean_error = (error_hits > 0.0)
+            .then(|| weighted_errors.iter().map(|(error, hits)| error * hits).sum::<f32>() / error_hits);
+        let anomalies = cells.iter()
+            .map(|(i, j)| (0..self.word_clusters[*i][*j].len()).filter(|text_index| self.is_anomalous(*i, *j, *text_index, calibration)).count())
+            .sum();
+
+        (hits, mean_error, anomalies)
+    }
+
+    // Most frequent texts of a cell with their counts, ties in alphabetical order
+    pub fn top_texts(&self, i: usize, j: usize, limit: usize) -> Vec<(&str, usize)> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for text in &self.word_clusters[i][j] {
+            *counts.entry(text.trim()).or_default() += 1;
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts.truncate(limit);
+        counts
+    }
+
+    pub fn anomaly_score(&self, i: usize, j: usize, text_index: usize) -> Option<f32> {
+        self.anomaly_scores.get(i)?.get(j)?.get(text_index).copied()
+    }
+
+    pub fn is_anomalous(&self, i: usize, j: usize, text_index: usize, calibration: Option<&AnomalyCalibration>) -> bool {
+        match (self.anomaly_score(i, j, text_index), calibration) {
+            (Some(score), Some(calibration)) => calibration.is_anomalous(score),
+            _ => false,
+        }
+    }
+
+    // Read from the map the visualization was made from, so that recalibrating it or changing its quantile shows
+    // up right away. None once the map is gone
+    pub fn map_calibration(&self, maps: &[SOMParams]) -> Option<Arc<Mutex<Option<AnomalyCalibration>>>> {
+        let provenance = self.provenance.as_ref()?;
+        find_map(maps, &provenance.map.id).map(|map| map.anomaly_calibration.clone())
+    }
+
+    pub fn transitions_to_csv(&self) -> String {
+        let mut csv = "from_row,from_col,to_row,to_col,count\n".to_owned();
+        for (from, to, count) in &self.transitions {
+            csv += &format!("{},{},{},{},{}\n", from.0, from.1, to.0, to.1, count);
         }
+
+        csv
     }
 
-    fn to_file(&self, filename: &PathBuf) -> Result<(), &str> {
-        // let json = serde_json::to_vec(&self.map_weights)?;
-        let writer = File::options().write(true).create(true).open(filename);
-        if writer.is_err() {
-            return Err("Error while opening the file");
+    pub fn transitions_to_dot(&self) -> String {
+        let max_count = self.transitions.iter().map(|transition| transition.2).max().unwrap_or(1);
+
+        let mut dot = format!("digraph \"{}\" {{\n    node [shape=box];\n", self.name.replace('"', "\\\""));
+        for (i, row) in self.data.iter().enumerate() {
+            for (j, count) in row.iter().enumerate() {
+                dot += &format!("    \"{i}_{j}\" [label=\"({i}, {j})\\n{count}\", pos=\"{i},-{j}!\"];\n");
+            }
         }
+        for (from, to, count) in &self.transitions {
+            let pen_width = 1.0 + 4.0 * (*count as f32) / (max_count as f32);
+            dot += &format!("    \"{}_{}\" -> \"{}_{}\" [weight={count}, label=\"{count}\", penwidth={pen_width:.2}];\n", 
+                from.0, from.1, to.0, to.1);
+        }
+        dot += "}\n";
 
-        let res = serde_json::to_writer_pretty(writer.unwrap(), self);
+        dot
+    }
+}
+
+pub enum TextMatcher {
+    Substring { pattern: String, case_sensitive: bool },
+    Regex(Regex),
+}
 
-        if res.is_err() {
-            Err("Error while serializing the map")
+impl TextMatcher {
+    pub fn new(pattern: &str, is_regex: bool, case_sensitive: bool) -> Result<Self, regex::Error> {
+        if is_regex {
+            let regex = RegexBuilder::new(pattern).case_insensitive(!case_sensitive).build()?;
+            Ok(TextMatcher::Regex(regex))
+        }
+        else if case_sensitive {
+            Ok(TextMatcher::Substring { pattern: pattern.to_owned(), case_sensitive })
         }
         else {
-            Ok(())
+            Ok(TextMatcher::Substring { pattern: pattern.to_lowercase(), case_sensitive })
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            TextMatcher::Substring { pattern, case_sensitive: true } => text.contains(pattern.as_str()),
+            TextMatcher::Substring { pattern, case_sensitive: false } => text.to_lowercase().contains(pattern.as_str()),
+            TextMatcher::Regex(regex) => regex.is_match(text),
         }
     }
 }
 
+impl Visualization {
+    // Indices of the matching texts for every cell with at least one match
+    pub fn search(&self, matcher: &TextMatcher) -> Vec<((usize, usize), Vec<usize>)> {
+        let mut results = vec![];
+        for (i, row) in self.word_clusters.iter().enumerate() {
+            for (j, texts) in row.iter().enumerate() {
+                let matches: Vec<usize> = texts.iter().enumerate()
+                    .filter(|(_, text)| matcher.is_match(text))
+                    .map(|(text_index, _)| text_index)
+                    .collect();
+                if !matches.is_empty() {
+                    results.push(((i, j), matches));
+                }
+            }
+        }
+
+        results
+    }
+}
+
+// Sparkline of a vector, the values themselves are in the hover text
+fn vector_ui(ui: &mut Ui, name: &str, vector: ArrayView1<f32>) {
+    ui.label(format!("{name}: {} values, norm {:.4}", vector.len(), vector.dot(&vector).sqrt()));
+
+    let (response, painter) = ui.allocate_painter(Vec2 { x: ui.available_width() - 10.0, y: 30.0 }, Sense::hover());
+    let rect = response.rect;
+    painter.rect_stroke(rect, Rounding::ZERO, Stroke::new(1.0, Color32::LIGHT_GRAY));
+
+    let min = vector.iter().copied().fold(0.0, f32::min);
+    let max = vector.iter().copied().fold(0.0, f32::max);
+    let zero = sparkline_points(rect, &[0.0], (min, max))[0].y;
+    painter.line_segment([Pos2 { x: rect.left(), y: zero }, Pos2 { x: rect.right(), y: zero }], Stroke::new(1.0, Color32::GRAY));
+    let points = sparkline_points(rect, &vector.to_vec(), (min, max));
+    painter.add(Shape::line(points, Stroke::new(1.5, Color32::DARK_BLUE)));
+
+    let values: Vec<String> = vector.iter().map(|value| format!("{value:.3}")).collect();
+    response.on_hover_text(values.join(", "));
+}
+
+// Values spread evenly across `rect`, `range` going from its bottom to its top
+fn sparkline_points(rect: Rect, values: &[f32], (min, max): (f32, f32)) -> Vec<Pos2> {
+    let range = if max > min { max - min } else { 1.0 };
+    values.iter().enumerate()
+        .map(|(index, value)| Pos2 {
+            x: rect.left() + rect.width() * (index as f32 + 0.5) / values.len() as f32,
+            y: rect.bottom() - rect.height() * (value - min) / range,
+        })
+        .collect()
+}
+
+fn export_text(contents: String, filter_name: &str, extension: &str) {
+    let files = FileDialog::new()
+        .add_filter(filter_name, &[extension])
+        .set_directory(".")
+        .save_file();
+
+    if let Some(path) = files {
+        if let Err(err) = std::fs::write(&path, contents) {
+            println!("Error while writing {}: {err}", path.display());
+        }
+    }
+}
+
+impl Versioned for Visualization {
+    fn format() -> String {
+        "visualization".to_owned()
+    }
+
+    fn migrate(payload: &mut Value, from_version: u32) -> Result<(), String> {
+        match from_version {
+            // Runtime status used to be saved along with the data
+            0 => remove_field(payload, "is_calculating"),
+            // The calibration is read from the map now, and the errors were the combined ones. Recomputing
+            // the visualization brings the errors back
+            1 => remove_field(payload, "anomaly_calibration").and_then(|_| remove_field(payload, "quantization_errors")),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Visualization {
+    fn from_file(filename: &Path) -> Result<Self, String> {
+        let mut visualization: Self = file_format::from_file(filename)?;
+        // Saved before the summaries existed, they only depend on the texts so are cheap to redo
+        if visualization.top_terms.is_empty() {
+            visualization.top_terms = visualization.term_index().top_terms_per_cell(TOP_TERMS);
+        }
+
+        Ok(visualization)
+    }
+
+    fn to_file(&self, filename: &Path) -> Result<(), String> {
+        file_format::to_file(self, filename)
+    }
+
+    fn term_index(&mut self) -> Arc<TermIndex> {
+        self.term_index.get_or_insert_with(|| Arc::new(TermIndex::new(&self.word_clusters))).clone()
+    }
+}
+
 #[derive(Debug)]
 pub struct VisualizationsUI {
-    visualizations: Vec<Arc<Mutex<Visualization>>>,
-    shown_visualization_index: Option<usize>,
+    shown_visualization_id: Option<String>,
     current_visualization: Visualization,
 
-    chosen_dataset_index: Option<usize>,
-    chosen_map_index: Option<usize>,
+    chosen_dataset_id: Option<String>,
+    chosen_map_id: Option<String>,
     
     current_shown_square: (usize, usize),
+    show_only_anomalies: bool,
+    show_transitions: bool,
+    transition_threshold: usize,
+    cell_labels: Annotation,
+    show_super_clusters: bool,
+    show_receptive_fields: bool,
+    receptive_words: Option<ReceptiveWords>,
+    selected_cells: BTreeSet<(usize, usize)>,
+    selection_terms: Option<SelectionTerms>,
+    selection_message: Option<String>,
+    region_name: String,
+    canvas: MapCanvas,
+
+    chosen_comparison_dataset_id: Option<String>,
+
+    query_map_id: Option<String>,
+    query_text: String,
+    query_trajectory: Vec<(usize, usize)>,
+    query_message: Option<String>,
+    query_is_dirty: bool,
+    embeddings_requested: bool,
+
+    search_text: String,
+    search_is_regex: bool,
+    search_case_sensitive: bool,
+    search_results: Vec<((usize, usize), Vec<usize>)>,
+    search_error: Option<String>,
+    searched_visualization_id: Option<String>,
+
+    inspected_map_id: Option<String>,
+    // The visualization the inspected map was chosen for
+    inspected_visualization_id: Option<String>,
+    // Nearest words of the last inspected (cell, map)
+    inspector_words: Option<((usize, usize), String, NearestWords)>,
+
+    playback: PlaybackUI,
+    projection: ProjectionUI,
 }
 
 impl Default for VisualizationsUI {
     fn default() -> Self {
-        Self { visualizations: vec![], shown_visualization_index: None, current_visualization: Visualization::default(), 
-            chosen_dataset_index: None, chosen_map_index: None, current_shown_square: (0, 0) }
+        Self { shown_visualization_id: None, current_visualization: Visualization::default(), 
+            chosen_dataset_id: None, chosen_map_id: None, chosen_comparison_dataset_id: None, current_shown_square: (0, 0), show_only_anomalies: false,
+            show_transitions: false, transition_threshold: 1, cell_labels: Annotation::None, show_super_clusters: false,
+            show_receptive_fields: false, receptive_words: None, selected_cells: BTreeSet::new(),
+            selection_terms: None, selection_message: None, region_name: "".to_owned(), canvas: MapCanvas::default(), query_map_id: None, query_text: "".to_owned(), 
+            query_trajectory: vec![], query_message: None, query_is_dirty: false, embeddings_requested: false,
+            search_text: "".to_owned(), search_is_regex: false, search_case_sensitive: false, search_results: vec![], 
+            search_error: None, searched_visualization_id: None, inspected_map_id: None, inspected_visualization_id: None, inspector_words: None,
+            playback: PlaybackUI::default(), projection: ProjectionUI::default() }
     }
 }
 
 impl VisualizationsUI {
-    fn visualization_list(&mut self, ui: &mut Ui) {
-        for (index, visualization) in self.visualizations.iter().enumerate() {
+    fn cell_tooltip_ui(ui: &mut Ui, visualization: &Visualization, i: usize, j: usize) {
+        ui.label(RichText::new(format!("Cell ({i}, {j})")).strong());
+        ui.label(format!("Hits: {}", visualization.data[i][j]));
+        if let Some(comparison) = &visualization.comparison {
+            ui.label(format!("Hits of {}: {}", comparison.other_dataset_name, comparison.other_hits[i][j]));
+        }
+        if let (VisualizationKind::DatasetDifference(measure), Some(value)) = (visualization.kind, visualization.cell_values()[i][j]) {
+            ui.label(format!("{}: {value:.4}", measure.name()));
+        }
+        if let Some(error) = visualization.quantization_error(i, j) {
+            ui.label(format!("Quantization error: {error:.4}"));
+        }
+        if let (VisualizationKind::ContextError | VisualizationKind::UMatrix, Some(value)) = (visualization.kind, visualization.cell_values()[i][j]) {
+            ui.label(format!("{}: {value:.4}", visualization.kind.name()));
+        }
+        if let Some((label, share)) = visualization.majority_label(i, j) {
+            ui.label(format!("Majority label: {label} ({:.0}%)", share * 100.0));
+        }
+
+        for (text, count) in visualization.top_texts(i, j, TOOLTIP_TEXTS) {
+            let preview: String = text.chars().take(TEXT_PREVIEW_CUTOFF).collect();
+            let ellipsis = if preview.len() < text.len() { "…" } else { "" };
+            ui.label(format!("{count} × {preview}{ellipsis}"));
+        }
+    }
+
+    // The inspector, basins and super-clusters describe the map the visualization was computed from, unless another
+    // map of the same size is picked by hand. Visualizations from before provenance was recorded use the query map
+    fn update_inspected_map(&mut self, visualization: &Arc<Mutex<Visualization>>, id: &str, maps: &[SOMParams], n: usize, m: usize) {
+        if self.inspected_visualization_id.as_deref() != Some(id) {
+            self.inspected_visualization_id = Some(id.to_owned());
+            self.inspected_map_id = None;
+        }
+        if !self.inspected_map_id.as_ref().and_then(|id| find_map(maps, id)).is_some_and(|map| is_inspectable(map, n, m)) {
+            self.inspected_map_id = None;
+        }
+        if self.inspected_map_id.is_none() {
+            let source_map_id = visualization.lock().unwrap().provenance.as_ref().map(|provenance| provenance.map.id.clone());
+            self.inspected_map_id = source_map_id.or(self.query_map_id.clone())
+                .filter(|id| find_map(maps, id).is_some_and(|map| is_inspectable(map, n, m)));
+        }
+    }
+
+    // Prototypes come from a map of the same size, the inspected map
+    fn inspector_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>, maps: &[SOMParams], n: usize, m: usize) {
+        let (i, j) = self.current_shown_square;
+        if i >= n || j >= m {
+            return;
+        }
+
+        egui::CollapsingHeader::new("Cell inspector").default_open(true).show(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Map:");
+                let cur_map_label = self.inspected_map_id.as_ref().and_then(|id| find_map(maps, id)).map_or("".to_owned(), |map| map.name.clone());
+                ComboBox::from_id_source("Inspected map")
+                .selected_text(cur_map_label)
+                .show_ui(ui, |ui| {
+                    for map in maps.iter() {
+                        if is_inspectable(map, n, m) {
+                            ui.selectable_value(&mut self.inspected_map_id, Some(map.id.clone()), map.name.as_str());
+                        }
+                    }
+                });
+            });
+
+            let source_map = visualization.lock().unwrap().provenance.as_ref().map(|provenance| provenance.map.clone());
+            if let Some(source_map) = source_map.filter(|source_map| self.inspected_map_id.as_ref().is_some_and(|id| *id != source_map.id)) {
+                ui.colored_label(Color32::RED, format!("Not the map the visualization was computed from ({}), \
+                    its prototypes, basins and super-clusters may not match the cells", source_map.name));
+            }
+
+            let locked_visualization = visualization.lock().unwrap();
+            ui.label(format!("Cell ({i}, {j}): {} hits", locked_visualization.data[i][j]));
+            if let Some(error) = locked_visualization.quantization_error(i, j) {
+                ui.label(format!("Mean quantization error: {error:.4}"));
+            }
+
+            let map = self.inspected_map_id.as_ref().and_then(|id| find_map(maps, id));
+            let weights = map.and_then(|map| map.map_weights.as_ref());
+            let locked_weights = weights.and_then(|weights| weights.try_lock().ok());
+            if weights.is_some() && locked_weights.is_none() {
+                ui.label("The map is being trained");
+            }
+
+            if let Some(weights) = &locked_weights {
+                vector_ui(ui, "som", weights.som().slice(ndarray::s![i, j, ..]));
+                vector_ui(ui, "context", weights.context().slice(ndarray::s![i, j, ..]));
+            }
+
+            if let (Some(weights), Some(map)) = (&locked_weights, map.filter(|map| map.pipeline.is_some())) {
+                let is_cached = matches!(&self.inspector_words, Some((cell, id, _)) if *cell == (i, j) && *id == map.id);
+                if !is_cached && embeddings_loaded() {
+                    let words = map.pipeline.as_ref().unwrap().nearest_words(weights.som().slice(ndarray::s![i, j, ..]), NEAREST_WORDS);
+                    self.inspector_words = Some(((i, j), map.id.clone(), words));
+                }
+                else if !is_cached {
+                    if !self.embeddings_requested {
+                        self.embeddings_requested = true;
+                        std::thread::spawn(embeddings);
+                    }
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Loading word embeddings");
+                    });
+                    ui.ctx().request_repaint_after(std::time::Duration::from_millis(200));
+                }
+
+                match &self.inspector_words {
+                    Some((_, _, Ok(words))) if is_cached => {
+                        let words: Vec<String> = words.iter().map(|(word, score)| format!("{word} ({score:.2})")).collect();
+                        ui.label(format!("Nearest words: {}", words.join(", ")));
+                    }
+                    Some((_, _, Err(err))) if is_cached => {
+                        ui.colored_label(Color32::RED, err);
+                    }
+                    _ => {}
+                }
+            }
+
+            ui.label("Neighbours:");
+            Grid::new("Neighbour stats").striped(true).show(ui, |ui| {
+                ui.label("Cell");
+                ui.label("Hits");
+                ui.label("QE");
+                if locked_weights.is_some() {
+                    ui.label("Prototype distance");
+                }
+                ui.end_row();
+
+                // Without an inspected map the topology is unknown, rectangular neighbours are shown then
+                let topology = map.map_or(Topology::Rectangular, |map| map.topology);
+                for (ni, nj) in topology.neighbours(i, j, n, m) {
+                    ui.label(format!("({ni}, {nj})"));
+                    ui.label(format!("{}", locked_visualization.data[ni][nj]));
+                    ui.label(locked_visualization.quantization_error(ni, nj).map_or("-".to_owned(), |error| format!("{error:.4}")));
+                    if let Some(weights) = &locked_weights {
+                        let difference = &weights.som().slice(ndarray::s![i, j, ..]) - &weights.som().slice(ndarray::s![ni, nj, ..]);
+                        ui.label(format!("{:.4}", difference.dot(&difference).sqrt()));
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    // A line for time series, nearest words of each step for embeddings, which need the inspected map's pipeline
+    fn receptive_field_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>, maps: &[SOMParams]) {
+        let (i, j) = self.current_shown_square;
+        let locked_visualization = visualization.lock().unwrap();
+        if locked_visualization.receptive_fields.is_empty() || i >= locked_visualization.data.len() || j >= locked_visualization.data[i].len() {
+            return;
+        }
+
+        egui::CollapsingHeader::new("Receptive field").show(ui, |ui| {
+            let Some(field) = locked_visualization.receptive_field(i, j) else {
+                ui.label("No samples in the cell");
+                return;
+            };
+            let chunk_size = locked_visualization.chunk_size.max(1);
+            ui.label(format!("Mean of {} samples, {} steps long", locked_visualization.data[i][j], field.len() / chunk_size));
+
+            if chunk_size == 1 {
+                let (response, painter) = ui.allocate_painter(Vec2 { x: ui.available_width() - 10.0, y: 80.0 }, Sense::hover());
+                painter.rect_stroke(response.rect, Rounding::ZERO, Stroke::new(1.0, Color32::LIGHT_GRAY));
+                let range = locked_visualization.receptive_field_range();
+                painter.add(Shape::line(sparkline_points(response.rect.shrink(4.0), field, range), Stroke::new(1.5, Color32::DARK_BLUE)));
+                let values: Vec<String> = field.iter().map(|value| format!("{value:.3}")).collect();
+                response.on_hover_text(values.join(", "));
+                ui.label(format!("All cells range from {:.3} to {:.3}", range.0, range.1));
+                return;
+            }
+
+            let Some((map_id, pipeline)) = self.inspected_map_id.as_ref()
+                .and_then(|id| find_map(maps, id))
+                .and_then(|map| map.pipeline.clone().map(|pipeline| (map.id.clone(), pipeline))) else {
+                ui.label("Inspect the cell with a map that has a text pipeline to see the words of each step");
+                return;
+            };
+
+            let revision = locked_visualization.revision;
+            let is_cached = matches!(&self.receptive_words, Some(cached) 
+                if cached.cell == (i, j) && cached.map_id == map_id && cached.revision == revision);
+            if !is_cached {
+                let words = Arc::new(Mutex::new(None));
+                let cloned_words = words.clone();
+                let chunks: Vec<Vec<f32>> = field.chunks(chunk_size).map(|chunk| chunk.to_vec()).collect();
+                std::thread::spawn(move || {
+                    let steps = chunks.into_iter()
+                        .map(|chunk| pipeline.nearest_words(ArrayView1::from(&chunk), RECEPTIVE_FIELD_WORDS)
+                            .map(|words| words.into_iter().map(|(word, _)| word).collect()))
+                        .collect();
+                    *cloned_words.lock().unwrap() = Some(steps);
+                });
+                self.receptive_words = Some(ReceptiveWords { cell: (i, j), map_id, revision, words });
+            }
+
+            let words = self.receptive_words.as_ref().unwrap().words.lock().unwrap();
+            match words.as_ref() {
+                None => {
+                    ui.spinner();
+                    ui.ctx().request_repaint_after(std::time::Duration::from_millis(200));
+                }
+                Some(Ok(steps)) => {
+                    Grid::new("Receptive field words").striped(true).show(ui, |ui| {
+                        for (step, words) in steps.iter().enumerate() {
+                            ui.label(format!("Step {}", step + 1));
+                            ui.label(words.join(", "));
+                            ui.end_row();
+                        }
+                    });
+                }
+                Some(Err(err)) => {
+                    ui.colored_label(Color32::RED, err);
+                }
+            }
+        });
+    }
+
+    fn color_bar_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>, scale: &FittedScale) {
+        let (response, painter) = ui.allocate_painter(Vec2 { x: ui.available_width() - 10.0, y: 12.0 }, Sense::hover());
+        let bar = response.rect;
+        let steps = 64;
+        for step in 0..steps {
+            let t = step as f32 / steps as f32;
+            let step_rect = Rect::from_min_max(
+                Pos2 { x: bar.left() + t * bar.width(), y: bar.top() },
+                Pos2 { x: bar.left() + (t + 1.0 / steps as f32) * bar.width() + 0.5, y: bar.bottom() });
+            painter.rect_filled(step_rect, Rounding::ZERO, scale.color_map().color(t));
+        }
+        painter.rect_stroke(bar, Rounding::ZERO, Stroke::new(1.0, Color32::BLACK));
+
+        ui.horizontal(|ui| {
+            ui.label(format!("{:.2}", scale.value_at(0.0)));
+            ui.with_layout(Layout::right_to_left(egui::Align::Center), |ui| {
+                ui.label(format!("{:.2}", scale.value_at(1.0)));
+                ui.centered_and_justified(|ui| {
+                    ui.label(format!("{:.2}", scale.value_at(0.5)));
+                });
+            });
+        });
+
+        let mut color_scale = visualization.lock().unwrap().color_scale;
+        Grid::new("Colour scale").show(ui, |ui| {
+            ui.label("Colour map:");
+            ComboBox::from_id_source("Colour map selection")
+                .selected_text(color_scale.color_map.name())
+                .show_ui(ui, |ui| {
+                    for color_map in ColorMap::ALL {
+                        ui.selectable_value(&mut color_scale.color_map, color_map, color_map.name());
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Scaling:");
+            ComboBox::from_id_source("Scaling selection")
+                .selected_text(color_scale.scaling.name())
+                .show_ui(ui, |ui| {
+                    for scaling in Scaling::ALL {
+                        ui.selectable_value(&mut color_scale.scaling, scaling, scaling.name());
+                    }
+                });
+            ui.end_row();
+
+            let mut is_fixed = color_scale.fixed_range.is_some();
+            ui.checkbox(&mut is_fixed, "Fixed range");
+            if is_fixed {
+                let (mut min, mut max) = color_scale.fixed_range.unwrap_or((scale.min, scale.max));
+                ui.horizontal(|ui| {
+                    ui.add(DragValue::new(&mut min).speed(0.1));
+                    ui.label("to");
+                    ui.add(DragValue::new(&mut max).speed(0.1));
+                });
+                color_scale.fixed_range = Some((min, max));
+            }
+            else {
+                color_scale.fixed_range = None;
+            }
+            ui.end_row();
+        });
+        visualization.lock().unwrap().color_scale = color_scale;
+    }
+
+    fn run_query(&mut self, map: &SOMParams) {
+        self.query_trajectory.clear();
+        self.query_message = None;
+        if self.query_text.trim().is_empty() {
+            return;
+        }
+
+        let Some(pipeline) = map.pipeline.as_ref() else {
+            return;
+        };
+        let Ok(weights) = map.map_weights.as_ref().unwrap().try_lock() else {
+            self.query_message = Some("The map is being trained".to_owned());
+            return;
+        };
+
+        match pipeline.embed(&self.query_text) {
+            Ok(sample) if sample.is_empty() => {
+                self.query_message = Some("None of the words are known".to_owned());
+            }
+            Ok(sample) if sample.len() % weights.map_input_size != 0 => {
+                self.query_message = Some(format!("Embedding length {} doesn't fit the map input size {}", 
+                    sample.len(), weights.map_input_size));
+            }
+            Ok(sample) => {
+                self.query_trajectory = weights.evaluate_trajectory(sample.view()).iter().map(|step| step.0).collect();
+            }
+            Err(err) => {
+                self.query_message = Some(err.to_owned());
+            }
+        }
+    }
+
+    fn query_ui(&mut self, ui: &mut Ui, maps: &[SOMParams], n: usize, m: usize) {
+        if self.query_map_id.is_some() && !self.query_map_id.as_ref().and_then(|id| find_map(maps, id)).is_some_and(|map| map.n == n && map.m == m) {
+            self.query_map_id = None;
+            self.query_trajectory.clear();
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Query map:");
+            let mut cur_map_label = "".to_owned();
+            if let Some(map) = self.query_map_id.as_ref().and_then(|id| find_map(maps, id)) {
+                cur_map_label = map.name.clone();
+            }
+
+            ComboBox::from_id_source("Query map")
+            .selected_text(cur_map_label)
+            .show_ui(ui, |ui| {
+                for map in maps.iter() {
+                    if map.map_weights.is_some() && map.pipeline.is_some() && map.n == n && map.m == m 
+                        && ui.selectable_value(&mut self.query_map_id, Some(map.id.clone()), map.name.as_str()).clicked() {
+                        self.query_is_dirty = true;
+                    }
+                }
+            });
+        });
+
+        let Some(map) = self.query_map_id.as_ref().and_then(|id| find_map(maps, id)) else {
+            return;
+        };
+
+        let response = ui.add(TextEdit::singleline(&mut self.query_text).hint_text("Text to project onto the map"));
+        if response.changed() {
+            self.query_is_dirty = true;
+        }
+
+        if self.query_is_dirty {
+            if embeddings_loaded() {
+                self.run_query(map);
+                self.query_is_dirty = false;
+            }
+            else {
+                if !self.embeddings_requested {
+                    self.embeddings_requested = true;
+                    std::thread::spawn(embeddings);
+                }
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Loading word embeddings");
+                });
+                ui.ctx().request_repaint_after(std::time::Duration::from_millis(200));
+            }
+        }
+
+        if let Some(message) = &self.query_message {
+            ui.colored_label(Color32::RED, message);
+        }
+        else if let Some(best_unit_coords) = self.query_trajectory.last() {
+            ui.label(format!("BMU: {best_unit_coords:?}, trajectory of {} steps", self.query_trajectory.len()));
+        }
+    }
+
+    fn difference_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>) {
+        let mut locked_visualization = visualization.lock().unwrap();
+        let VisualizationKind::DatasetDifference(mut measure) = locked_visualization.kind else {
+            return;
+        };
+        let Some(comparison) = locked_visualization.comparison.clone() else {
+            return;
+        };
+
+        ui.label(format!("{} compared with {}", comparison.dataset_name, comparison.other_dataset_name));
+        ComboBox::from_label("Measure")
+            .selected_text(measure.name())
+            .show_ui(ui, |ui| {
+                for option in DifferenceMeasure::ALL {
+                    ui.selectable_value(&mut measure, option, option.name());
+                }
+            });
+        if locked_visualization.kind != VisualizationKind::DatasetDifference(measure) {
+            locked_visualization.kind = VisualizationKind::DatasetDifference(measure);
+            locked_visualization.revision = next_revision();
+        }
+
+        let (first, second) = locked_visualization.over_represented(OVER_REPRESENTED_CELLS);
+        drop(locked_visualization);
+        egui::CollapsingHeader::new("Over-represented cells").show(ui, |ui| {
+            for (dataset_name, cells) in [(&comparison.dataset_name, first), (&comparison.other_dataset_name, second)] {
+                ui.label(RichText::new(format!("More of {dataset_name}")).strong());
+                if cells.is_empty() {
+                    ui.label("None");
+                }
+                for (cell, value) in cells {
+                    ui.horizontal(|ui| {
+                        if ui.button("Go to").clicked() {
+                            self.current_shown_square = cell;
+                            self.selected_cells.clear();
+                        }
+                        ui.label(format!("Cell {cell:?}: {value:.4}"));
+                    });
+                }
+            }
+        });
+    }
+
+    // Selected cells, the chosen cell alone if nothing else is selected
+    fn selection(&self, n: usize, m: usize) -> Vec<(usize, usize)> {
+        let (i, j) = self.current_shown_square;
+        if self.selected_cells.is_empty() && i < n && j < m {
+            return vec![(i, j)];
+        }
+        self.selected_cells.iter().copied().filter(|(i, j)| *i < n && *j < m).collect()
+    }
+
+    // Cells draining to the same U-matrix minimum as `cell` on the inspected map
+    fn select_basin(&mut self, maps: &[SOMParams], cell: (usize, usize), n: usize, m: usize) {
+        let weights = self.inspected_map_id.as_ref()
+            .and_then(|id| find_map(maps, id))
+            .filter(|map| map.n == n && map.m == m)
+            .and_then(|map| map.map_weights.as_ref());
+        let Some(weights) = weights else {
+            self.selection_message = Some("Choose a trained map of the same size in the cell inspector".to_owned());
+            return;
+        };
+        let Ok(weights) = weights.try_lock() else {
+            self.selection_message = Some("The map is being trained".to_owned());
+            return;
+        };
+
+        let basins = u_matrix_basins(&weights.u_matrix(), weights.topology);
+        self.selected_cells = basins.indexed_iter()
+            .filter(|(_, basin)| **basin == basins[cell])
+            .map(|(cell, _)| cell)
+            .collect();
+        self.selection_message = None;
+    }
+
+    fn selection_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>, id: &str, maps: &[SOMParams], n: usize, m: usize) {
+        let selection = self.selection(n, m);
+
+        egui::CollapsingHeader::new("Selection and regions").default_open(true).show(ui, |ui| {
+            ui.label("Shift + click or shift + drag to select several cells, alt + click for a U-matrix basin");
+
+            {
+                let locked_visualization = visualization.lock().unwrap();
+                let map_calibration = locked_visualization.map_calibration(maps);
+                let calibration = map_calibration.as_ref().map(|calibration| calibration.lock().unwrap());
+                let calibration = calibration.as_ref().and_then(|calibration| calibration.as_ref());
+                let (hits, mean_error, anomalies) = locked_visualization.region_stats(&selection, calibration);
+                ui.label(format!("{} cells, {hits} hits", selection.len()));
+                if let Some(error) = mean_error {
+                    ui.label(format!("Mean quantization error: {error:.4}"));
+                }
+                if calibration.is_some() {
+                    ui.label(format!("{anomalies} anomalous texts"));
+                }
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button("Select basin").on_hover_text("U-matrix basin of the chosen cell on the inspected map").clicked() {
+                    self.select_basin(maps, self.current_shown_square, n, m);
+                }
+                if ui.button("Clear selection").clicked() {
+                    self.selected_cells.clear();
+                }
+            });
+            if let Some(message) = &self.selection_message {
+                ui.colored_label(Color32::RED, message);
+            }
+
+            ui.horizontal(|ui| {
+                ui.add(TextEdit::singleline(&mut self.region_name).hint_text("Region name").desired_width(120.0));
+                if ui.add_enabled(!self.region_name.is_empty(), egui::Button::new("Save as region")).clicked() {
+                    let region = Region { name: self.region_name.clone(), cells: selection.clone() };
+                    visualization.lock().unwrap().regions.push(region);
+                    self.region_name.clear();
+                }
+            });
+
+            let mut removed = None;
+            for (region_index, region) in visualization.lock().unwrap().regions.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} ({} cells)", region.name, region.cells.len()));
+                    if ui.button("Select").clicked() {
+                        self.selected_cells = region.cells.iter().copied().collect();
+                        if let Some(first) = region.cells.first() {
+                            self.current_shown_square = *first;
+                        }
+                    }
+                    if ui.button("Delete").clicked() {
+                        removed = Some(region_index);
+                    }
+                });
+            }
+            if let Some(region_index) = removed {
+                visualization.lock().unwrap().regions.remove(region_index);
+            }
+        });
+
+        let revision = visualization.lock().unwrap().revision;
+        let is_cached = matches!(&self.selection_terms, Some(cached) if cached.visualization_id == id 
+            && cached.revision == revision && cached.cells == selection);
+        if selection.len() > 1 && !is_cached {
+            let term_index = visualization.lock().unwrap().term_index();
+            let terms = term_index.region_top_terms(&selection, TOP_TERMS);
+            self.selection_terms = Some(SelectionTerms { visualization_id: id.to_owned(), revision, cells: selection, terms });
+        }
+    }
+
+    // Top terms of the selection sized by their score, clicking one searches for it
+    fn word_cloud_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>, id: &str, selection: &[(usize, usize)]) {
+        let (title, terms) = match (selection, &self.selection_terms) {
+            ([(i, j)], _) => (format!("Word cloud of cell ({i}, {j})"), visualization.lock().unwrap().top_terms(*i, *j).to_vec()),
+            (_, Some(cached)) if cached.visualization_id == id && cached.cells == selection =>
+                (format!("Word cloud of {} cells", selection.len()), cached.terms.clone()),
+            _ => ("Word cloud".to_owned(), vec![]),
+        };
+
+        egui::CollapsingHeader::new(title).id_source("Word cloud").default_open(true).show(ui, |ui| {
+            let Some(max_score) = terms.first().map(|(_, score)| *score) else {
+                ui.label("No texts in the cluster");
+                return;
+            };
+
+            ui.horizontal_wrapped(|ui| {
+                for (term, score) in &terms {
+                    let weight = score / max_score;
+                    let text = RichText::new(term).size(11.0 + 15.0 * weight).color(ColorMap::Blues.color(0.4 + 0.6 * weight));
+                    let response = ui.add(Label::new(text).sense(Sense::click()))
+                        .on_hover_cursor(egui::CursorIcon::PointingHand)
+                        .on_hover_text(format!("TF-IDF {score:.4}"));
+                    if response.clicked() {
+                        self.search_text = term.clone();
+                        self.search_is_regex = false;
+                        self.search_case_sensitive = false;
+                        self.searched_visualization_id = None;
+                    }
+                }
+            });
+        });
+    }
+
+    fn search_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>, id: &str) {
+        // Clusters are still being filled while calculating, so the results are refreshed every frame
+        let mut search_changed = self.searched_visualization_id.as_deref() != Some(id) || visualization.lock().unwrap().is_calculating;
+        ui.horizontal(|ui| {
+            let response = ui.add(TextEdit::singleline(&mut self.search_text).hint_text("Search the cluster texts"));
+            search_changed |= response.changed();
+            search_changed |= ui.checkbox(&mut self.search_is_regex, "Regex").changed();
+            search_changed |= ui.checkbox(&mut self.search_case_sensitive, "Aa").on_hover_text("Case sensitive").changed();
+        });
+
+        if search_changed {
+            self.searched_visualization_id = Some(id.to_owned());
+            self.search_results.clear();
+            self.search_error = None;
+
+            if !self.search_text.is_empty() {
+                match TextMatcher::new(&self.search_text, self.search_is_regex, self.search_case_sensitive) {
+                    Ok(matcher) => self.search_results = visualization.lock().unwrap().search(&matcher),
+                    Err(err) => self.search_error = Some(err.to_string()),
+                }
+            }
+        }
+
+        if let Some(err) = &self.search_error {
+            ui.colored_label(Color32::RED, err);
+        }
+    }
+
+    fn search_results_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>) {
+        if self.search_text.is_empty() || self.search_error.is_some() {
+            return;
+        }
+
+        let match_count: usize = self.search_results.iter().map(|(_, matches)| matches.len()).sum();
+        ui.collapsing(format!("{match_count} matches in {} cells", self.search_results.len()), |ui| {
+            let locked_visualization = visualization.lock().unwrap();
+            for (cell, matches) in &self.search_results {
+                ui.horizontal(|ui| {
+                    if ui.button("Go to").clicked() {
+                        self.current_shown_square = *cell;
+                    }
+                    ui.label(format!("Cell {cell:?}: {} matches", matches.len()));
+                });
+
+                for text_index in matches.iter().take(TEXT_PREVIEW_CUTOFF) {
+                    let line = &locked_visualization.word_clusters[cell.0][cell.1][*text_index];
+                    ui.add(Label::new(line).truncate(true));
+                }
+                if matches.len() > TEXT_PREVIEW_CUTOFF {
+                    ui.label(format!("... and {} more", matches.len() - TEXT_PREVIEW_CUTOFF));
+                }
+            }
+        });
+    }
+
+    fn provenance_ui(&mut self, ui: &mut Ui, visualization: &Arc<Mutex<Visualization>>, maps: &[SOMParams],
+        datasets: &[(String, Arc<Mutex<DataSet>>)]) {
+        let (provenance, stale_reasons, is_calculating) = {
+            let locked_visualization = visualization.lock().unwrap();
+            (locked_visualization.provenance.clone(), locked_visualization.stale_reasons(maps, datasets), locked_visualization.is_calculating)
+        };
+
+        egui::CollapsingHeader::new("Provenance").default_open(!stale_reasons.is_empty()).show(ui, |ui| {
+            let Some(provenance) = provenance else {
+                ui.label("Not recorded, the visualization was saved before provenance was");
+                return;
+            };
+
+            Grid::new("Provenance").num_columns(2).show(ui, |ui| {
+                let source_row = |ui: &mut Ui, label: &str, source: &SourceRef| {
+                    ui.label(label);
+                    ui.label(&source.name).on_hover_text(format!("ID {}\nContent hash {}", source.id, source.hash));
+                    ui.end_row();
+                };
+                source_row(ui, "Map:", &provenance.map);
+                source_row(ui, "Dataset:", &provenance.dataset);
+                if let Some(comparison_dataset) = &provenance.comparison_dataset {
+                    source_row(ui, "Compared with:", comparison_dataset);
+                }
+                ui.label("Parameters:");
+                ui.add(Label::new(&provenance.parameters).wrap(true));
+                ui.end_row();
+                ui.label("Computed at:");
+                ui.label(&provenance.computed_at);
+                ui.end_row();
+            });
+
+            for reason in &stale_reasons {
+                ui.colored_label(Color32::RED, reason);
+            }
+            if !stale_reasons.is_empty() && ui.add_enabled(!is_calculating, egui::Button::new("Recompute")).clicked() {
+                let res = recompute(visualization, maps, datasets);
+                if res.is_err() {
+                    println!("{}", res.err().unwrap());
+                }
+            }
+        });
+    }
+
+    // Returns the ID of the visualization to remove, if one was picked
+    fn visualization_list(&mut self, ui: &mut Ui, registry: &Registry) -> Option<String> {
+        let mut removed = None;
+        for (id, visualization) in registry.visualizations.iter() {
             let frame_style = Style::default();
-            let is_current = Some(index) == self.shown_visualization_index;
+            let is_current = self.shown_visualization_id.as_ref() == Some(id);
             let stroke_color = if is_current {
                 Color32::DARK_GRAY
             }
@@ -141,7 +1447,11 @@ impl VisualizationsUI {
 
                 ui.horizontal_centered(|ui| {
                     ui.label(visualization.lock().unwrap().name.as_str());
-                    
+                    let stale_reasons = visualization.lock().unwrap().stale_reasons(&registry.maps, &registry.datasets);
+                    if !stale_reasons.is_empty() {
+                        ui.colored_label(Color32::RED, "(stale)").on_hover_text(stale_reasons.join("\n"));
+                    }
+
                     ui.with_layout(Layout::right_to_left(egui::Align::Center), |ui|{
                         if visualization.lock().unwrap().is_calculating {
                             ui.spinner();
@@ -152,7 +1462,8 @@ impl VisualizationsUI {
 
             let response = frame.allocate_space(ui).on_hover_cursor(egui::CursorIcon::PointingHand).interact(Sense::click());
             if response.clicked() {
-                self.shown_visualization_index = Some(index);
+                self.shown_visualization_id = Some(id.clone());
+                self.selected_cells.clear();
             }
 
             if response.hovered() {
@@ -177,21 +1488,55 @@ impl VisualizationsUI {
 
                     ui.close_menu();
                 }
+
+                if ui.button("Export as image").clicked() {
+                    let files = FileDialog::new()
+                        .add_filter("PNG image", &["png"])
+                        .add_filter("SVG image", &["svg"])
+                        .set_directory(".")
+                        .save_file();
+
+                    if let Some(path) = files {
+                        let vis = visualization.lock().unwrap();
+                        let options = RenderOptions { title: Some(vis.name.clone()), ..Default::default() };
+                        let res = render::render_to_file(&vis, &options, &path);
+                        if res.is_err() {
+                            println!("{}", res.err().unwrap());
+                        }
+                    }
+
+                    ui.close_menu();
+                }
+
+                if ui.button("Remove").clicked() {
+                    removed = Some(id.clone());
+                    ui.close_menu();
+                }
             });
 
             frame.paint(ui);
         }
+        removed
     }
 
-    pub fn show(&mut self, ui: &mut Ui, maps: Vec<SOMParams>, datasets: &Vec<Arc<Mutex<DataSet>>>) {
+    pub fn show(&mut self, ui: &mut Ui, registry: &mut Registry) {
         ui.painter().rect_filled(ui.max_rect(), Rounding::ZERO, Color32::WHITE);
         let modal = Modal::new(ui.ctx(), "visualization modal");
-        
-        if let Some(index) = self.shown_visualization_index {
+        // Added and removed once the panes are drawn, the lists are borrowed until then
+        let mut created = None;
+        let mut loaded = None;
+        let mut removed = None;
+        let (maps, datasets) = (&registry.maps, &registry.datasets);
+
+        let shown_visualization = self.shown_visualization_id.as_ref().and_then(|id| registry.visualization(id)).cloned();
+        if shown_visualization.is_none() {
+            self.shown_visualization_id = None;
+        }
+        if let Some(shown_visualization) = shown_visualization {
+            let id = shown_visualization.lock().unwrap().id.clone();
             SidePanel::right("visualization_preview")
             .show_inside(ui, |ui| {
                 ScrollArea::vertical().show(ui, |ui| {
-                    let shown_visualization = &self.visualizations[index];
                     if shown_visualization.lock().unwrap().data.len() == 0 {
                         return ;
                     }
@@ -200,75 +1545,252 @@ impl VisualizationsUI {
                         return ;
                     } 
 
-                    let available_size = ui.available_size();
-                    let (response, painter) = ui.allocate_painter(
-                        egui::Vec2::new(
-                            available_size.x - 10.0,
-                            available_size.x - 10.0,
-                        ),
-                        egui::Sense::hover(),
-                    );
-                    let available_rect = response.rect;
-                    let mut rects = vec![];
-        
                     let n = shown_visualization.lock().unwrap().data.len();
                     let m = shown_visualization.lock().unwrap().data[0].len();
-                    let i_step = available_rect.width() / (n as f32);
-                    let j_step = available_rect.height() / (m as f32);
-        
-                    let max_val = shown_visualization.lock().unwrap()
-                        .data.iter().flatten().max_by(|a, b| a.total_cmp(b)).unwrap().clone();
+                    self.query_ui(ui, maps, n, m);
+                    self.update_inspected_map(&shown_visualization, &id, maps, n, m);
+                    self.search_ui(ui, &shown_visualization, &id);
+
+                    let available_size = ui.available_size();
+                    let side = available_size.x - 10.0;
+                    let response = self.canvas.allocate(ui, n, m, Vec2 { x: side, y: side });
+                    if response.double_clicked() {
+                        self.canvas.reset_view();
+                    }
+                    let painter = self.canvas.painter(ui);
+                    let Vec2 { x: i_step, y: j_step } = self.canvas.cell_size();
+
+                    let hovered_cell = if response.dragged() { None } else { response.hover_pos().and_then(|pos| self.canvas.cell_at(pos)) };
+                    // Click picks a cell, shift + click adds or removes one, alt + click takes its U-matrix basin
+                    if response.clicked() {
+                        if let Some(cell) = response.interact_pointer_pos().and_then(|pos| self.canvas.cell_at(pos)) {
+                            let modifiers = ui.input(|input| input.modifiers);
+                            if modifiers.shift {
+                                self.selected_cells.extend(self.selection(n, m));
+                                if !self.selected_cells.remove(&cell) {
+                                    self.selected_cells.insert(cell);
+                                }
+                            }
+                            else if modifiers.alt {
+                                self.select_basin(maps, cell, n, m);
+                            }
+                            else {
+                                self.selected_cells.clear();
+                            }
+                            self.current_shown_square = cell;
+                        }
+                    }
+                    if let Some(cells) = self.canvas.take_selection() {
+                        self.selected_cells.extend(self.selection(n, m));
+                        self.selected_cells.extend(cells);
+                    }
+                    let selection = self.selection(n, m);
 
-                    let brightness = shown_visualization.lock().unwrap().data.clone();
                     let mut lines_to_display = vec![];
-        
-                    for i in 0..n {
-                        for j in 0..m {
-                            let i_f = i as f32;
-                            let j_f = j as f32;
-                            let cur_rect = Rect {
-                                min: Pos2 {x: i_f * i_step, y: j_f * j_step}, 
-                                max: Pos2 { x: (i_f + 1.0) * i_step, y: (j_f + 1.0) * j_step }
-                            }.translate(response.rect.min.to_vec2());
-        
-                            if self.current_shown_square == (i, j) {
-                                lines_to_display = shown_visualization.lock().unwrap().word_clusters[i][j].clone();
-                                rects.push(Shape::Rect(RectShape::new(cur_rect, Rounding::ZERO, Color32::RED, Stroke::new(1.0, Color32::BLACK))));
+                    let mut line_scores = vec![];
+                    let map_calibration = shown_visualization.lock().unwrap().map_calibration(maps);
+                    let calibration = map_calibration.as_ref().map(|calibration| calibration.lock().unwrap());
+                    let calibration = calibration.as_ref().and_then(|calibration| calibration.as_ref());
+                    let scale = {
+                        let locked_visualization = shown_visualization.lock().unwrap();
+                        let scale = locked_visualization.fitted_scale();
+                        let values = locked_visualization.cell_values();
+                        let fill = |i: usize, j: usize| values[i][j].map_or(Color32::GRAY, |value| scale.color(value));
+                        self.canvas.paint_cells(&painter, locked_visualization.revision, locked_visualization.color_scale, fill);
+
+                        // Of the inspected map, it has the size of this visualization
+                        let inspected_map = self.inspected_map_id.as_ref().and_then(|id| find_map(maps, id));
+                        if let Some(map) = inspected_map.filter(|map| self.show_super_clusters && map.n == n && map.m == m) {
+                            if let Some(clusters) = map.super_clusters.lock().unwrap().as_ref() {
+                                self.canvas.paint_super_clusters(&painter, clusters);
                             }
-                            else if ui.rect_contains_pointer(cur_rect) {
+                        }
 
-                                if response.interact(Sense::click()).clicked() {
-                                    self.current_shown_square = (i, j);
+                        // Level of detail: labels show up once they are legible, big cells get a few top terms
+                        let (i_range, j_range) = self.canvas.visible_cells();
+                        let font_size = (0.25 * i_step.min(j_step)).min(MAX_LABEL_SIZE);
+                        let lines = ((0.8 * j_step / (1.3 * font_size)) as usize).clamp(1, MAX_LABEL_LINES);
+                        for i in i_range {
+                            for j in j_range.clone() {
+                                let labels = render::cell_labels(&locked_visualization, self.cell_labels, i, j, lines);
+                                let line_height = 1.3 * font_size;
+                                let top = self.canvas.cell_center((i, j)).y - 0.5 * line_height * (labels.len() as f32 - 1.0);
+                                for (line, label) in labels.iter().enumerate() {
+                                    // A character is roughly 0.55 of the font size wide
+                                    let size = font_size.min(1.6 * i_step / label.chars().count() as f32);
+                                    if size < MIN_LABEL_SIZE {
+                                        continue;
+                                    }
+                                    let pos = Pos2 { x: self.canvas.cell_center((i, j)).x, y: top + line as f32 * line_height };
+                                    painter.text(pos, Align2::CENTER_CENTER, label, FontId::proportional(size), text_color(fill(i, j)));
                                 }
+                            }
+                        }
 
-                                rects.push(Shape::Rect(RectShape::new(cur_rect, Rounding::ZERO, Color32::DARK_GREEN, Stroke::new(1.0, Color32::BLACK))));
+                        if self.show_receptive_fields && locked_visualization.chunk_size == 1 && i_step.min(j_step) >= MIN_SPARKLINE_CELL {
+                            let range = locked_visualization.receptive_field_range();
+                            let (i_range, j_range) = self.canvas.visible_cells();
+                            for i in i_range {
+                                for j in j_range.clone() {
+                                    if let Some(field) = locked_visualization.receptive_field(i, j) {
+                                        let rect = self.canvas.cell_rect((i, j)).shrink2(Vec2 { x: 0.1 * i_step, y: 0.2 * j_step });
+                                        painter.add(Shape::line(sparkline_points(rect, field, range), Stroke::new(1.5, text_color(fill(i, j)))));
+                                    }
+                                }
                             }
-                            else {
-                                if brightness[i][j] == 0.0 {
-                                    rects.push(Shape::Rect(RectShape::new(cur_rect, Rounding::ZERO, 
-                                        Color32::GRAY, 
-                                        Stroke::new(1.0, Color32::BLACK))));
+                        }
+
+                        for &(i, j) in &selection {
+                            lines_to_display.extend(locked_visualization.word_clusters[i][j].iter().cloned());
+                            line_scores.extend((0..locked_visualization.word_clusters[i][j].len())
+                                .map(|text_index| {
+                                    let score = locked_visualization.anomaly_score(i, j, text_index);
+                                    let percentile = score.zip(calibration).map(|(score, calibration)| calibration.percentile(score));
+                                    (score.zip(percentile), locked_visualization.is_anomalous(i, j, text_index, calibration))
+                                }));
+
+                            let width = if (i, j) == self.current_shown_square { 3.0 } else { 2.0 };
+                            painter.rect_stroke(self.canvas.cell_rect((i, j)).shrink(width / 2.0), Rounding::ZERO, Stroke::new(width, Color32::RED));
+                        }
+                        self.canvas.paint_selection_box(&painter);
+
+                        if let Some((i, j)) = hovered_cell.filter(|cell| !selection.contains(cell)) {
+                            painter.rect_stroke(self.canvas.cell_rect((i, j)).shrink(1.0), Rounding::ZERO, Stroke::new(2.0, Color32::DARK_GREEN));
+                        }
+                        if let Some((i, j)) = hovered_cell {
+                            response.clone().on_hover_ui_at_pointer(|ui| Self::cell_tooltip_ui(ui, &locked_visualization, i, j));
+                        }
+
+                        scale
+                    };
+
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Zoom ×{:.1}", self.canvas.zoom()))
+                            .on_hover_text("Ctrl + scroll to zoom, drag to pan, double click to reset");
+                        if ui.button("−").clicked() {
+                            self.canvas.set_zoom(self.canvas.zoom() / 1.5);
+                        }
+                        if ui.button("+").clicked() {
+                            self.canvas.set_zoom(self.canvas.zoom() * 1.5);
+                        }
+                        if ui.button("Reset view").clicked() {
+                            self.canvas.reset_view();
+                        }
+                    });
+                    self.color_bar_ui(ui, &shown_visualization, &scale);
+                    self.difference_ui(ui, &shown_visualization);
+                    ComboBox::from_label("Cell labels")
+                        .selected_text(self.cell_labels.name())
+                        .show_ui(ui, |ui| {
+                            for annotation in Annotation::ALL {
+                                ui.selectable_value(&mut self.cell_labels, annotation, annotation.name());
+                            }
+                        });
+                    ui.checkbox(&mut self.show_super_clusters, "Show super-clusters of the inspected map");
+                    self.provenance_ui(ui, &shown_visualization, maps, datasets);
+                    let has_sequences = {
+                        let locked_visualization = shown_visualization.lock().unwrap();
+                        !locked_visualization.receptive_fields.is_empty() && locked_visualization.chunk_size == 1
+                    };
+                    if has_sequences {
+                        ui.checkbox(&mut self.show_receptive_fields, "Show receptive fields as sparklines");
+                    }
+                    self.inspector_ui(ui, &shown_visualization, maps, n, m);
+                    self.receptive_field_ui(ui, &shown_visualization, maps);
+                    self.selection_ui(ui, &shown_visualization, &id, maps, n, m);
+                    self.word_cloud_ui(ui, &shown_visualization, &id, &selection);
+
+                    let cell_center = |cell: (usize, usize)| self.canvas.cell_center(cell);
+
+                    if let Some(best_unit_coords) = self.query_trajectory.last() {
+                        let query_stroke = Stroke::new(2.0, Color32::from_rgb(255, 140, 0));
+                        let points: Vec<Pos2> = self.query_trajectory.iter().map(|cell| cell_center(*cell)).collect();
+                        painter.add(Shape::line(points.clone(), query_stroke));
+                        for point in points {
+                            painter.circle_filled(point, 3.0, query_stroke.color);
+                        }
+
+                        let bmu_rect = Rect::from_center_size(cell_center(*best_unit_coords), Vec2 { x: i_step, y: j_step });
+                        painter.rect_stroke(bmu_rect, Rounding::ZERO, Stroke::new(3.0, query_stroke.color));
+                    }
+
+                    for (cell, matches) in &self.search_results {
+                        let cell_rect = Rect::from_center_size(cell_center(*cell), Vec2 { x: i_step, y: j_step });
+                        painter.rect_stroke(cell_rect.shrink(1.0), Rounding::ZERO, Stroke::new(2.0, Color32::YELLOW));
+                        painter.text(cell_center(*cell), Align2::CENTER_CENTER, matches.len().to_string(), 
+                            FontId::proportional(0.5 * i_step.min(j_step)), Color32::YELLOW);
+                    }
+
+                    let transitions = shown_visualization.lock().unwrap().transitions.clone();
+                    if !transitions.is_empty() {
+                        let max_count = transitions.iter().map(|transition| transition.2).max().unwrap();
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut self.show_transitions, "Show BMU transitions");
+                            ui.add(Slider::new(&mut self.transition_threshold, 1..=max_count).text("min count"));
+                        });
+                        ui.horizontal(|ui| {
+                            if ui.button("Export transitions as CSV").clicked() {
+                                export_text(shown_visualization.lock().unwrap().transitions_to_csv(), "CSV table", "csv");
+                            }
+                            if ui.button("Export transitions as DOT").clicked() {
+                                export_text(shown_visualization.lock().unwrap().transitions_to_dot(), "GraphViz DOT graph", "dot");
+                            }
+                        });
+
+                        if self.show_transitions {
+                            for (from, to, count) in transitions {
+                                if count < self.transition_threshold {
+                                    continue;
+                                }
+
+                                let weight = (count as f32) / (max_count as f32);
+                                let stroke = Stroke::new(1.0 + 3.0 * weight, Color32::BLACK.gamma_multiply(0.3 + 0.7 * weight));
+                                if from == to {
+                                    painter.circle_stroke(cell_center(from), 0.25 * i_step.min(j_step), stroke);
                                 }
                                 else {
-                                    rects.push(Shape::Rect(RectShape::new(cur_rect, Rounding::ZERO, 
-                                        Color32::BLUE.gamma_multiply(brightness[i][j] / max_val), 
-                                        Stroke::new(1.0, Color32::BLACK))));
+                                    let start = cell_center(from);
+                                    painter.arrow(start, cell_center(to) - start, stroke);
                                 }
                             }
-
                         }
                     }
-        
-                    painter.extend(rects);
+
+                    // On top of the overlays, which stay on the main view
+                    {
+                        let locked_visualization = shown_visualization.lock().unwrap();
+                        let values = locked_visualization.cell_values();
+                        let fill = |i: usize, j: usize| values[i][j].map_or(Color32::GRAY, |value| scale.color(value));
+                        self.canvas.paint_minimap(&painter, locked_visualization.revision, locked_visualization.color_scale, fill);
+                    }
+                    self.search_results_ui(ui, &shown_visualization);
+
                     if lines_to_display.len() == 0 {
                         ui.label("No texts in the cluster");
                     }
                     else {
                         ui.label(format!("{} Texts in chosen cluster: ", lines_to_display.len()));
-                        for line in lines_to_display {
+                        if let Some(threshold) = calibration.map(|calibration| calibration.threshold) {
+                            let anomaly_count = line_scores.iter().filter(|(_, is_anomalous)| *is_anomalous).count();
+                            ui.label(format!("{anomaly_count} anomalous (threshold {threshold:.4})"));
+                            ui.checkbox(&mut self.show_only_anomalies, "Show only anomalous texts");
+                        }
+
+                        for (line, (score, is_anomalous)) in lines_to_display.iter().zip(line_scores) {
                             // println!("{:?}, {:?}", ui.available_size(), available_size);
-                            
-                            let response = ui.add(Label::new(&line).truncate(true));
+                            if self.show_only_anomalies && !is_anomalous {
+                                continue;
+                            }
+
+                            let mut text = RichText::new(line);
+                            if is_anomalous {
+                                text = text.color(Color32::RED);
+                            }
+                            let response = ui.add(Label::new(text).truncate(true));
+                            if let Some((score, percentile)) = score {
+                                response.on_hover_text(format!("Anomaly score: {score:.4} ({:.1}% of training samples score lower)", 
+                                    percentile * 100.0));
+                            }
                             // response.on_hover_text(&line); 
                             // println!("{:?}", response.rect);
                             
@@ -283,11 +1805,11 @@ impl VisualizationsUI {
 
         ScrollArea::vertical().show(ui, |ui| {
             ui.vertical_centered(|ui| {
-                if self.visualizations.len() == 0 {
+                if registry.visualizations.is_empty() {
                     ui.label("No visualizations loaded");
                     // ui.label("No datasets loaded");
                 }
-                self.visualization_list(ui);
+                removed = self.visualization_list(ui, registry);
                 
                 modal.show(|ui| {
                     modal.title(ui, "Choose the parameters for the Visualization");
@@ -308,63 +1830,123 @@ impl VisualizationsUI {
                             ui.end_row();
 
                             let mut cur_dataset_label = "".to_owned();
-                            if let Some(dataset_index) = self.chosen_dataset_index {
-                                cur_dataset_label = datasets[dataset_index].lock().unwrap().name.clone();
+                            if let Some(dataset) = self.chosen_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                                cur_dataset_label = dataset.lock().unwrap().name.clone();
                             }
                             ui.label("Dataset for evaluation:");
                             ComboBox::from_id_source("Dataset")
                             .selected_text(cur_dataset_label)
                             .show_ui(ui, |ui| {
-                                for (index, dataset) in datasets.iter().enumerate() {
+                                for (_, dataset) in datasets.iter() {
                                     let locked_dataset = dataset.lock().unwrap();
                                     if locked_dataset.is_processed() {
-                                        ui.selectable_value(&mut self.chosen_dataset_index, 
-                                            Some(index), locked_dataset.name.as_str());
+                                        ui.selectable_value(&mut self.chosen_dataset_id, 
+                                            Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
                                     }
                                 }
                             });
                             ui.end_row();
                             
                             let mut cur_map_label = "".to_owned();
-                            if let Some(map_index) = self.chosen_map_index {
-                                cur_map_label = maps[map_index].name.clone();
+                            if let Some(map) = self.chosen_map_id.as_ref().and_then(|id| find_map(maps, id)) {
+                                cur_map_label = map.name.clone();
                             }
                             ui.label("Map to evaluate:");
                             ComboBox::from_id_source("Map")
                             .selected_text(cur_map_label)
                             .show_ui(ui, |ui| {
-                                for (index, map) in maps.iter().enumerate() {
-                                    if map.map_weights.is_some() {
-                                        ui.selectable_value(&mut self.chosen_map_index, 
-                                            Some(index), map.name.as_str());
+                                // Maps being trained would hold up the calculation until the training is done
+                                for map in maps.iter() {
+                                    if map.map_weights.is_some() && !*map.is_training.lock().unwrap() {
+                                        ui.selectable_value(&mut self.chosen_map_id, 
+                                            Some(map.id.clone()), map.name.as_str());
                                     }
                                 }
                             });
                             ui.end_row();
+
+                            let kind = &mut self.current_visualization.kind;
+                            ui.label("Kind:");
+                            ComboBox::from_id_source("Kind")
+                            .selected_text(kind.name())
+                            .show_ui(ui, |ui| {
+                                for option in VisualizationKind::SIMPLE {
+                                    ui.selectable_value(kind, option, option.name());
+                                }
+                                let is_difference = matches!(kind, VisualizationKind::DatasetDifference(_));
+                                if ui.selectable_label(is_difference, "Dataset difference").clicked() && !is_difference {
+                                    *kind = VisualizationKind::DatasetDifference(DifferenceMeasure::default());
+                                }
+                            });
+                            ui.end_row();
+
+                            if let VisualizationKind::DatasetDifference(measure) = kind {
+                                let mut cur_dataset_label = "".to_owned();
+                                if let Some(dataset) = self.chosen_comparison_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                                    cur_dataset_label = dataset.lock().unwrap().name.clone();
+                                }
+                                ui.label("Dataset to compare with:");
+                                ComboBox::from_id_source("Comparison dataset")
+                                .selected_text(cur_dataset_label)
+                                .show_ui(ui, |ui| {
+                                    for (_, dataset) in datasets.iter() {
+                                        let locked_dataset = dataset.lock().unwrap();
+                                        if locked_dataset.is_processed() {
+                                            ui.selectable_value(&mut self.chosen_comparison_dataset_id, 
+                                                Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
+                                        }
+                                    }
+                                });
+                                ui.end_row();
+
+                                ui.label("Measure:");
+                                ComboBox::from_id_source("Difference measure")
+                                .selected_text(measure.name())
+                                .show_ui(ui, |ui| {
+                                    for option in DifferenceMeasure::ALL {
+                                        ui.selectable_value(measure, option, option.name());
+                                    }
+                                });
+                                ui.end_row();
+                            }
                         });
                     });
 
                     modal.buttons(ui, |ui| {
                         modal.button(ui, "Cancel");
 
-                        // ToDo: Implement Visualization creation and finally decide how to share Vecs' elements across tabs
+                        let is_difference = matches!(self.current_visualization.kind, VisualizationKind::DatasetDifference(_));
                         if modal.button(ui, "Create").clicked() {
-                            let visualization = Arc::new(Mutex::new(self.current_visualization.clone()));
-                            self.visualizations.push(visualization.clone());
-                            println!("The stuff with stuff: {:?} {:?}", self.chosen_dataset_index, self.chosen_map_index);
-
-                            let map = maps[self.chosen_map_index.unwrap()].map_weights.as_ref().unwrap().lock().unwrap().clone();
-                            let dataset = datasets[self.chosen_dataset_index.unwrap()].lock().unwrap().clone();
+                            let map = self.chosen_map_id.as_ref().and_then(|id| find_map(maps, id));
+                            let dataset = self.chosen_dataset_id.as_ref().and_then(|id| find_dataset(datasets, id));
+                            let comparison_dataset = self.chosen_comparison_dataset_id.as_ref()
+                                .filter(|_| is_difference)
+                                .and_then(|id| find_dataset(datasets, id));
+                            let (Some(map), Some(dataset)) = (map, dataset) else {
+                                println!("Choose a map and a dataset");
+                                return;
+                            };
+                            if *map.is_training.lock().unwrap() {
+                                println!("Map {} is being trained, try again once it's done", map.name);
+                                return;
+                            }
+                            if is_difference && comparison_dataset.is_none() {
+                                println!("Choose a dataset to compare with");
+                                return;
+                            }
+                            let is_labelled = dataset.lock().unwrap().labels.is_some();
+                            if self.current_visualization.kind == VisualizationKind::LabelPurity && !is_labelled {
+                                println!("Label purity needs a dataset with labels");
+                                return;
+                            }
+                            if is_difference {
+                                self.current_visualization.color_scale.color_map = ColorMap::Diverging;
+                            }
 
-                            // ToDo: Add progress tracking and maybe thread termination
-                            let handle = std::thread::spawn(move || {
-                                visualization.lock().unwrap().is_calculating = true;
-                                calculate_visualization_data(visualization.clone(), map, dataset);
+                            let visualization = Arc::new(Mutex::new(self.current_visualization.clone()));
 
-                                visualization.lock().unwrap().is_calculating = false;
-                                
-                            });
-                            self.shown_visualization_index = Some(self.visualizations.len() - 1);
+                            start_calculation(visualization.clone(), map, dataset, comparison_dataset);
+                            created = Some(visualization);
                         }
                     }); 
                 });
@@ -389,13 +1971,26 @@ impl VisualizationsUI {
                             println!("{}", res.err().unwrap());
                         }
                         else {
-                            self.visualizations.push(Arc::new(Mutex::new(res.unwrap())));
+                            loaded = res.ok();
                         }
                     }
                 }
             });
+
+            ui.separator();
+            self.playback.show(ui, maps, datasets);
+            self.projection.show(ui, maps, datasets);
         });
 
+        if let Some(id) = removed {
+            registry.remove_visualization(&id);
+        }
+        if let Some(visualization) = loaded {
+            registry.add_visualization(visualization);
+        }
+        if let Some(visualization) = created {
+            self.shown_visualization_id = Some(registry.insert_visualization(visualization));
+        }
     }
 
 }
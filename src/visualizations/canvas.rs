@@ -0,0 +1,360 @@
+use std::ops::Range;
+
+use egui::{Align2, Color32, FontId, Mesh, Painter, Pos2, Rect, Response, Rounding, Sense, Shape, Stroke, Ui, Vec2};
+
+use crate::msom::clustering::SuperClusters;
+use super::color_scale::{categorical, ColorScale};
+
+// Zoomable and pannable view of a map grid. The cells are drawn as one mesh that is only rebuilt when the
+// data, the colours or the view change, so large maps don't cost a shape per cell every frame.
+
+const MAX_ZOOM: f32 = 64.0;
+const MINIMAP_FRACTION: f32 = 0.25;
+// Below this many pixels per cell the grid lines would cover the colours
+const GRID_LINE_MIN_CELL: f32 = 4.0;
+const REGION_ALPHA: u8 = 90;
+
+// Everything the cached mesh depends on
+#[derive(Debug, Clone, PartialEq)]
+struct MeshKey {
+    revision: u64,
+    color_scale: ColorScale,
+    map_rect: Rect,
+    clip_rect: Rect,
+}
+
+#[derive(Debug)]
+pub struct MapCanvas {
+    zoom: f32,
+    // Top left corner of the view, in cells
+    offset: Vec2,
+    n: usize,
+    m: usize,
+    rect: Rect,
+    dragging_minimap: bool,
+    // Corners of the box being selected with shift + drag, in cells
+    selection_box: Option<(Vec2, Vec2)>,
+    finished_selection: Option<Vec<(usize, usize)>>,
+    mesh: Option<(MeshKey, Mesh)>,
+    minimap_mesh: Option<(MeshKey, Mesh)>,
+    regions: Option<CachedRegions>,
+}
+
+// Tinted super-clusters with their borders, and where their labels go
+#[derive(Debug)]
+struct CachedRegions {
+    clusters: SuperClusters,
+    map_rect: Rect,
+    clip_rect: Rect,
+    mesh: Mesh,
+    label_cells: Vec<(usize, usize)>,
+}
+
+impl Default for MapCanvas {
+    fn default() -> Self {
+        Self { zoom: 1.0, offset: Vec2::ZERO, n: 1, m: 1, rect: Rect::NOTHING, dragging_minimap: false,
+            selection_box: None, finished_selection: None, mesh: None, minimap_mesh: None, regions: None }
+    }
+}
+
+impl MapCanvas {
+    // Allocates the canvas and applies this frame's zooming (ctrl + scroll or pinch), panning (dragging) and
+    // box selection (shift + dragging), the response is for the caller's clicks and hovers
+    pub fn allocate(&mut self, ui: &mut Ui, n: usize, m: usize, size: Vec2) -> Response {
+        if (n, m) != (self.n, self.m) {
+            self.n = n;
+            self.m = m;
+            self.reset_view();
+        }
+
+        let response = ui.allocate_response(size, Sense::click_and_drag());
+        self.rect = response.rect;
+
+        if response.hovered() {
+            let zoom_delta = ui.input(|input| input.zoom_delta());
+            if zoom_delta != 1.0 {
+                let anchor = response.hover_pos().map_or(self.rect.center(), |pos| pos);
+                self.zoom_around(anchor, self.zoom * zoom_delta);
+            }
+        }
+
+        if response.drag_started() {
+            let pos = response.interact_pointer_pos();
+            self.dragging_minimap = pos.is_some_and(|pos| self.is_over_minimap(pos));
+            let shift = ui.input(|input| input.modifiers.shift);
+            self.selection_box = pos.filter(|_| shift && !self.dragging_minimap)
+                .map(|pos| (self.to_cells(pos), self.to_cells(pos)));
+        }
+        if response.dragged() {
+            match (response.interact_pointer_pos(), self.selection_box) {
+                (Some(pos), _) if self.dragging_minimap => self.center_on_minimap(pos),
+                (Some(pos), Some((start, _))) => self.selection_box = Some((start, self.to_cells(pos))),
+                _ => self.pan(response.drag_delta()),
+            }
+        }
+        if response.clicked() {
+            if let Some(pos) = response.interact_pointer_pos().filter(|pos| self.is_over_minimap(*pos)) {
+                self.center_on_minimap(pos);
+            }
+        }
+        if response.drag_released() {
+            self.dragging_minimap = false;
+            if let Some((start, end)) = self.selection_box.take() {
+                self.finished_selection = Some(self.cells_between(start, end));
+            }
+        }
+
+        response
+    }
+
+    // Cells of a box selection finished this frame
+    pub fn take_selection(&mut self) -> Option<Vec<(usize, usize)>> {
+        self.finished_selection.take()
+    }
+
+    pub fn paint_selection_box(&self, painter: &Painter) {
+        if let Some((start, end)) = self.selection_box {
+            let rect = Rect::from_two_pos(self.to_screen(start), self.to_screen(end));
+            painter.rect(rect, Rounding::ZERO, Color32::from_rgba_unmultiplied(255, 0, 0, 40), Stroke::new(1.0, Color32::RED));
+        }
+    }
+
+    pub fn zoom(&self) -> f32 {
+        self.zoom
+    }
+
+    pub fn set_zoom(&mut self, zoom: f32) {
+        self.zoom_around(self.rect.center(), zoom);
+    }
+
+    pub fn reset_view(&mut self) {
+        self.zoom = 1.0;
+        self.offset = Vec2::ZERO;
+    }
+
+    // Screen size of a single cell
+    pub fn cell_size(&self) -> Vec2 {
+        Vec2 { x: self.rect.width() / self.n as f32, y: self.rect.height() / self.m as f32 } * self.zoom
+    }
+
+    pub fn cell_rect(&self, cell: (usize, usize)) -> Rect {
+        Rect::from_min_size(self.to_screen(Vec2 { x: cell.0 as f32, y: cell.1 as f32 }), self.cell_size())
+    }
+
+    pub fn cell_center(&self, cell: (usize, usize)) -> Pos2 {
+        self.to_screen(Vec2 { x: cell.0 as f32 + 0.5, y: cell.1 as f32 + 0.5 })
+    }
+
+    // None outside the map and over the minimap, which has its own clicks
+    pub fn cell_at(&self, pos: Pos2) -> Option<(usize, usize)> {
+        if !self.rect.contains(pos) || self.is_over_minimap(pos) {
+            return None;
+        }
+
+        let cell = self.to_cells(pos);
+        let (i, j) = (cell.x.floor(), cell.y.floor());
+        if i < 0.0 || j < 0.0 || i >= self.n as f32 || j >= self.m as f32 {
+            return None;
+        }
+        Some((i as usize, j as usize))
+    }
+
+    // Ranges of the cells at least partially in view
+    pub fn visible_cells(&self) -> (Range<usize>, Range<usize>) {
+        visible_range(self.map_rect(), self.rect, self.n, self.m)
+    }
+
+    pub fn painter(&self, ui: &Ui) -> Painter {
+        ui.painter_at(self.rect)
+    }
+
+    // `revision` changes whenever the values behind `fill` do
+    pub fn paint_cells(&mut self, painter: &Painter, revision: u64, color_scale: ColorScale, fill: impl Fn(usize, usize) -> Color32) {
+        let key = MeshKey { revision, color_scale, map_rect: self.map_rect(), clip_rect: self.rect };
+        if self.mesh.as_ref().map(|(cached_key, _)| cached_key) != Some(&key) {
+            self.mesh = Some((key.clone(), cells_mesh(key.map_rect, key.clip_rect, self.n, self.m, &fill, true)));
+        }
+
+        if let Some((_, mesh)) = &self.mesh {
+            painter.add(Shape::mesh(mesh.clone()));
+        }
+    }
+
+    // Every super-cluster in its own translucent colour, outlined and labelled with its number
+    pub fn paint_super_clusters(&mut self, painter: &Painter, clusters: &SuperClusters) {
+        let (map_rect, clip_rect) = (self.map_rect(), self.rect);
+        let is_cached = matches!(&self.regions, Some(cached) 
+            if cached.clusters == *clusters && cached.map_rect == map_rect && cached.clip_rect == clip_rect);
+        if !is_cached {
+            let fill = |i: usize, j: usize| {
+                let color = categorical(clusters.labels[(i, j)]);
+                Color32::from_rgba_unmultiplied(color.r(), color.g(), color.b(), REGION_ALPHA)
+            };
+            let mut mesh = cells_mesh(map_rect, clip_rect, self.n, self.m, &fill, false);
+
+            let (i_range, j_range) = visible_range(map_rect, clip_rect, self.n, self.m);
+            let cell = self.cell_size();
+            for i in i_range {
+                for j in j_range.clone() {
+                    let min = map_rect.min + Vec2 { x: i as f32, y: j as f32 } * cell;
+                    if i + 1 < self.n && clusters.labels[(i, j)] != clusters.labels[(i + 1, j)] {
+                        let x = min.x + cell.x;
+                        mesh.add_colored_rect(Rect::from_min_max(Pos2 { x: x - 1.5, y: min.y }, Pos2 { x: x + 1.5, y: min.y + cell.y }), Color32::BLACK);
+                    }
+                    if j + 1 < self.m && clusters.labels[(i, j)] != clusters.labels[(i, j + 1)] {
+                        let y = min.y + cell.y;
+                        mesh.add_colored_rect(Rect::from_min_max(Pos2 { x: min.x, y: y - 1.5 }, Pos2 { x: min.x + cell.x, y: y + 1.5 }), Color32::BLACK);
+                    }
+                }
+            }
+
+            let label_cells = match &self.regions {
+                Some(cached) if cached.clusters == *clusters => cached.label_cells.clone(),
+                _ => clusters.label_cells(),
+            };
+            self.regions = Some(CachedRegions { clusters: clusters.clone(), map_rect, clip_rect, mesh, label_cells });
+        }
+
+        if let Some(cached) = &self.regions {
+            painter.add(Shape::mesh(cached.mesh.clone()));
+            for (label, cell) in cached.label_cells.iter().enumerate() {
+                let text = painter.layout_no_wrap(label.to_string(), FontId::proportional(14.0), Color32::BLACK);
+                let rect = Align2::CENTER_CENTER.anchor_rect(Rect::from_min_size(self.cell_center(*cell), text.size()));
+                painter.rect_filled(rect.expand(2.0), Rounding::same(3.0), categorical(label).gamma_multiply(0.8));
+                painter.galley(rect.min, text, Color32::BLACK);
+            }
+        }
+    }
+
+    // Overview of the whole map with the visible part outlined, only while zoomed in
+    pub fn paint_minimap(&mut self, painter: &Painter, revision: u64, color_scale: ColorScale, fill: impl Fn(usize, usize) -> Color32) {
+        let Some(minimap_rect) = self.minimap_rect() else {
+            return;
+        };
+
+        let key = MeshKey { revision, color_scale, map_rect: minimap_rect, clip_rect: minimap_rect };
+        if self.minimap_mesh.as_ref().map(|(cached_key, _)| cached_key) != Some(&key) {
+            self.minimap_mesh = Some((key.clone(), cells_mesh(minimap_rect, minimap_rect, self.n, self.m, &fill, true)));
+        }
+
+        painter.rect_filled(minimap_rect.expand(2.0), Rounding::ZERO, Color32::WHITE);
+        if let Some((_, mesh)) = &self.minimap_mesh {
+            painter.add(Shape::mesh(mesh.clone()));
+        }
+        painter.rect_stroke(minimap_rect.expand(2.0), Rounding::ZERO, Stroke::new(1.0, Color32::BLACK));
+
+        let to_minimap = |cells: Vec2| minimap_rect.min + cells * minimap_rect.size() / Vec2 { x: self.n as f32, y: self.m as f32 };
+        let view = Rect::from_min_max(to_minimap(self.offset), to_minimap(self.offset + self.view_size()));
+        painter.rect_stroke(view, Rounding::ZERO, Stroke::new(2.0, Color32::RED));
+    }
+
+    fn cells_between(&self, start: Vec2, end: Vec2) -> Vec<(usize, usize)> {
+        let to_index = |value: f32, size: usize| (value.floor().max(0.0) as usize).min(size - 1);
+        let (min, max) = (start.min(end), start.max(end));
+
+        let mut cells = vec![];
+        for i in to_index(min.x, self.n)..=to_index(max.x, self.n) {
+            for j in to_index(min.y, self.m)..=to_index(max.y, self.m) {
+                cells.push((i, j));
+            }
+        }
+        cells
+    }
+
+    fn is_over_minimap(&self, pos: Pos2) -> bool {
+        self.minimap_rect().is_some_and(|rect| rect.expand(2.0).contains(pos))
+    }
+
+    fn minimap_rect(&self) -> Option<Rect> {
+        if self.zoom <= 1.0 {
+            return None;
+        }
+
+        let size = self.rect.size() * MINIMAP_FRACTION;
+        Some(Rect::from_min_size(self.rect.max - size - Vec2::splat(4.0), size))
+    }
+
+    fn center_on_minimap(&mut self, pos: Pos2) {
+        if let Some(minimap_rect) = self.minimap_rect() {
+            let cells = (pos - minimap_rect.min) / minimap_rect.size() * Vec2 { x: self.n as f32, y: self.m as f32 };
+            self.offset = cells - self.view_size() / 2.0;
+            self.clamp_offset();
+        }
+    }
+
+    fn pan(&mut self, delta: Vec2) {
+        self.offset -= delta / self.cell_size();
+        self.clamp_offset();
+    }
+
+    // Keeps the map point under `anchor` in place
+    fn zoom_around(&mut self, anchor: Pos2, zoom: f32) {
+        let fixed = self.to_cells(anchor);
+        let zoom = zoom.clamp(1.0, MAX_ZOOM);
+        self.offset = fixed - (fixed - self.offset) * self.zoom / zoom;
+        self.zoom = zoom;
+        self.clamp_offset();
+    }
+
+    // How many cells fit the canvas at the current zoom
+    fn view_size(&self) -> Vec2 {
+        Vec2 { x: self.n as f32, y: self.m as f32 } / self.zoom
+    }
+
+    fn clamp_offset(&mut self) {
+        let max = Vec2 { x: self.n as f32, y: self.m as f32 } - self.view_size();
+        self.offset = self.offset.clamp(Vec2::ZERO, max.max(Vec2::ZERO));
+    }
+
+    // Where the whole map would be on the screen
+    fn map_rect(&self) -> Rect {
+        Rect::from_min_size(self.to_screen(Vec2::ZERO), self.cell_size() * Vec2 { x: self.n as f32, y: self.m as f32 })
+    }
+
+    fn to_screen(&self, cells: Vec2) -> Pos2 {
+        self.rect.min + (cells - self.offset) * self.cell_size()
+    }
+
+    fn to_cells(&self, pos: Pos2) -> Vec2 {
+        self.offset + (pos - self.rect.min) / self.cell_size()
+    }
+}
+
+fn visible_range(map_rect: Rect, clip_rect: Rect, n: usize, m: usize) -> (Range<usize>, Range<usize>) {
+    let cell = map_rect.size() / Vec2 { x: n as f32, y: m as f32 };
+    let first = ((clip_rect.min - map_rect.min) / cell).floor().max(Vec2::ZERO);
+    let last = ((clip_rect.max - map_rect.min) / cell).ceil();
+    let i_range = first.x as usize..(last.x.max(0.0) as usize).min(n);
+    let j_range = first.y as usize..(last.y.max(0.0) as usize).min(m);
+    (i_range, j_range)
+}
+
+// Filled cells with the grid on top, only the cells inside `clip_rect`
+fn cells_mesh(map_rect: Rect, clip_rect: Rect, n: usize, m: usize, fill: &dyn Fn(usize, usize) -> Color32, grid: bool) -> Mesh {
+    let mut mesh = Mesh::default();
+    let cell = map_rect.size() / Vec2 { x: n as f32, y: m as f32 };
+    let (i_range, j_range) = visible_range(map_rect, clip_rect, n, m);
+
+    for i in i_range.clone() {
+        for j in j_range.clone() {
+            let rect = Rect::from_min_size(map_rect.min + Vec2 { x: i as f32, y: j as f32 } * cell, cell);
+            mesh.add_colored_rect(rect.intersect(clip_rect), fill(i, j));
+        }
+    }
+
+    if grid && cell.min_elem() >= GRID_LINE_MIN_CELL {
+        let visible = map_rect.intersect(clip_rect);
+        for i in i_range.start..=i_range.end {
+            let x = map_rect.min.x + i as f32 * cell.x;
+            let line = Rect::from_min_max(Pos2 { x: x - 0.5, y: visible.min.y }, Pos2 { x: x + 0.5, y: visible.max.y });
+            mesh.add_colored_rect(line, Color32::BLACK);
+        }
+        for j in j_range.start..=j_range.end {
+            let y = map_rect.min.y + j as f32 * cell.y;
+            let line = Rect::from_min_max(Pos2 { x: visible.min.x, y: y - 0.5 }, Pos2 { x: visible.max.x, y: y + 0.5 });
+            mesh.add_colored_rect(line, Color32::BLACK);
+        }
+    }
+
+    mesh
+}
@@ -0,0 +1,186 @@
+use egui::Color32;
+use serde::{Deserialize, Serialize};
+
+// How cell values turn into colours, shared by the painter and the image export
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ColorMap {
+    // White to blue, how the maps were always drawn
+    #[default]
+    Blues,
+    Viridis,
+    Magma,
+    // Blue over white to red, for values around a meaningful middle
+    Diverging,
+}
+
+impl ColorMap {
+    pub const ALL: [ColorMap; 4] = [ColorMap::Blues, ColorMap::Viridis, ColorMap::Magma, ColorMap::Diverging];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorMap::Blues => "Blues",
+            ColorMap::Viridis => "Viridis",
+            ColorMap::Magma => "Magma",
+            ColorMap::Diverging => "Diverging",
+        }
+    }
+
+    // `t` in 0..=1
+    pub fn color(&self, t: f32) -> Color32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            ColorMap::Blues => lerp([255.0, 255.0, 255.0], [0.0, 0.0, 255.0], t),
+            ColorMap::Viridis => polynomial(&VIRIDIS, t),
+            ColorMap::Magma => polynomial(&MAGMA, t),
+            ColorMap::Diverging if t < 0.5 => lerp([59.0, 76.0, 192.0], [221.0, 221.0, 221.0], 2.0 * t),
+            ColorMap::Diverging => lerp([221.0, 221.0, 221.0], [180.0, 4.0, 38.0], 2.0 * t - 1.0),
+        }
+    }
+}
+
+// Polynomial fits of matplotlib's maps, lowest order first
+const VIRIDIS: [[f32; 3]; 7] = [
+    [0.277_727_33, 0.005_407_344_5, 0.334_099_8],
+    [0.105_093_04, 1.404_613_5, 1.384_590_2],
+    [-0.330_861_83, 0.214_847_56, 0.095_095_16],
+    [-4.634_230_5, -5.799_101, -19.332_441],
+    [6.228_27, 14.179_933, 56.690_55],
+    [4.776_385, -13.745_145, -65.353_03],
+    [-5.435_456, 4.645_852_6, 26.312_435],
+];
+
+const MAGMA: [[f32; 3]; 7] = [
+    [-0.002_136_485, -0.000_749_655, -0.005_386_128],
+    [0.251_660_54, 0.677_523_24, 2.494_026_6],
+    [8.353_717, -3.577_719_5, 0.314_467_9],
+    [-27.668_733, 14.264_731, -13.649_213],
+    [52.176_14, -27.943_606, 12.944_169],
+    [-50.768_525, 29.046_583, 4.234_153],
+    [18.655_705, -11.489_774, -5.601_961_5],
+];
+
+fn polynomial(coefficients: &[[f32; 3]; 7], t: f32) -> Color32 {
+    let mut rgb = [0.0; 3];
+    for (power, coefficient) in coefficients.iter().enumerate() {
+        for channel in 0..3 {
+            rgb[channel] += coefficient[channel] * t.powi(power as i32);
+        }
+    }
+
+    let [r, g, b] = rgb.map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8);
+    Color32::from_rgb(r, g, b)
+}
+
+// Tableau 10, for things that have no order like clusters
+const CATEGORICAL: [[u8; 3]; 10] = [
+    [31, 119, 180], [255, 127, 14], [44, 160, 44], [214, 39, 40], [148, 103, 189],
+    [140, 86, 75], [227, 119, 194], [127, 127, 127], [188, 189, 34], [23, 190, 207],
+];
+
+pub fn categorical(index: usize) -> Color32 {
+    let [r, g, b] = CATEGORICAL[index % CATEGORICAL.len()];
+    Color32::from_rgb(r, g, b)
+}
+
+// Black or white, whichever reads better on top of `fill`
+pub fn text_color(fill: Color32) -> Color32 {
+    if fill.r() as f32 * 0.3 + fill.g() as f32 * 0.6 + fill.b() as f32 * 0.1 < 110.0 { Color32::WHITE } else { Color32::BLACK }
+}
+
+fn lerp(from: [f32; 3], to: [f32; 3], t: f32) -> Color32 {
+    let [r, g, b] = [0, 1, 2].map(|channel| (from[channel] + (to[channel] - from[channel]) * t).round() as u8);
+    Color32::from_rgb(r, g, b)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Scaling {
+    #[default]
+    Linear,
+    // log(1 + value), negative values are clamped to zero
+    Log,
+    // Position among the sorted values, evens out skewed distributions
+    Rank,
+}
+
+impl Scaling {
+    pub const ALL: [Scaling; 3] = [Scaling::Linear, Scaling::Log, Scaling::Rank];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scaling::Linear => "Linear",
+            Scaling::Log => "Log",
+            Scaling::Rank => "Rank",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColorScale {
+    pub color_map: ColorMap,
+    pub scaling: Scaling,
+    // None takes the range of the shown values
+    pub fixed_range: Option<(f32, f32)>,
+}
+
+impl ColorScale {
+    pub fn fit<'a>(&self, values: impl IntoIterator<Item = &'a f32>) -> FittedScale {
+        let mut sorted: Vec<f32> = values.into_iter().copied().filter(|value| value.is_finite()).collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+
+        let (min, max) = self.fixed_range
+            .unwrap_or((sorted.first().copied().unwrap_or(0.0), sorted.last().copied().unwrap_or(1.0)));
+        let (min, max) = if min <= max { (min, max) } else { (max, min) };
+        FittedScale { scale: *self, min, max, sorted }
+    }
+}
+
+// A scale together with the values it is applied to
+#[derive(Debug, Clone)]
+pub struct FittedScale {
+    scale: ColorScale,
+    pub min: f32,
+    pub max: f32,
+    sorted: Vec<f32>,
+}
+
+impl FittedScale {
+    pub fn normalize(&self, value: f32) -> f32 {
+        let value = value.clamp(self.min, self.max);
+        let t = match self.scale.scaling {
+            Scaling::Linear => (value - self.min) / (self.max - self.min),
+            Scaling::Log => {
+                let log = |value: f32| value.max(0.0).ln_1p();
+                (log(value) - log(self.min)) / (log(self.max) - log(self.min))
+            }
+            Scaling::Rank => {
+                let rank = self.sorted.partition_point(|sorted| *sorted < value);
+                rank as f32 / (self.sorted.len() as f32 - 1.0)
+            }
+        };
+
+        // All values equal
+        if t.is_finite() { t } else { 1.0 }
+    }
+
+    pub fn color(&self, value: f32) -> Color32 {
+        self.scale.color_map.color(self.normalize(value))
+    }
+
+    // Inverse of normalize, for the ticks of a colour bar
+    pub fn value_at(&self, t: f32) -> f32 {
+        match self.scale.scaling {
+            Scaling::Linear => self.min + t * (self.max - self.min),
+            Scaling::Log => {
+                let (low, high) = (self.min.max(0.0).ln_1p(), self.max.max(0.0).ln_1p());
+                (low + t * (high - low)).exp_m1()
+            }
+            Scaling::Rank if self.sorted.is_empty() => self.min,
+            Scaling::Rank => self.sorted[(t * (self.sorted.len() - 1) as f32).round() as usize],
+        }
+    }
+
+    pub fn color_map(&self) -> ColorMap {
+        self.scale.color_map
+    }
+}
@@ -0,0 +1,287 @@
+use std::{path::Path, sync::{Arc, Mutex}};
+
+use egui::{CollapsingHeader, Color32, ComboBox, DragValue, Grid, Slider, Ui, Vec2};
+use ndarray::{Array2, Axis};
+use rfd::FileDialog;
+use tqdm::tqdm;
+
+use crate::{data_processing::DataSet, msom::{history::TrainingSnapshot, MSOM}, registry::{find_dataset, find_map}, SOMParams};
+
+use super::{canvas::MapCanvas, next_revision, render::{self, Annotation, RenderOptions}, Visualization};
+
+// How a map changed while it was fitted, one frame per training snapshot
+
+const PLAYBACK_SIZE: f32 = 400.0;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PlaybackLayer {
+    #[default]
+    Hits,
+    UMatrix,
+    // Weights of one input dimension across the som
+    ComponentPlane(usize),
+}
+
+impl PlaybackLayer {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlaybackLayer::Hits => "Hit map",
+            PlaybackLayer::UMatrix => "U-matrix",
+            PlaybackLayer::ComponentPlane(_) => "Component plane",
+        }
+    }
+}
+
+fn layer_values(map: &MSOM, layer: PlaybackLayer, samples: &[ndarray::ArrayView1<f32>]) -> Vec<Vec<f32>> {
+    let to_rows = |values: Array2<f32>| values.rows().into_iter().map(|row| row.to_vec()).collect();
+    match layer {
+        PlaybackLayer::Hits => {
+            let mut hits = vec![vec![0.0; map.m]; map.n];
+            for sample in samples {
+                if let Some((i, j)) = map.evaluate(*sample) {
+                    hits[i][j] += 1.0;
+                }
+            }
+            hits
+        }
+        PlaybackLayer::UMatrix => to_rows(map.u_matrix()),
+        PlaybackLayer::ComponentPlane(component) => to_rows(map.som().index_axis(Axis(2), component).to_owned()),
+    }
+}
+
+// One visualization per snapshot, all with the same colour range so that the frames can be compared
+pub fn calculate_frames(map: MSOM, snapshots: Vec<TrainingSnapshot>, layer: PlaybackLayer, dataset: Option<DataSet>,
+    frames: Arc<Mutex<Vec<Visualization>>>) {
+    let samples = dataset.as_ref()
+        .and_then(|dataset| dataset.processed_data.as_ref())
+        .map(|samples| samples.views())
+        .unwrap_or_default();
+
+    for snapshot in tqdm(snapshots.iter()) {
+        let data = layer_values(&snapshot.to_map(&map), layer, &samples);
+        let name = format!("{} at iteration {}", layer.name(), snapshot.iteration);
+        frames.lock().unwrap().push(Visualization { name, data, ..Default::default() });
+    }
+
+    let mut frames = frames.lock().unwrap();
+    let values: Vec<f32> = frames.iter().flat_map(|frame| frame.cell_values().into_iter().flatten().flatten()).collect();
+    let range = values.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), value| (min.min(*value), max.max(*value)));
+    if range.0 <= range.1 {
+        for frame in frames.iter_mut() {
+            frame.color_scale.fixed_range = Some(range);
+            frame.revision = next_revision();
+        }
+    }
+}
+
+// Written as frame_0000.png, frame_0001.png... in `directory`
+pub fn export_frames(frames: &[Visualization], directory: &Path) -> Result<(), String> {
+    for (index, frame) in frames.iter().enumerate() {
+        let options = RenderOptions { title: Some(frame.name.clone()), annotation: Annotation::None, ..Default::default() };
+        render::render_to_file(frame, &options, &directory.join(format!("frame_{index:04}.png")))?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct PlaybackUI {
+    map_id: Option<String>,
+    dataset_id: Option<String>,
+    layer: PlaybackLayer,
+    frames: Arc<Mutex<Vec<Visualization>>>,
+    is_calculating: Arc<Mutex<bool>>,
+    frame: usize,
+    is_playing: bool,
+    frames_per_second: f32,
+    // When the shown frame came up, in seconds of UI time
+    frame_started: f64,
+    canvas: MapCanvas,
+}
+
+impl Default for PlaybackUI {
+    fn default() -> Self {
+        Self { map_id: None, dataset_id: None, layer: PlaybackLayer::Hits, frames: Arc::new(Mutex::new(vec![])),
+            is_calculating: Arc::new(Mutex::new(false)), frame: 0, is_playing: false, frames_per_second: 4.0,
+            frame_started: 0.0, canvas: MapCanvas::default() }
+    }
+}
+
+impl PlaybackUI {
+    pub fn show(&mut self, ui: &mut Ui, maps: &[SOMParams], datasets: &[(String, Arc<Mutex<DataSet>>)]) {
+        CollapsingHeader::new("Training playback").show(ui, |ui| {
+            self.settings_ui(ui, maps, datasets);
+
+            let frame_count = self.frames.lock().unwrap().len();
+            if frame_count == 0 {
+                ui.label("Set snapshot_every on a map and fit it to record its training");
+                return;
+            }
+            self.frame = self.frame.min(frame_count - 1);
+            if *self.is_calculating.lock().unwrap() {
+                self.is_playing = false;
+            }
+
+            ui.horizontal(|ui| {
+                let label = if self.is_playing { "Pause" } else { "Play" };
+                if ui.button(label).clicked() {
+                    self.is_playing = !self.is_playing;
+                    if self.frame + 1 == frame_count {
+                        self.frame = 0;
+                    }
+                    self.frame_started = ui.input(|input| input.time);
+                }
+                ui.add(Slider::new(&mut self.frame, 0..=frame_count - 1).text("frame"));
+                ui.add(DragValue::new(&mut self.frames_per_second).clamp_range(0.5..=30.0).suffix(" fps"));
+            });
+
+            if self.is_playing {
+                let now = ui.input(|input| input.time);
+                let frame_time = 1.0 / self.frames_per_second as f64;
+                if now - self.frame_started >= frame_time {
+                    self.frame_started = now;
+                    self.frame += 1;
+                    if self.frame + 1 >= frame_count {
+                        self.frame = frame_count - 1;
+                        self.is_playing = false;
+                    }
+                }
+                ui.ctx().request_repaint_after(std::time::Duration::from_secs_f64(frame_time));
+            }
+
+            let frames = self.frames.clone();
+            let frames = frames.lock().unwrap();
+            let frame = &frames[self.frame];
+            ui.label(&frame.name);
+
+            let (n, m) = (frame.data.len(), frame.data.first().map_or(0, |column| column.len()));
+            let side = ui.available_width().min(PLAYBACK_SIZE);
+            let response = self.canvas.allocate(ui, n, m, Vec2 { x: side, y: side });
+            if response.double_clicked() {
+                self.canvas.reset_view();
+            }
+            let painter = self.canvas.painter(ui);
+            let scale = frame.fitted_scale();
+            let values = frame.cell_values();
+            let fill = |i: usize, j: usize| values[i][j].map_or(Color32::GRAY, |value| scale.color(value));
+            self.canvas.paint_cells(&painter, frame.revision, frame.color_scale, fill);
+            self.canvas.paint_minimap(&painter, frame.revision, frame.color_scale, fill);
+            if let Some((i, j)) = response.hover_pos().and_then(|pos| self.canvas.cell_at(pos)) {
+                response.on_hover_text_at_pointer(format!("Cell ({i}, {j}): {:.4}", frame.data[i][j]));
+            }
+            ui.label(format!("Range: {:.4} to {:.4}", scale.min, scale.max));
+
+            if ui.button("Export as image sequence").clicked() {
+                if let Some(directory) = FileDialog::new().set_directory(".").pick_folder() {
+                    let res = export_frames(&frames, &directory);
+                    if res.is_err() {
+                        println!("{}", res.err().unwrap());
+                    }
+                }
+            }
+        });
+    }
+
+    fn settings_ui(&mut self, ui: &mut Ui, maps: &[SOMParams], datasets: &[(String, Arc<Mutex<DataSet>>)]) {
+        let recorded_map = self.map_id.as_ref()
+            .and_then(|id| find_map(maps, id))
+            .filter(|map| map.map_weights.is_some() && !map.snapshots.lock().unwrap().is_empty() && !*map.is_training.lock().unwrap());
+        if recorded_map.is_none() {
+            self.map_id = None;
+        }
+        // The component could have been picked for another map with more inputs
+        if let (PlaybackLayer::ComponentPlane(component), Some(map)) = (&mut self.layer, recorded_map) {
+            *component = (*component).min(map.map_input_size.saturating_sub(1));
+        }
+        if !self.dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)).is_some_and(|dataset| dataset.lock().unwrap().is_processed()) {
+            self.dataset_id = None;
+        }
+
+        Grid::new("Playback settings").show(ui, |ui| {
+            ui.label("Map:");
+            ComboBox::from_id_source("Playback map")
+            .selected_text(recorded_map.map_or("", |map| map.name.as_str()))
+            .show_ui(ui, |ui| {
+                for map in maps.iter() {
+                    let snapshot_count = map.snapshots.lock().unwrap().len();
+                    if map.map_weights.is_some() && snapshot_count != 0 && !*map.is_training.lock().unwrap() {
+                        ui.selectable_value(&mut self.map_id, Some(map.id.clone()), format!("{} ({snapshot_count} snapshots)", map.name));
+                    }
+                }
+            });
+            ui.end_row();
+
+            ui.label("Layer:");
+            ComboBox::from_id_source("Playback layer")
+            .selected_text(self.layer.name())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.layer, PlaybackLayer::Hits, PlaybackLayer::Hits.name());
+                ui.selectable_value(&mut self.layer, PlaybackLayer::UMatrix, PlaybackLayer::UMatrix.name());
+                let is_plane = matches!(self.layer, PlaybackLayer::ComponentPlane(_));
+                if ui.selectable_label(is_plane, PlaybackLayer::ComponentPlane(0).name()).clicked() && !is_plane {
+                    self.layer = PlaybackLayer::ComponentPlane(0);
+                }
+            });
+            ui.end_row();
+
+            if let PlaybackLayer::ComponentPlane(component) = &mut self.layer {
+                let input_size = recorded_map.map_or(1, |map| map.map_input_size);
+                ui.label("Component:");
+                ui.add(DragValue::new(component).clamp_range(0..=input_size.saturating_sub(1)));
+                ui.end_row();
+            }
+
+            if self.layer == PlaybackLayer::Hits {
+                let mut cur_dataset_label = "".to_owned();
+                if let Some(dataset) = self.dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                    cur_dataset_label = dataset.lock().unwrap().name.clone();
+                }
+                ui.label("Dataset for the hits:");
+                ComboBox::from_id_source("Playback dataset")
+                .selected_text(cur_dataset_label)
+                .show_ui(ui, |ui| {
+                    for (_, dataset) in datasets.iter() {
+                        let locked_dataset = dataset.lock().unwrap();
+                        if locked_dataset.is_processed() {
+                            ui.selectable_value(&mut self.dataset_id, Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
+                        }
+                    }
+                });
+                ui.end_row();
+            }
+        });
+
+        ui.horizontal(|ui| {
+            let is_calculating = *self.is_calculating.lock().unwrap();
+            let can_compute = recorded_map.is_some() && (self.layer != PlaybackLayer::Hits || self.dataset_id.is_some());
+            if ui.add_enabled(can_compute && !is_calculating, egui::Button::new("Compute frames")).clicked() {
+                let map = recorded_map.unwrap();
+                let weights = map.map_weights.as_ref().unwrap().lock().unwrap().clone();
+                let snapshots = map.snapshots.lock().unwrap().clone();
+                let dataset = self.dataset_id.as_ref()
+                    .filter(|_| self.layer == PlaybackLayer::Hits)
+                    .and_then(|id| find_dataset(datasets, id))
+                    .map(|dataset| dataset.lock().unwrap().clone());
+                // map_input_size is only a parameter, the weights are what the frames index into
+                let layer = match self.layer {
+                    PlaybackLayer::ComponentPlane(component) => PlaybackLayer::ComponentPlane(component.min(weights.som().shape()[2].saturating_sub(1))),
+                    layer => layer,
+                };
+
+                // A new list, so that a calculation still running for older settings can't mix its frames in
+                self.frames = Arc::new(Mutex::new(vec![]));
+                self.frame = 0;
+                self.is_playing = false;
+                let cloned_frames = self.frames.clone();
+                let cloned_status = self.is_calculating.clone();
+                *cloned_status.lock().unwrap() = true;
+                std::thread::spawn(move || {
+                    calculate_frames(weights, snapshots, layer, dataset, cloned_frames);
+                    *cloned_status.lock().unwrap() = false;
+                });
+            }
+            if is_calculating {
+                ui.spinner();
+            }
+        });
+    }
+}
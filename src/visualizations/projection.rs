@@ -0,0 +1,256 @@
+use std::sync::{Arc, Mutex};
+
+use egui::{CollapsingHeader, Color32, ComboBox, Grid, Rounding, Sense, Shape, Stroke, Ui, Vec2};
+use ndarray::s;
+
+use crate::{data_processing::DataSet, msom::{projection::{Projection, ProjectionMethod}, Topology, MSOM}, registry::{find_dataset, find_map}, SOMParams};
+
+// Prototypes laid out in input space, with lines between grid neighbours so that folds and twists show
+
+const PROJECTION_SIZE: f32 = 400.0;
+// Inputs drawn at most, taken evenly from all chunks of the dataset
+const MAX_PROJECTED_INPUTS: usize = 2000;
+const HOVER_DISTANCE: f32 = 8.0;
+
+// Everything needed to draw, points padded to 3 coordinates
+#[derive(Debug, Clone)]
+pub struct ProjectedMap {
+    pub n: usize,
+    pub m: usize,
+    pub topology: Topology,
+    pub dimensions: usize,
+    pub prototypes: Vec<[f32; 3]>,
+    pub inputs: Vec<[f32; 3]>,
+}
+
+fn padded(point: ndarray::ArrayView1<f32>) -> [f32; 3] {
+    let mut padded = [0.0; 3];
+    for (coordinate, value) in padded.iter_mut().zip(point) {
+        *coordinate = *value;
+    }
+    padded
+}
+
+pub fn calculate_projection(map: &MSOM, method: ProjectionMethod, dimensions: usize, dataset: Option<DataSet>) -> ProjectedMap {
+    let projection = Projection::new(map, method, dimensions);
+    let prototypes = projection.points.rows().into_iter().map(padded).collect();
+
+    // Every chunk of a sample is one input of the som
+    let samples = dataset.as_ref()
+        .and_then(|dataset| dataset.processed_data.as_ref())
+        .map(|samples| samples.views())
+        .unwrap_or_default();
+    let chunks: Vec<_> = samples.iter()
+        .flat_map(|sample| (0..sample.len() / map.map_input_size)
+            .map(move |chunk| sample.slice(s![chunk * map.map_input_size..(chunk + 1) * map.map_input_size])))
+        .collect();
+    let stride = chunks.len().div_ceil(MAX_PROJECTED_INPUTS).max(1);
+    let inputs = chunks.iter().step_by(stride).map(|chunk| padded(projection.project(*chunk).view())).collect();
+
+    ProjectedMap { n: map.n, m: map.m, topology: map.topology, dimensions, prototypes, inputs }
+}
+
+#[derive(Debug)]
+pub struct ProjectionUI {
+    map_id: Option<String>,
+    dataset_id: Option<String>,
+    method: ProjectionMethod,
+    dimensions: usize,
+    show_inputs: bool,
+    projected: Arc<Mutex<Option<ProjectedMap>>>,
+    is_calculating: Arc<Mutex<bool>>,
+    // Rotation of the 3D view in radians, around the vertical then the horizontal axis
+    yaw: f32,
+    pitch: f32,
+}
+
+impl Default for ProjectionUI {
+    fn default() -> Self {
+        Self { map_id: None, dataset_id: None, method: ProjectionMethod::Pca, dimensions: 2, show_inputs: false,
+            projected: Arc::new(Mutex::new(None)), is_calculating: Arc::new(Mutex::new(false)), yaw: 0.5, pitch: 0.3 }
+    }
+}
+
+impl ProjectionUI {
+    pub fn show(&mut self, ui: &mut Ui, maps: &[SOMParams], datasets: &[(String, Arc<Mutex<DataSet>>)]) {
+        CollapsingHeader::new("Prototype projection").show(ui, |ui| {
+            self.settings_ui(ui, maps, datasets);
+
+            let projected = self.projected.lock().unwrap().clone();
+            if let Some(projected) = projected {
+                self.projection_ui(ui, &projected);
+            }
+        });
+    }
+
+    fn settings_ui(&mut self, ui: &mut Ui, maps: &[SOMParams], datasets: &[(String, Arc<Mutex<DataSet>>)]) {
+        let chosen_map = self.map_id.as_ref()
+            .and_then(|id| find_map(maps, id))
+            .filter(|map| map.map_weights.is_some() && !*map.is_training.lock().unwrap());
+        if chosen_map.is_none() {
+            self.map_id = None;
+        }
+        if !self.dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)).is_some_and(|dataset| dataset.lock().unwrap().is_processed()) {
+            self.dataset_id = None;
+        }
+
+        Grid::new("Projection settings").show(ui, |ui| {
+            ui.label("Map:");
+            ComboBox::from_id_source("Projection map")
+            .selected_text(chosen_map.map_or("", |map| map.name.as_str()))
+            .show_ui(ui, |ui| {
+                for map in maps.iter() {
+                    if map.map_weights.is_some() && !*map.is_training.lock().unwrap() {
+                        ui.selectable_value(&mut self.map_id, Some(map.id.clone()), map.name.as_str());
+                    }
+                }
+            });
+            ui.end_row();
+
+            ui.label("Method:");
+            ComboBox::from_id_source("Projection method")
+            .selected_text(self.method.name())
+            .show_ui(ui, |ui| {
+                for method in ProjectionMethod::ALL {
+                    ui.selectable_value(&mut self.method, method, method.name());
+                }
+            });
+            ui.end_row();
+
+            ui.label("Dimensions:");
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.dimensions, 2, "2D");
+                ui.radio_value(&mut self.dimensions, 3, "3D");
+            });
+            ui.end_row();
+
+            ui.label("Overlay inputs:");
+            ui.checkbox(&mut self.show_inputs, "");
+            ui.end_row();
+
+            if self.show_inputs {
+                let mut cur_dataset_label = "".to_owned();
+                if let Some(dataset) = self.dataset_id.as_ref().and_then(|id| find_dataset(datasets, id)) {
+                    cur_dataset_label = dataset.lock().unwrap().name.clone();
+                }
+                ui.label("Dataset to overlay:");
+                ComboBox::from_id_source("Projection dataset")
+                .selected_text(cur_dataset_label)
+                .show_ui(ui, |ui| {
+                    for (_, dataset) in datasets.iter() {
+                        let locked_dataset = dataset.lock().unwrap();
+                        if locked_dataset.is_processed() {
+                            ui.selectable_value(&mut self.dataset_id, Some(locked_dataset.id.clone()), locked_dataset.name.as_str());
+                        }
+                    }
+                });
+                ui.end_row();
+            }
+        });
+
+        ui.horizontal(|ui| {
+            let is_calculating = *self.is_calculating.lock().unwrap();
+            if ui.add_enabled(chosen_map.is_some() && !is_calculating, egui::Button::new("Project the prototypes")).clicked() {
+                let map = chosen_map.unwrap().map_weights.as_ref().unwrap().lock().unwrap().clone();
+                let dataset = self.dataset_id.as_ref()
+                    .filter(|_| self.show_inputs)
+                    .and_then(|id| find_dataset(datasets, id))
+                    .map(|dataset| dataset.lock().unwrap().clone());
+                let (method, dimensions) = (self.method, self.dimensions);
+
+                let cloned_projected = self.projected.clone();
+                let cloned_status = self.is_calculating.clone();
+                *cloned_status.lock().unwrap() = true;
+                std::thread::spawn(move || {
+                    let projected = calculate_projection(&map, method, dimensions, dataset);
+                    *cloned_projected.lock().unwrap() = Some(projected);
+                    *cloned_status.lock().unwrap() = false;
+                });
+            }
+            if is_calculating {
+                ui.spinner();
+            }
+        });
+    }
+
+    fn projection_ui(&mut self, ui: &mut Ui, projected: &ProjectedMap) {
+        let side = ui.available_width().min(PROJECTION_SIZE);
+        let (response, painter) = ui.allocate_painter(Vec2 { x: side, y: side }, Sense::click_and_drag());
+        let rect = response.rect;
+        painter.rect_filled(rect, Rounding::ZERO, Color32::WHITE);
+        painter.rect_stroke(rect, Rounding::ZERO, Stroke::new(1.0, Color32::GRAY));
+
+        if projected.dimensions == 3 && response.dragged() {
+            let delta = response.drag_delta();
+            self.yaw += 0.01 * delta.x;
+            self.pitch = (self.pitch + 0.01 * delta.y).clamp(-1.5, 1.5);
+        }
+        let (yaw, pitch) = if projected.dimensions == 3 { (self.yaw, self.pitch) } else { (0.0, 0.0) };
+        let rotate = |[x, y, z]: [f32; 3]| {
+            let (x, z) = (x * yaw.cos() + z * yaw.sin(), z * yaw.cos() - x * yaw.sin());
+            Vec2 { x, y: y * pitch.cos() - z * pitch.sin() }
+        };
+
+        // Scaled by the radius around the centroid, which rotating doesn't change
+        let count = projected.prototypes.len().max(1) as f32;
+        let mut center = [0.0; 3];
+        for point in &projected.prototypes {
+            for (coordinate, value) in center.iter_mut().zip(point) {
+                *coordinate += value / count;
+            }
+        }
+        let radius = projected.prototypes.iter().chain(&projected.inputs)
+            .map(|point| point.iter().zip(&center).map(|(value, center)| (value - center).powi(2)).sum::<f32>().sqrt())
+            .fold(f32::EPSILON, f32::max);
+        let scale = 0.45 * side / radius;
+        let center = rotate(center);
+        // Screen y grows downwards
+        let to_screen = |point: &[f32; 3]| {
+            let offset = (rotate(*point) - center) * scale;
+            rect.center() + Vec2 { x: offset.x, y: -offset.y }
+        };
+
+        for input in &projected.inputs {
+            painter.circle_filled(to_screen(input), 1.5, Color32::from_gray(170));
+        }
+
+        let (n, m) = (projected.n, projected.m);
+        let position = |(i, j): (usize, usize)| to_screen(&projected.prototypes[i * m + j]);
+        let mut lines = vec![];
+        for i in 0..n {
+            for j in 0..m {
+                for neighbour in projected.topology.neighbours(i, j, n, m).into_iter().filter(|neighbour| *neighbour > (i, j)) {
+                    lines.push(Shape::line_segment([position((i, j)), position(neighbour)], Stroke::new(1.0, Color32::DARK_GRAY)));
+                }
+            }
+        }
+        painter.extend(lines);
+
+        // Coloured by place on the grid, red along i and blue along j, so that twists stand out
+        let grid_color = |(i, j): (usize, usize)| {
+            let share = |index: usize, size: usize| (255.0 * index as f32 / (size.max(2) - 1) as f32) as u8;
+            Color32::from_rgb(share(i, n), 60, share(j, m))
+        };
+        for i in 0..n {
+            for j in 0..m {
+                painter.circle_filled(position((i, j)), 3.5, grid_color((i, j)));
+            }
+        }
+
+        let hovered = response.hover_pos().and_then(|pointer| {
+            (0..n).flat_map(|i| (0..m).map(move |j| (i, j)))
+                .map(|cell| (cell, position(cell).distance(pointer)))
+                .filter(|(_, distance)| *distance <= HOVER_DISTANCE)
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(cell, _)| cell)
+        });
+        if let Some(cell) = hovered {
+            painter.circle_stroke(position(cell), 6.0, Stroke::new(2.0, Color32::BLACK));
+            response.on_hover_text_at_pointer(format!("Neuron ({}, {})", cell.0, cell.1));
+        }
+
+        if projected.dimensions == 3 {
+            ui.label("Drag to rotate");
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use std::path::Path;
+
+use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
+use egui::{Color32, Pos2, Rect, Vec2};
+use image::{Rgba, RgbaImage};
+
+use super::{color_scale::text_color, Visualization};
+
+// Figures of visualizations for reports, drawn without the GUI. The figure is laid out once as a list of
+// primitives that are then either written out as SVG or rasterized into a PNG.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Annotation {
+    None,
+    Counts,
+    // Most frequent text of each cell
+    TopLabels,
+    // Most characteristic word of each cell
+    TopTerms,
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub cell_size: f32,
+    pub title: Option<String>,
+    pub annotation: Annotation,
+    pub legend: bool,
+    pub axes: bool,
+}
+
+impl Annotation {
+    pub const ALL: [Annotation; 4] = [Annotation::None, Annotation::Counts, Annotation::TopLabels, Annotation::TopTerms];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Annotation::None => "None",
+            Annotation::Counts => "Hit counts",
+            Annotation::TopLabels => "Most frequent text",
+            Annotation::TopTerms => "Top terms",
+        }
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self { cell_size: 40.0, title: None, annotation: Annotation::Counts, legend: true, axes: true }
+    }
+}
+
+const MARGIN: f32 = 10.0;
+const FONT_SIZE: f32 = 12.0;
+const TITLE_SIZE: f32 = 18.0;
+const LEGEND_WIDTH: f32 = 16.0;
+const LEGEND_STEPS: usize = 64;
+const LABEL_CUTOFF: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+enum Primitive {
+    Rect { rect: Rect, fill: Color32, stroke: Option<Color32> },
+    // `pos` is the anchor point on the vertical middle of the text
+    Text { pos: Pos2, text: String, size: f32, anchor: Anchor, color: Color32 },
+}
+
+struct Figure {
+    size: Vec2,
+    primitives: Vec<Primitive>,
+}
+
+fn layout(visualization: &Visualization, options: &RenderOptions) -> Result<Figure, String> {
+    let n = visualization.data.len();
+    let m = visualization.data.first().map_or(0, |column| column.len());
+    if n == 0 || m == 0 {
+        return Err(format!("Visualization {} has no data yet", visualization.name));
+    }
+
+    let cell = options.cell_size;
+    let scale = visualization.fitted_scale();
+
+    let title_height = if options.title.is_some() { TITLE_SIZE + MARGIN } else { 0.0 };
+    let axis_size = if options.axes { 2.0 * FONT_SIZE } else { 0.0 };
+    let legend_size = if options.legend { MARGIN + LEGEND_WIDTH + 4.0 * FONT_SIZE } else { 0.0 };
+
+    let grid_min = Pos2 { x: MARGIN + axis_size, y: MARGIN + title_height + axis_size };
+    let grid_size = Vec2 { x: n as f32 * cell, y: m as f32 * cell };
+    let size = Vec2 {
+        x: grid_min.x + grid_size.x + legend_size + MARGIN,
+        y: grid_min.y + grid_size.y + MARGIN,
+    };
+
+    let mut primitives = vec![Primitive::Rect { rect: Rect::from_min_size(Pos2::ZERO, size), fill: Color32::WHITE, stroke: None }];
+
+    if let Some(title) = &options.title {
+        primitives.push(Primitive::Text {
+            pos: Pos2 { x: size.x / 2.0, y: MARGIN + TITLE_SIZE / 2.0 },
+            text: title.clone(), size: TITLE_SIZE, anchor: Anchor::Middle, color: Color32::BLACK,
+        });
+    }
+
+    let values = visualization.cell_values();
+    for (i, column) in values.iter().enumerate() {
+        for (j, value) in column.iter().enumerate() {
+            let rect = Rect::from_min_size(grid_min + Vec2 { x: i as f32 * cell, y: j as f32 * cell }, Vec2::splat(cell));
+            let fill = value.map_or(Color32::GRAY, |value| scale.color(value));
+            primitives.push(Primitive::Rect { rect, fill, stroke: Some(Color32::BLACK) });
+
+            if let Some(annotation) = cell_labels(visualization, options.annotation, i, j, 1).pop() {
+                let color = text_color(fill);
+                // Shrunk so that the text fits the cell, a character is roughly 0.55 of the font size wide
+                let size = (cell / 3.0).min(FONT_SIZE).min(1.6 * cell / annotation.chars().count() as f32);
+                primitives.push(Primitive::Text { pos: rect.center(), text: annotation, size, anchor: Anchor::Middle, color });
+            }
+        }
+    }
+
+    if options.axes {
+        for i in 0..n {
+            primitives.push(Primitive::Text {
+                pos: Pos2 { x: grid_min.x + (i as f32 + 0.5) * cell, y: grid_min.y - FONT_SIZE },
+                text: i.to_string(), size: FONT_SIZE, anchor: Anchor::Middle, color: Color32::BLACK,
+            });
+        }
+        for j in 0..m {
+            primitives.push(Primitive::Text {
+                pos: Pos2 { x: grid_min.x - FONT_SIZE / 2.0, y: grid_min.y + (j as f32 + 0.5) * cell },
+                text: j.to_string(), size: FONT_SIZE, anchor: Anchor::End, color: Color32::BLACK,
+            });
+        }
+    }
+
+    if options.legend {
+        let legend_min = Pos2 { x: grid_min.x + grid_size.x + MARGIN, y: grid_min.y };
+        let step_height = grid_size.y / LEGEND_STEPS as f32;
+        // Highest values on top
+        for step in 0..LEGEND_STEPS {
+            let t = 1.0 - step as f32 / LEGEND_STEPS as f32;
+            let rect = Rect::from_min_size(legend_min + Vec2 { x: 0.0, y: step as f32 * step_height },
+                Vec2 { x: LEGEND_WIDTH, y: step_height + 0.5 });
+            primitives.push(Primitive::Rect { rect, fill: scale.color_map().color(t), stroke: None });
+        }
+        primitives.push(Primitive::Rect {
+            rect: Rect::from_min_size(legend_min, Vec2 { x: LEGEND_WIDTH, y: grid_size.y }), fill: Color32::TRANSPARENT, stroke: Some(Color32::BLACK),
+        });
+
+        for fraction in [0.0, 0.5, 1.0] {
+            primitives.push(Primitive::Text {
+                pos: legend_min + Vec2 { x: LEGEND_WIDTH + FONT_SIZE / 2.0, y: fraction * grid_size.y },
+                text: format_value(scale.value_at(1.0 - fraction)), size: FONT_SIZE, anchor: Anchor::Start, color: Color32::BLACK,
+            });
+        }
+    }
+
+    Ok(Figure { size, primitives })
+}
+
+// Up to `lines` labels of a cell, only top terms can have more than one
+pub fn cell_labels(visualization: &Visualization, annotation: Annotation, i: usize, j: usize, lines: usize) -> Vec<String> {
+    let value = visualization.data[i][j];
+    match annotation {
+        Annotation::None => vec![],
+        Annotation::Counts if value != 0.0 => vec![format_value(value)],
+        Annotation::Counts => vec![],
+        Annotation::TopLabels => visualization.top_texts(i, j, 1).into_iter()
+            .map(|(text, _)| text.chars().take(LABEL_CUTOFF).collect())
+            .collect(),
+        Annotation::TopTerms => visualization.top_terms(i, j).iter()
+            .take(lines)
+            .map(|(term, _)| term.chars().take(LABEL_CUTOFF).collect())
+            .collect(),
+    }
+}
+
+fn format_value(value: f32) -> String {
+    if value.fract() == 0.0 {
+        format!("{value:.0}")
+    } else {
+        format!("{value:.2}")
+    }
+}
+
+fn to_svg(figure: &Figure) -> String {
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+        figure.size.x, figure.size.y);
+
+    for primitive in &figure.primitives {
+        match primitive {
+            Primitive::Rect { rect, fill, stroke } => {
+                let stroke = stroke.map_or(String::new(), |stroke| format!(" stroke=\"{}\" stroke-width=\"1\"", svg_color(stroke)));
+                let fill = if *fill == Color32::TRANSPARENT { "none".to_owned() } else { svg_color(*fill) };
+                svg += &format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{fill}\"{stroke}/>\n",
+                    rect.min.x, rect.min.y, rect.width(), rect.height());
+            }
+            Primitive::Text { pos, text, size, anchor, color } => {
+                let anchor = match anchor {
+                    Anchor::Start => "start",
+                    Anchor::Middle => "middle",
+                    Anchor::End => "end",
+                };
+                svg += &format!("<text x=\"{}\" y=\"{}\" font-family=\"sans-serif\" font-size=\"{size}\" text-anchor=\"{anchor}\" \
+                    dominant-baseline=\"central\" fill=\"{}\">{}</text>\n", pos.x, pos.y, svg_color(*color), escape_xml(text));
+            }
+        }
+    }
+
+    svg + "</svg>\n"
+}
+
+fn svg_color(color: Color32) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r(), color.g(), color.b())
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn to_png(figure: &Figure) -> Result<RgbaImage, String> {
+    // The same font egui draws the GUI with
+    let fonts = egui::FontDefinitions::default();
+    let font_data = fonts.font_data.get("Ubuntu-Light").ok_or("The default egui font is missing")?;
+    let font = FontRef::try_from_slice(&font_data.font).map_err(|err| err.to_string())?;
+
+    let mut image = RgbaImage::from_pixel(figure.size.x.ceil() as u32, figure.size.y.ceil() as u32, Rgba([255, 255, 255, 255]));
+    for primitive in &figure.primitives {
+        match primitive {
+            Primitive::Rect { rect, fill, stroke } => {
+                if *fill != Color32::TRANSPARENT {
+                    fill_rect(&mut image, *rect, *fill);
+                }
+                if let Some(stroke) = stroke {
+                    let (min, max) = (rect.min.round(), rect.max.round());
+                    fill_rect(&mut image, Rect::from_min_max(min, Pos2 { x: max.x, y: min.y + 1.0 }), *stroke);
+                    fill_rect(&mut image, Rect::from_min_max(Pos2 { x: min.x, y: max.y - 1.0 }, max), *stroke);
+                    fill_rect(&mut image, Rect::from_min_max(min, Pos2 { x: min.x + 1.0, y: max.y }), *stroke);
+                    fill_rect(&mut image, Rect::from_min_max(Pos2 { x: max.x - 1.0, y: min.y }, max), *stroke);
+                }
+            }
+            Primitive::Text { pos, text, size, anchor, color } => draw_text(&mut image, &font, *pos, text, *size, *anchor, *color),
+        }
+    }
+
+    Ok(image)
+}
+
+fn fill_rect(image: &mut RgbaImage, rect: Rect, color: Color32) {
+    let x_range = (rect.min.x.round().max(0.0) as u32)..(rect.max.x.round().min(image.width() as f32) as u32);
+    let y_range = (rect.min.y.round().max(0.0) as u32)..(rect.max.y.round().min(image.height() as f32) as u32);
+    for y in y_range {
+        for x in x_range.clone() {
+            blend(image, x, y, color, 1.0);
+        }
+    }
+}
+
+fn blend(image: &mut RgbaImage, x: u32, y: u32, color: Color32, coverage: f32) {
+    let alpha = coverage * color.a() as f32 / 255.0;
+    let pixel = image.get_pixel_mut(x, y);
+    for (channel, value) in pixel.0.iter_mut().zip([color.r(), color.g(), color.b()]) {
+        *channel = (*channel as f32 * (1.0 - alpha) + value as f32 * alpha).round() as u8;
+    }
+}
+
+fn draw_text(image: &mut RgbaImage, font: &FontRef, pos: Pos2, text: &str, size: f32, anchor: Anchor, color: Color32) {
+    let font = font.as_scaled(PxScale::from(size));
+
+    let mut glyphs = vec![];
+    let mut caret = 0.0;
+    let mut previous = None;
+    for character in text.chars() {
+        let glyph_id = font.glyph_id(character);
+        if let Some(previous) = previous {
+            caret += font.kern(previous, glyph_id);
+        }
+        glyphs.push(glyph_id.with_scale_and_position(size, ab_glyph::point(caret, 0.0)));
+        caret += font.h_advance(glyph_id);
+        previous = Some(glyph_id);
+    }
+
+    let start_x = match anchor {
+        Anchor::Start => pos.x,
+        Anchor::Middle => pos.x - caret / 2.0,
+        Anchor::End => pos.x - caret,
+    };
+    let baseline = pos.y + (font.ascent() + font.descent()) / 2.0;
+
+    for mut glyph in glyphs {
+        glyph.position = ab_glyph::point(start_x + glyph.position.x, baseline);
+        let Some(outline) = font.outline_glyph(glyph) else {
+            continue;
+        };
+
+        let bounds = outline.px_bounds();
+        outline.draw(|x, y, coverage| {
+            let (x, y) = (bounds.min.x as i32 + x as i32, bounds.min.y as i32 + y as i32);
+            if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+                blend(image, x as u32, y as u32, color, coverage);
+            }
+        });
+    }
+}
+
+// The format follows the extension, .svg or .png
+pub fn render_to_file(visualization: &Visualization, options: &RenderOptions, path: &Path) -> Result<(), String> {
+    let figure = layout(visualization, options)?;
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("svg") => std::fs::write(path, to_svg(&figure))
+            .map_err(|err| format!("Error while writing {}: {err}", path.display())),
+        Some("png") => to_png(&figure)?.save(path)
+            .map_err(|err| format!("Error while writing {}: {err}", path.display())),
+        _ => Err(format!("Can't tell the image format of {}, use .png or .svg", path.display())),
+    }
+}
+
+const USAGE: &str = "Usage: render <visualization.json_vis> <output.png|output.svg> [--title TITLE] \
+    [--annotate counts|labels|terms|none] [--cell-size PIXELS] [--no-legend] [--no-axes]";
+
+// Entry point of the `render` subcommand
+pub fn run_cli(args: &[String]) -> Result<(), String> {
+    let [input, output, flags @ ..] = args else {
+        return Err(USAGE.to_owned());
+    };
+
+    let mut options = RenderOptions::default();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let mut value = || flags.next().ok_or(format!("{flag} needs a value\n{USAGE}"));
+        match flag.as_str() {
+            "--title" => options.title = Some(value()?.clone()),
+            "--annotate" => options.annotation = match value()?.as_str() {
+                "counts" => Annotation::Counts,
+                "labels" => Annotation::TopLabels,
+                "terms" => Annotation::TopTerms,
+                "none" => Annotation::None,
+                other => return Err(format!("Unknown annotation `{other}`\n{USAGE}")),
+            },
+            "--cell-size" => options.cell_size = value()?.parse()
+                .map_err(|err| format!("Invalid cell size: {err}"))?,
+            "--no-legend" => options.legend = false,
+            "--no-axes" => options.axes = false,
+            other => return Err(format!("Unknown option `{other}`\n{USAGE}")),
+        }
+    }
+
+    let visualization = Visualization::from_file(Path::new(input))?;
+    render_to_file(&visualization, &options, Path::new(output))
+}
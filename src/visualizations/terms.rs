@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use crate::data_processing::tokenize;
+
+// Characteristic terms of every cell by TF-IDF. Each cell's texts together form one document and the
+// documents of all cells form the corpus, so words that are everywhere score low.
+// The counts and the IDF table are worked out once per visualization, a selection only adds up counts
+#[derive(Debug, Default)]
+pub struct TermIndex {
+    term_counts: Vec<Vec<HashMap<String, usize>>>,
+    idf: HashMap<String, f32>,
+}
+
+impl TermIndex {
+    pub fn new(word_clusters: &[Vec<Vec<String>>]) -> Self {
+        let term_counts = cell_term_counts(word_clusters);
+        let idf = inverse_document_frequencies(&term_counts);
+        Self { term_counts, idf }
+    }
+
+    pub fn top_terms_per_cell(&self, limit: usize) -> Vec<Vec<Vec<(String, f32)>>> {
+        self.term_counts.iter()
+            .map(|column| column.iter().map(|counts| tf_idf(counts, &self.idf, limit)).collect())
+            .collect()
+    }
+
+    // Same as for a single cell, with the texts of all `cells` taken as one document
+    pub fn region_top_terms(&self, cells: &[(usize, usize)], limit: usize) -> Vec<(String, f32)> {
+        let mut region_counts: HashMap<String, usize> = HashMap::new();
+        for (i, j) in cells {
+            let Some(counts) = self.term_counts.get(*i).and_then(|column| column.get(*j)) else {
+                continue;
+            };
+            for (term, count) in counts {
+                *region_counts.entry(term.clone()).or_default() += count;
+            }
+        }
+
+        tf_idf(&region_counts, &self.idf, limit)
+    }
+}
+
+fn cell_term_counts(word_clusters: &[Vec<Vec<String>>]) -> Vec<Vec<HashMap<String, usize>>> {
+    word_clusters.iter()
+        .map(|column| column.iter()
+            .map(|texts| {
+                let mut counts = HashMap::new();
+                for word in texts.iter().flat_map(|text| tokenize(text)) {
+                    *counts.entry(word).or_default() += 1;
+                }
+                counts
+            })
+            .collect())
+        .collect()
+}
+
+fn inverse_document_frequencies(term_counts: &[Vec<HashMap<String, usize>>]) -> HashMap<String, f32> {
+    let mut document_frequency: HashMap<&str, usize> = HashMap::new();
+    let mut document_count = 0;
+    for counts in term_counts.iter().flatten().filter(|counts| !counts.is_empty()) {
+        document_count += 1;
+        for term in counts.keys() {
+            *document_frequency.entry(term).or_default() += 1;
+        }
+    }
+
+    // Smoothed, so that a term found in every cell still gets a small positive weight
+    document_frequency.into_iter()
+        .map(|(term, frequency)| (term.to_owned(), ((1 + document_count) as f32 / (1 + frequency) as f32).ln() + 1.0))
+        .collect()
+}
+
+fn tf_idf(counts: &HashMap<String, usize>, idf: &HashMap<String, f32>, limit: usize) -> Vec<(String, f32)> {
+    let total: usize = counts.values().sum();
+    let mut scores: Vec<(String, f32)> = counts.iter()
+        .map(|(term, count)| (term.clone(), *count as f32 / total as f32 * idf[term]))
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    scores.truncate(limit);
+    scores
+}
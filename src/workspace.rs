@@ -0,0 +1,96 @@
+use std::{path::Path, sync::{Arc, Mutex}};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{data_processing::DataSet, file_format::{self, read_json, write_json, Versioned}, maps::SOMParams, 
+    visualizations::Visualization, Pane};
+
+// Everything the three panes hold, plus optionally how the tabs were laid out
+#[derive(Serialize, Deserialize)]
+pub struct Workspace {
+    pub datasets: Vec<Arc<Mutex<DataSet>>>,
+    pub maps: Vec<SOMParams>,
+    pub visualizations: Vec<Arc<Mutex<Visualization>>>,
+
+    #[serde(default)]
+    pub tree: Option<egui_tiles::Tree<Pane>>,
+}
+
+impl Versioned for Workspace {
+    fn format() -> String {
+        "workspace".to_owned()
+    }
+
+    fn migrate(payload: &mut Value, from_version: u32) -> Result<(), String> {
+        let Value::Object(workspace) = payload else {
+            return Err("expected an object".to_owned());
+        };
+
+        if let Some(datasets) = workspace.get_mut("datasets") {
+            Vec::<DataSet>::migrate(datasets, from_version).map_err(|err| format!("datasets: {err}"))?;
+        }
+        if let Some(maps) = workspace.get_mut("maps") {
+            Vec::<SOMParams>::migrate(maps, from_version).map_err(|err| format!("maps: {err}"))?;
+        }
+        if let Some(visualizations) = workspace.get_mut("visualizations") {
+            Vec::<Visualization>::migrate(visualizations, from_version).map_err(|err| format!("visualizations: {err}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Workspace {
+    pub fn from_file(filename: &Path) -> Result<Self, String> {
+        file_format::from_file(filename)
+    }
+
+    pub fn to_file(&self, filename: &Path) -> Result<(), String> {
+        file_format::to_file(self, filename)
+    }
+
+    // The autosave is split per pane, a broken or missing file only loses that pane's state
+    pub fn from_autosave(datasets_path: &str, maps_path: &str, visualizations_path: &str, layout_path: &str) -> Self {
+        fn or_default<T: Default>(path: &str, read: fn(&Path) -> Result<T, String>) -> T {
+            if !Path::new(path).exists() {
+                return T::default();
+            }
+
+            read(Path::new(path)).unwrap_or_else(|err| {
+                println!("{err}");
+                T::default()
+            })
+        }
+
+        Self {
+            datasets: or_default(datasets_path, file_format::from_file),
+            maps: or_default(maps_path, file_format::from_file),
+            visualizations: or_default(visualizations_path, file_format::from_file),
+            // The layout is egui_tiles' own structure, if it doesn't parse the default one is used
+            tree: or_default(layout_path, read_json),
+        }
+    }
+
+    pub fn to_autosave(&self, datasets_path: &str, maps_path: &str, visualizations_path: &str, layout_path: &str) -> Result<(), String> {
+        file_format::to_file(&self.datasets, Path::new(datasets_path))?;
+        file_format::to_file(&self.maps, Path::new(maps_path))?;
+        file_format::to_file(&self.visualizations, Path::new(visualizations_path))?;
+        write_json(Path::new(layout_path), &self.tree)
+    }
+
+    // A map being trained keeps its weights locked until training ends, those are saved with the weights
+    // they had before the fit so that saving never has to wait for the training thread
+    pub fn without_locked_maps(mut self) -> Self {
+        for map in self.maps.iter_mut() {
+            if *map.is_training.lock().unwrap() {
+                println!("Map {} is being trained, the weights from before the fit are saved", map.name);
+                map.map_weights = map.pre_training_weights.lock().unwrap().clone().map(|weights| Arc::new(Mutex::new(weights)));
+                map.is_training = Arc::new(Mutex::new(false));
+                map.pre_training_weights = Arc::new(Mutex::new(None));
+            }
+        }
+
+        self
+    }
+}